@@ -0,0 +1,47 @@
+//! Times the hot path of counting, incrementing the counter of every IP, with the FxHash of
+//! `Stats` against a map using the default SipHash.
+//!
+//! Run with `cargo run --release --example hash_benchmark`, optionally followed by the number of
+//! IPs to count (5 million by default).
+
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::time::{ Duration, Instant };
+
+use ipstats::Stats;
+
+
+/// A fixed mix of recurring and one-off IPs, the same on every run
+fn ips(count: usize) -> Vec<String> {
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    (0..count).map(|i| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let [a, b, c, d, ..] = state.to_le_bytes();
+        if i % 2 == 0 { format!("10.0.{}.{d}", c % 64) } else { format!("{}.{b}.{c}.{d}", a % 223 + 1) }
+    }).collect()
+}
+
+/// The keys are handed over owned, like `normalize_key` makes them, so only the map is timed
+fn count<S: BuildHasher>(ips: Vec<String>, mut stats: HashMap<String, u32, S>) -> (Duration, usize) {
+    let started = Instant::now();
+    for ip in ips {
+        stats.entry(ip)
+            .and_modify(|counter: &mut u32| *counter = counter.saturating_add(1))
+            .or_insert(1);
+    }
+    (started.elapsed(), stats.len())
+}
+
+
+fn main() {
+    let total = std::env::args().nth(1).map_or(5_000_000, |n| n.parse().expect("the number of IPs"));
+    let ips = ips(total);
+
+    let (sip, distinct) = count(ips.clone(), HashMap::new());
+    let (fx, _) = count(ips, Stats::default());
+    println!("{total} IPs, {distinct} distinct");
+    println!("SipHash: {sip:.2?}");
+    println!("FxHash:  {fx:.2?} ({:.0}% of SipHash)", fx.as_secs_f64() / sip.as_secs_f64() * 100.0);
+}
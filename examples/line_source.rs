@@ -0,0 +1,77 @@
+//! Counting the IPs of messages that arrive one by one, the way a consumer of a message queue
+//! would hand them over, instead of reading a file.
+//!
+//! Run with `cargo run --example line_source`, `cargo test` checks its counts.
+
+use std::collections::VecDeque;
+use std::io;
+
+use ipstats::{ DEFAULT_PATTERN, LineSource, Next, ProcessOptions, Stats, Summary, process_source };
+use regex::Regex;
+
+
+/// Stands in for a queue consumer: `None` is a poll that came back empty, the queue is closed
+/// once all messages are taken
+struct Queue {
+    messages: VecDeque<Option<&'static str>>,
+}
+
+impl LineSource for Queue {
+    fn next_line(&mut self, line: &mut String) -> io::Result<Next> {
+        match self.messages.pop_front() {
+            Some(Some(message)) => {
+                line.push_str(message);
+                Ok(Next::Line { bytes: message.len(), too_long: false })
+            }
+            Some(None) => Ok(Next::Pending),
+            None => Ok(Next::Eof),
+        }
+    }
+
+    fn name(&self) -> &str {
+        "queue"
+    }
+}
+
+
+/// Counts the IPs of a few messages with empty polls in between
+fn count_queue() -> Result<(Stats, Summary), ipstats::Error> {
+    let mut queue = Queue {
+        messages: VecDeque::from([
+            Some("login from ::ffff:192.0.2.1"),
+            None,
+            Some("login from ::ffff:198.51.100.7"),
+            None,
+            None,
+            Some("logout from ::ffff:192.0.2.1"),
+        ]),
+    };
+    let opts = ProcessOptions::new(Regex::new(DEFAULT_PATTERN).expect("the default pattern compiles"));
+    let mut stats = Stats::default();
+    let mut summary = Summary::default();
+    process_source(&mut queue, &mut stats, &opts, &mut summary, None, None)?;
+    Ok((stats, summary))
+}
+
+fn main() -> Result<(), ipstats::Error> {
+    let (stats, _) = count_queue()?;
+    for (ip, count) in &stats {
+        println!("{count} {ip}");
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_polls_are_waited_out() {
+        let (stats, summary) = count_queue().unwrap();
+        // They are neither lines nor the end of the input
+        assert_eq!(summary.lines, 3);
+        assert_eq!(stats["192.0.2.1"], 2);
+        assert_eq!(stats["198.51.100.7"], 1);
+    }
+}
@@ -0,0 +1,61 @@
+use std::cell::{ Cell, RefCell };
+use std::net::Ipv4Addr;
+
+
+/// Number of 64 bit words needed for one bit per IPv4 address, 512MB in total
+const WORDS: usize = 1 << 26;
+
+
+/// Records which IPv4 addresses were seen, with one bit per address.
+///
+/// The memory is requested zeroed up front, so the OS only actually hands out the pages
+/// that get written to, sparse input stays cheap while dense input never needs more than 512MB.
+pub struct Bitmap {
+    words: RefCell<Vec<u64>>,
+    len: Cell<usize>,
+}
+
+impl Default for Bitmap {
+    fn default() -> Self {
+        Bitmap::new()
+    }
+}
+
+impl Bitmap {
+    pub fn new() -> Self {
+        Bitmap { words: RefCell::new(vec![0; WORDS]), len: Cell::new(0) }
+    }
+
+    pub fn insert(&self, ip: Ipv4Addr) {
+        let ip = u32::from(ip) as usize;
+        let word = &mut self.words.borrow_mut()[ip / 64];
+        let bit = 1 << (ip % 64);
+        if *word & bit == 0 {
+            *word |= bit;
+            self.len.set(self.len.get() + 1);
+        }
+    }
+
+    /// Number of distinct addresses seen
+    pub fn len(&self) -> usize {
+        self.len.get()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len.get() == 0
+    }
+
+    /// Hands all addresses seen to `callback` in ascending order, without collecting
+    /// them first, as that could take up to 16GB. Stops at the first error of `callback`.
+    pub fn each<E>(&self, mut callback: impl FnMut(Ipv4Addr) -> Result<(), E>) -> Result<(), E> {
+        for (i, word) in self.words.borrow().iter().enumerate() {
+            let mut word = *word;
+            while word != 0 {
+                let bit = word.trailing_zeros() as usize;
+                callback(Ipv4Addr::from((i * 64 + bit) as u32))?;
+                word &= word - 1;
+            }
+        }
+        Ok(())
+    }
+}
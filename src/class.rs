@@ -0,0 +1,76 @@
+use std::fmt;
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr };
+
+
+/// Rough kind of network an address belongs to, for `{class}` and --class-filter
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NetworkClass {
+    /// 127.0.0.0/8 and ::1
+    Loopback,
+    /// RFC 1918 and unique local (fc00::/7) addresses
+    Private,
+    /// 169.254.0.0/16 and fe80::/10
+    LinkLocal,
+    /// 224.0.0.0/4 and ff00::/8
+    Multicast,
+    /// RFC 5737 and RFC 3849 documentation ranges
+    Documentation,
+    /// Everything else
+    Public,
+}
+
+impl NetworkClass {
+    pub fn of(ip: IpAddr) -> Self {
+        // IPv4 mapped addresses are classified like the IPv4 address they carry
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => Self::of_v4(ip),
+            IpAddr::V6(ip) => Self::of_v6(ip),
+        }
+    }
+
+    fn of_v4(ip: Ipv4Addr) -> Self {
+        if ip.is_loopback() {
+            NetworkClass::Loopback
+        } else if ip.is_private() {
+            NetworkClass::Private
+        } else if ip.is_link_local() {
+            NetworkClass::LinkLocal
+        } else if ip.is_multicast() {
+            NetworkClass::Multicast
+        } else if ip.is_documentation() {
+            NetworkClass::Documentation
+        } else {
+            NetworkClass::Public
+        }
+    }
+
+    fn of_v6(ip: Ipv6Addr) -> Self {
+        if ip.is_loopback() {
+            NetworkClass::Loopback
+        } else if ip.is_unique_local() {
+            NetworkClass::Private
+        } else if ip.is_unicast_link_local() {
+            NetworkClass::LinkLocal
+        } else if ip.is_multicast() {
+            NetworkClass::Multicast
+        } else if ip.segments()[..2] == [0x2001, 0xdb8] {
+            // 2001:db8::/32
+            NetworkClass::Documentation
+        } else {
+            NetworkClass::Public
+        }
+    }
+}
+
+impl fmt::Display for NetworkClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            NetworkClass::Loopback => "loopback",
+            NetworkClass::Private => "private",
+            NetworkClass::LinkLocal => "link-local",
+            NetworkClass::Multicast => "multicast",
+            NetworkClass::Documentation => "documentation",
+            NetworkClass::Public => "public",
+        })
+    }
+}
@@ -0,0 +1,196 @@
+use std::io::Read;
+
+use anyhow::bail;
+use regex::Regex;
+
+use crate::{ DEFAULT_PATTERN, Error, MAC_PATTERN, ProcessOptions, Stats, Summary, process_file };
+
+
+/// Configures the counting step by step and counts any number of inputs with it, for embedding
+/// without spelling out all of `ProcessOptions`. Contradicting settings are reported by
+/// `collect_from` instead of being silently resolved.
+///
+/// ```
+/// use std::io::Cursor;
+/// use ipstats::StatsCollector;
+///
+/// let collector = StatsCollector::new()
+///     .pattern(regex::Regex::new(r"[0-9.]+").unwrap())
+///     .key(2)
+///     .validate(true);
+/// let mut stats = collector.collect_from(Cursor::new("192.0.2.9 -> 192.0.2.1\n"))?;
+/// let other = collector.collect_from(Cursor::new("192.0.2.9 -> 192.0.2.1\n192.0.2.1 -> 192.0.2.7\n"))?;
+/// collector.merge(&mut stats, other)?;
+/// assert_eq!(stats["192.0.2.1"], 2);
+/// assert_eq!(stats["192.0.2.7"], 1);
+/// # Ok::<(), ipstats::Error>(())
+/// ```
+pub struct StatsCollector {
+    opts: ProcessOptions,
+    custom_pattern: bool,
+}
+
+impl StatsCollector {
+    /// Counts the first IP of every line with the default pattern, skipping lines without one
+    pub fn new() -> Self {
+        let pattern = Regex::new(DEFAULT_PATTERN).expect("the default pattern compiles");
+        StatsCollector { opts: ProcessOptions::new(pattern), custom_pattern: false }
+    }
+
+    /// Extract the IPs with `pattern` instead of the default one
+    pub fn pattern(mut self, pattern: Regex) -> Self {
+        self.opts.pattern = pattern;
+        self.custom_pattern = true;
+        self
+    }
+
+    /// Count the `key`th match of every line, starting at 1
+    pub fn key(mut self, key: usize) -> Self {
+        self.opts.key = key;
+        self
+    }
+
+    /// Count MAC addresses instead of IPs, found with a pattern for them unless `pattern` is set
+    pub fn mac(mut self, mac: bool) -> Self {
+        self.opts.mac = mac;
+        if !self.custom_pattern {
+            let pattern = if mac { MAC_PATTERN } else { DEFAULT_PATTERN };
+            self.opts.pattern = Regex::new(pattern).expect("the built in patterns compile");
+        }
+        self
+    }
+
+    /// Count IPv4 addresses in their IPv4-mapped IPv6 form, like --ipv4-as-ipv6
+    pub fn ipv4_as_ipv6(mut self, ipv4_as_ipv6: bool) -> Self {
+        self.opts.ipv4_as_ipv6 = ipv4_as_ipv6;
+        self
+    }
+
+    /// Count IPv4 addresses with leading zeros like `010.001.002.003` as 10.1.2.3, the default
+    pub fn normalize_octets(mut self, normalize_octets: bool) -> Self {
+        self.opts.normalize_octets = normalize_octets;
+        self
+    }
+
+    /// Take every whole line as an IP instead of searching it with a pattern
+    pub fn fixed_ips(mut self, fixed_ips: bool) -> Self {
+        self.opts.fixed_ips = fixed_ips;
+        self
+    }
+
+    /// Fail on the first line without an IP instead of skipping it, like --pedantic
+    pub fn validate(mut self, validate: bool) -> Self {
+        self.opts.pedantic = validate;
+        self
+    }
+
+    /// Give up once there are more than `max` distinct IPs, while counting as well as merging
+    pub fn max_distinct(mut self, max: usize) -> Self {
+        self.opts.max_distinct = Some(max);
+        self
+    }
+
+    /// The options everything is counted with
+    pub fn options(&self) -> &ProcessOptions {
+        &self.opts
+    }
+
+    /// The options to count with `process_file` and the like directly, checked like `collect_from` does
+    pub fn into_options(self) -> Result<ProcessOptions, Error> {
+        self.check()?;
+        Ok(self.opts)
+    }
+
+    /// Counts the IPs of a single input, gzipped input is decompressed on the fly
+    pub fn collect_from(&self, mut reader: impl Read) -> Result<Stats, Error> {
+        self.check()?;
+        let mut stats = Stats::default();
+        process_file(&mut reader, "input", &mut stats, &self.opts, &mut Summary::default(), None, None)?;
+        Ok(stats)
+    }
+
+    /// Adds the counts of `other` to `stats`, e.g. to combine inputs counted separately
+    pub fn merge(&self, stats: &mut Stats, other: Stats) -> Result<(), Error> {
+        for (ip, count) in other {
+            stats.entry(ip)
+                .and_modify(|counter: &mut u32| *counter = counter.saturating_add(count))
+                .or_insert(count);
+        }
+        if let Some(max) = self.opts.max_distinct.filter(|max| stats.len() > *max) {
+            let context = format!("Found more than {max} distinct IPs after merging, giving up");
+            return Err(Error::TooManyDistinct { context, max });
+        }
+        Ok(())
+    }
+
+    fn check(&self) -> anyhow::Result<()> {
+        if self.opts.key == 0 {
+            bail!("The key counts from 1, 0 does not select any match")
+        }
+        if self.opts.fixed_ips && self.custom_pattern {
+            bail!("fixed_ips takes the whole line as the IP, so a pattern would never be used")
+        }
+        if self.opts.fixed_ips && self.opts.key != 1 {
+            bail!("fixed_ips takes the whole line as the IP, so there is no match number {} to select", self.opts.key)
+        }
+        Ok(())
+    }
+}
+
+impl Default for StatsCollector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(collector: StatsCollector) -> String {
+        collector.collect_from("192.0.2.1\n".as_bytes()).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn key_counts_from_one() {
+        assert!(error(StatsCollector::new().key(0)).contains("counts from 1"));
+    }
+
+    #[test]
+    fn fixed_ips_rejects_a_pattern() {
+        let collector = StatsCollector::new().pattern(Regex::new("[0-9.]+").unwrap()).fixed_ips(true);
+        assert!(error(collector).contains("a pattern would never be used"));
+    }
+
+    #[test]
+    fn fixed_ips_rejects_a_key() {
+        assert!(error(StatsCollector::new().fixed_ips(true).key(2)).contains("no match number 2"));
+        let stats = StatsCollector::new().fixed_ips(true).collect_from("192.0.2.1\n".as_bytes()).unwrap();
+        assert_eq!(stats["192.0.2.1"], 1);
+    }
+
+    #[test]
+    fn into_options_is_checked() {
+        assert!(StatsCollector::new().key(0).into_options().is_err());
+        assert_eq!(StatsCollector::new().key(2).into_options().unwrap().key, 2);
+    }
+
+    #[test]
+    fn mac_keeps_a_custom_pattern() {
+        let pattern = Regex::new("[0-9a-f:]{17}").unwrap();
+        for collector in [StatsCollector::new().mac(true).pattern(pattern.clone()), StatsCollector::new().pattern(pattern).mac(true)] {
+            assert_eq!(collector.options().pattern.as_str(), "[0-9a-f:]{17}");
+        }
+        assert_eq!(StatsCollector::new().mac(true).options().pattern.as_str(), MAC_PATTERN);
+    }
+
+    #[test]
+    fn merge_gives_up_past_max_distinct() {
+        let collector = StatsCollector::new().pattern(Regex::new("[0-9.]+").unwrap()).max_distinct(1);
+        let mut stats = collector.collect_from("192.0.2.1\n".as_bytes()).unwrap();
+        let other = collector.collect_from("192.0.2.2\n".as_bytes()).unwrap();
+        let err = collector.merge(&mut stats, other).unwrap_err();
+        assert!(matches!(err, Error::TooManyDistinct { max: 1, .. }), "{err}");
+    }
+}
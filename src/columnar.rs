@@ -0,0 +1,68 @@
+use std::io::prelude::*;
+
+use crate::Error;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Uncompressed,
+    Snappy,
+    Gzip,
+    Zstd,
+}
+
+
+/// A report in columnar form, `ip` and `count` followed by any string columns like `host`
+pub struct Table {
+    pub ips: Vec<String>,
+    pub counts: Vec<i64>,
+    pub columns: Vec<(&'static str, Vec<String>)>,
+}
+
+/// Writes the table as a Parquet file.
+///
+/// The file is assembled in memory first, since the writer needs to own its output.
+#[cfg(feature = "parquet")]
+pub fn write_parquet(out: &mut dyn Write, table: Table, compression: Compression) -> Result<(), Error> {
+    use std::sync::Arc;
+
+    use anyhow::Context;
+    use arrow_array::{ ArrayRef, Int64Array, RecordBatch, StringArray };
+    use arrow_schema::{ DataType, Field, Schema };
+    use parquet::arrow::ArrowWriter;
+    use parquet::basic::{ self, GzipLevel, ZstdLevel };
+    use parquet::file::properties::WriterProperties;
+
+    let mut fields = vec![Field::new("ip", DataType::Utf8, false), Field::new("count", DataType::Int64, false)];
+    let mut arrays: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from(table.ips)),
+        Arc::new(Int64Array::from(table.counts)),
+    ];
+    for (name, values) in table.columns {
+        fields.push(Field::new(name, DataType::Utf8, false));
+        arrays.push(Arc::new(StringArray::from(values)));
+    }
+    let schema = Arc::new(Schema::new(fields));
+    let batch = RecordBatch::try_new(schema.clone(), arrays).context("Could not build record batch")?;
+
+    let compression = match compression {
+        Compression::Uncompressed => basic::Compression::UNCOMPRESSED,
+        Compression::Snappy => basic::Compression::SNAPPY,
+        Compression::Gzip => basic::Compression::GZIP(GzipLevel::default()),
+        Compression::Zstd => basic::Compression::ZSTD(ZstdLevel::default()),
+    };
+    let props = WriterProperties::builder().set_compression(compression).build();
+
+    let mut buffer = Vec::new();
+    let mut writer = ArrowWriter::try_new(&mut buffer, schema, Some(props)).context("Could not create Parquet writer")?;
+    writer.write(&batch).context("Could not write Parquet data")?;
+    writer.close().context("Could not finish Parquet file")?;
+    out.write_all(&buffer)?;
+    Ok(())
+}
+
+/// Stand-in used when built without the `parquet` feature
+#[cfg(not(feature = "parquet"))]
+pub fn write_parquet(_out: &mut dyn Write, _table: Table, _compression: Compression) -> Result<(), Error> {
+    Err(Error::Other("Parquet output is not available, ipstats was built without the `parquet` feature".to_string()))
+}
@@ -0,0 +1,137 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{ Context, Result };
+use log::{ info, warn };
+use serde::Deserialize;
+
+
+/// Per-project overrides, looked up in the current directory
+const LOCAL_CONFIG: &str = ".ipstats.toml";
+
+
+/// Defaults for some of the command line options, read from
+/// `~/.config/ipstats/config.toml` and `./.ipstats.toml`, the latter taking precedence
+#[derive(Default, Deserialize)]
+pub struct Config {
+    pub pattern: Option<String>,
+    pub format: Option<String>,
+    pub numeric: Option<bool>,
+    pub threshold: Option<u32>,
+    pub max_results: Option<usize>,
+    pub skip_v4_rdns: Option<bool>,
+    pub skip_v6_rdns: Option<bool>,
+    pub dns_rate: Option<u32>,
+}
+
+impl Config {
+    pub fn load() -> Result<Self> {
+        let mut config = Config::default();
+        for path in global_path().into_iter().chain([PathBuf::from(LOCAL_CONFIG)]) {
+            if path.exists() {
+                info!("Loading config file {}", path.display());
+                config = Config::load_file(&path)?.or(config);
+            }
+        }
+        Ok(config)
+    }
+
+    fn load_file(path: &PathBuf) -> Result<Self> {
+        let data = fs::read_to_string(path)
+            .with_context(|| format!("Could not read config file: {}", path.display()))?;
+        // The parse errors already point at the line and column
+        let (config, unknown) = Config::parse(&data)
+            .with_context(|| format!("Could not parse config file: {}", path.display()))?;
+        for key in unknown {
+            warn!("Ignoring unknown key {key:?} in config file {}", path.display());
+        }
+        Ok(config)
+    }
+
+    /// The config in `data` and the keys it has no field for, which would otherwise be dropped silently
+    fn parse(data: &str) -> Result<(Self, Vec<String>), toml::de::Error> {
+        let mut unknown = Vec::new();
+        let config = serde_ignored::deserialize(toml::Deserializer::new(data), |key| unknown.push(key.to_string()))?;
+        Ok((config, unknown))
+    }
+
+    /// Takes every value set in `self`, falling back to `other`
+    fn or(self, other: Config) -> Config {
+        Config {
+            pattern: self.pattern.or(other.pattern),
+            format: self.format.or(other.format),
+            numeric: self.numeric.or(other.numeric),
+            threshold: self.threshold.or(other.threshold),
+            max_results: self.max_results.or(other.max_results),
+            skip_v4_rdns: self.skip_v4_rdns.or(other.skip_v4_rdns),
+            skip_v6_rdns: self.skip_v6_rdns.or(other.skip_v6_rdns),
+            dns_rate: self.dns_rate.or(other.dns_rate),
+        }
+    }
+}
+
+/// `$XDG_CONFIG_HOME/ipstats/config.toml`, or `~/.config/ipstats/config.toml`
+fn global_path() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("ipstats").join("config.toml"))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(name: &str, data: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("ipstats-config-{name}-{}.toml", std::process::id()));
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn reads_every_key() {
+        let path = write("keys", r#"
+            pattern = "[0-9.]+"
+            format = "{ip}"
+            numeric = true
+            threshold = 3
+            max_results = 20
+            skip_v4_rdns = true
+            skip_v6_rdns = false
+            dns_rate = 10
+        "#);
+        let config = Config::load_file(&path).unwrap();
+        assert_eq!(config.pattern.as_deref(), Some("[0-9.]+"));
+        assert_eq!(config.format.as_deref(), Some("{ip}"));
+        assert_eq!(config.numeric, Some(true));
+        assert_eq!(config.threshold, Some(3));
+        assert_eq!(config.max_results, Some(20));
+        assert_eq!(config.skip_v4_rdns, Some(true));
+        assert_eq!(config.skip_v6_rdns, Some(false));
+        assert_eq!(config.dns_rate, Some(10));
+    }
+
+    #[test]
+    fn local_values_win() {
+        let global = Config::load_file(&write("global", "numeric = true\nthreshold = 3\nmax_results = 20")).unwrap();
+        let local = Config::load_file(&write("local", "numeric = false\nmax_results = 5")).unwrap();
+        let config = local.or(global);
+        assert_eq!((config.numeric, config.threshold, config.max_results), (Some(false), Some(3), Some(5)));
+    }
+
+    #[test]
+    fn reports_unknown_keys() {
+        let (config, unknown) = Config::parse("max_result = 3\nthreshold = 2\n[colors]\nip = \"red\"\n").unwrap();
+        assert_eq!(config.threshold, Some(2));
+        assert_eq!(unknown, ["max_result", "colors"]);
+    }
+
+    #[test]
+    fn rejects_wrong_types() {
+        assert!(Config::load_file(&write("types", "max_results = \"many\"")).is_err());
+        assert!(Config::load_file(&write("syntax", "max_results = ")).is_err());
+    }
+}
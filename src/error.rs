@@ -0,0 +1,135 @@
+use std::io;
+
+use serde_json::json;
+
+
+/// Errors at the library boundary, classified the same way `--errors json` reports them.
+/// The message is the context the error happened in, the underlying error is its `source`.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// A line of the input was rejected
+    #[error("{message}")]
+    Input { file: String, line: u64, message: String },
+    /// Reading the input or writing the output failed
+    #[error("{context}")]
+    Io { context: String, source: io::Error },
+    /// A pattern could not be compiled
+    #[error("{context}")]
+    Pattern { context: String, source: regex::Error },
+    /// A file like a baseline or a saved state could not be parsed
+    #[error("{context}")]
+    Parse { context: String, source: Box<dyn std::error::Error + Send + Sync> },
+    /// More distinct IPs were found than `max_distinct` allows
+    #[error("{context}")]
+    TooManyDistinct { context: String, max: usize },
+    /// Anything else, e.g. a failed lookup or a broken format string
+    #[error("{0}")]
+    Other(String),
+}
+
+impl Error {
+    /// An error about line `line` of `file`
+    pub fn input(file: &str, line: u64, message: String) -> Self {
+        Error::Input { file: file.to_string(), line, message }
+    }
+
+    /// The `kind` reported by `--errors json`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Input { .. } => "input",
+            Error::Io { .. } => "io",
+            Error::Pattern { .. } => "pattern",
+            Error::Parse { .. } => "parse",
+            Error::TooManyDistinct { .. } => "limit",
+            Error::Other(_) => "error",
+        }
+    }
+
+    /// The file and line the error is about, if it is about the input
+    pub fn location(&self) -> Option<(&str, u64)> {
+        match self {
+            Error::Input { file, line, .. } => Some((file, *line)),
+            _ => None,
+        }
+    }
+
+    /// Puts `outer` in front of the message, like `anyhow::Context` would
+    fn within(self, outer: &str) -> Self {
+        if outer.is_empty() {
+            return self;
+        }
+        match self {
+            Error::Input { file, line, message } => Error::Input { file, line, message: format!("{outer}: {message}") },
+            Error::Io { context, source } => Error::Io { context: format!("{outer}: {context}"), source },
+            Error::Pattern { context, source } => Error::Pattern { context: format!("{outer}: {context}"), source },
+            Error::Parse { context, source } => Error::Parse { context: format!("{outer}: {context}"), source },
+            Error::TooManyDistinct { context, max } => Error::TooManyDistinct { context: format!("{outer}: {context}"), max },
+            Error::Other(message) => Error::Other(format!("{outer}: {message}")),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(source: io::Error) -> Self {
+        Error::Io { context: "I/O error".to_string(), source }
+    }
+}
+
+impl From<regex::Error> for Error {
+    fn from(source: regex::Error) -> Self {
+        Error::Pattern { context: "Invalid pattern".to_string(), source }
+    }
+}
+
+/// The internals use `anyhow` for context, the first cause with a type of its own decides the variant
+/// and the messages of the causes around it become its context.
+impl From<anyhow::Error> for Error {
+    fn from(err: anyhow::Error) -> Self {
+        fn typed(cause: &(dyn std::error::Error + 'static)) -> bool {
+            cause.is::<Error>()
+                || cause.is::<io::Error>()
+                || cause.is::<regex::Error>()
+                || cause.is::<serde_json::Error>()
+                || cause.is::<toml::de::Error>()
+        }
+        let outer = err.chain().take_while(|cause| !typed(*cause)).map(ToString::to_string).collect::<Vec<_>>();
+        if outer.len() == err.chain().count() {
+            return Error::Other(format!("{err:#}"));
+        }
+        let context = |default: &str| if outer.is_empty() { default.to_string() } else { outer.join(": ") };
+        let err = match err.downcast::<Error>() {
+            Ok(err) => return err.within(&outer.join(": ")),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<io::Error>() {
+            Ok(source) => return Error::Io { context: context("I/O error"), source },
+            Err(err) => err,
+        };
+        let err = match err.downcast::<regex::Error>() {
+            Ok(source) => return Error::Pattern { context: context("Invalid pattern"), source },
+            Err(err) => err,
+        };
+        let err = match err.downcast::<serde_json::Error>() {
+            Ok(source) => return Error::Parse { context: context("Parse error"), source: Box::new(source) },
+            Err(err) => err,
+        };
+        match err.downcast::<toml::de::Error>() {
+            Ok(source) => Error::Parse { context: context("Parse error"), source: Box::new(source) },
+            Err(err) => Error::Other(format!("{err:#}")),
+        }
+    }
+}
+
+
+/// Prints a fatal error as a single line of JSON to stderr
+pub fn print_json(err: &Error) {
+    let causes = std::iter::successors(Some(err as &dyn std::error::Error), |cause| cause.source());
+    let location = err.location();
+    let error = json!({
+        "kind": err.kind(),
+        "message": causes.map(ToString::to_string).collect::<Vec<_>>().join(": "),
+        "file": location.map(|(file, _)| file),
+        "line": location.map(|(_, line)| line),
+    });
+    eprintln!("{error}");
+}
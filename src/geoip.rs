@@ -0,0 +1,92 @@
+use std::net::IpAddr;
+
+use crate::Error;
+
+
+/// City level location of an IP, all fields are empty if unknown
+#[derive(Default)]
+pub struct City {
+    pub city: String,
+    pub region: String,
+    pub postal: String,
+    pub lat: String,
+    pub lon: String,
+}
+
+
+/// Lookups against a MaxMind GeoIP2/GeoLite2 country (or city) database.
+///
+/// The whole database is read into memory when opening it, so lookups never hit the disk.
+#[cfg(feature = "geoip")]
+pub struct GeoIp {
+    reader: maxminddb::Reader<Vec<u8>>,
+}
+
+#[cfg(feature = "geoip")]
+impl GeoIp {
+    pub fn open(path: &str) -> Result<Self, Error> {
+        use anyhow::Context;
+
+        let reader = maxminddb::Reader::open_readfile(path)
+            .with_context(|| format!("Could not open GeoIP database: {path}"))?;
+        Ok(GeoIp { reader })
+    }
+
+    /// Returns the ISO 3166-1 alpha-2 code of the country the IP is located in, if known
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let record: maxminddb::geoip2::Country = self.reader.lookup(ip).ok()?;
+        record.country?.iso_code.map(String::from)
+    }
+
+    /// Returns the city, first subdivision (in English), postal code and coordinates of the IP,
+    /// which requires a city database
+    pub fn city(&self, ip: IpAddr) -> City {
+        let Ok(record) = self.reader.lookup::<maxminddb::geoip2::City>(ip) else {
+            return City::default();
+        };
+        let english = |names: Option<std::collections::BTreeMap<&str, &str>>| {
+            names.and_then(|names| names.get("en").map(|name| name.to_string())).unwrap_or_default()
+        };
+        let location = record.location.as_ref();
+        let coordinate = |value: Option<f64>| value.map(|value| format!("{value:.4}")).unwrap_or_default();
+        City {
+            city: english(record.city.and_then(|city| city.names)),
+            region: english(record.subdivisions.and_then(|subdivisions| subdivisions.into_iter().next()?.names)),
+            postal: record.postal.and_then(|postal| postal.code).map(String::from).unwrap_or_default(),
+            lat: coordinate(location.and_then(|location| location.latitude)),
+            lon: coordinate(location.and_then(|location| location.longitude)),
+        }
+    }
+
+    /// Returns the number and organization of the autonomous system the IP is announced by,
+    /// which requires an ASN database
+    pub fn asn(&self, ip: IpAddr) -> Option<(u32, String)> {
+        let record: maxminddb::geoip2::Asn = self.reader.lookup(ip).ok()?;
+        let organization = record.autonomous_system_organization.unwrap_or_default().to_string();
+        Some((record.autonomous_system_number?, organization))
+    }
+}
+
+
+/// Stand-in used when built without the `geoip` feature, which refuses to open any database
+#[cfg(not(feature = "geoip"))]
+pub struct GeoIp;
+
+#[cfg(not(feature = "geoip"))]
+impl GeoIp {
+    pub fn open(_path: &str) -> Result<Self, Error> {
+        Err(Error::Other("GeoIP support is not available, ipstats was built without the `geoip` feature".to_string()))
+    }
+
+    pub fn country(&self, _ip: IpAddr) -> Option<String> {
+        None
+    }
+
+    pub fn city(&self, _ip: IpAddr) -> City {
+        City::default()
+    }
+
+    pub fn asn(&self, _ip: IpAddr) -> Option<(u32, String)> {
+        None
+    }
+}
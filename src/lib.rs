@@ -0,0 +1,2732 @@
+//! The engine behind the `ipstats` command line tool: extracting IPs from lines of text,
+//! counting them and rendering the counts in one of the supported output formats.
+//!
+//! Counting happens with [`process_file`] according to [`ProcessOptions`], the resulting
+//! [`Stats`] are rendered by [`print_stats`] according to [`ReportOptions`]. Nothing is
+//! printed directly, all output goes into the writer that is passed in, diagnostics go to the
+//! [`log`] crate for whatever logger the application sets up. [`StatsCollector`] covers the common
+//! cases of counting without setting up all of the options, [`Results`] walks the counts in
+//! order instead of printing them.
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::prelude::*;
+use std::net::{ IpAddr, Ipv4Addr };
+use std::ops::Range;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::cell::{ Cell, RefCell };
+use std::collections::{ HashMap, HashSet, VecDeque };
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+
+use regex::Regex;
+use rustc_hash::FxBuildHasher;
+use flate2::bufread::GzDecoder;
+use dns_lookup::lookup_addr;
+use anyhow::{ Context, Result, bail };
+use log::{ debug, info, warn };
+use serde::ser::{ Serialize, SerializeMap, Serializer };
+
+pub mod bitmap;
+pub mod class;
+pub mod collector;
+pub mod columnar;
+pub mod error;
+pub mod geoip;
+pub mod offsets;
+pub mod redact;
+pub mod source;
+pub mod results;
+pub mod serve;
+pub mod state;
+pub mod stix;
+pub mod syslog;
+pub mod whois;
+pub mod xml;
+
+use bitmap::Bitmap;
+use class::NetworkClass;
+use columnar::{ Compression, Table };
+use geoip::{ City, GeoIp };
+use offsets::Offsets;
+use source::{ ReaderSource, TailSource };
+use state::State;
+use syslog::{ Facility, Rfc5424, Severity };
+use whois::{ Whois, WhoisInfo };
+use xml::XmlPath;
+
+pub use collector::StatsCollector;
+pub use error::Error;
+pub use results::{ Entries, Entry, Family, Filters, Order, Resolver, Results, SortKey };
+pub use source::{ LineSource, Next };
+
+
+/// The counts per IP. Hashed with FxHash instead of the default SipHash, which is considerably
+/// faster on the hot path of counting. SipHash guards against input crafted to make all keys
+/// collide, which is not much of a concern for logs, and the worst case is a slow run, so create
+/// them with `Stats::default()`.
+pub type Stats = HashMap<String, u32, FxBuildHasher>;
+
+/// The first string matched for every key, before normalization, see {raw}
+pub type RawMatches = HashMap<String, String>;
+
+/// The distinct ports seen for every key, see {distinct_ports}
+pub type PortSets = HashMap<String, HashSet<u16>>;
+
+/// How often two keys were found on the same line, in the order they appear on it, see
+/// `--output-format dot`
+pub type PairCounts = HashMap<(String, String), u32>;
+
+/// What is collected per key next to its count in `Stats`, each only if it is set to an empty map
+/// before counting, as it costs memory
+#[derive(Clone, Default)]
+pub struct Details {
+    /// The first match of every key
+    pub raw: Option<RawMatches>,
+    /// The distinct ports of every key, found with `ProcessOptions::port_pattern`
+    pub ports: Option<PortSets>,
+    /// The keys found together on a line, which takes `ProcessOptions::key_end` to be more than one
+    pub pairs: Option<PairCounts>,
+}
+
+/// Matches IPv4-mapped IPv6 addresses like ::ffff:192.0.2.1 and plain IPv6 addresses
+pub const DEFAULT_PATTERN: &str = r"((::ffff:)(?:[0-9]{1,3}\.){3}[0-9]{1,3})|((([0-9a-f]{1,4}:){7}([0-9a-f]{1,4}|:))|(([0-9a-f]{1,4}:){6}(:[0-9a-f]{1,4}|((25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])(\.(25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])){3})|:))|(([0-9a-f]{1,4}:){5}(((:[0-9a-f]{1,4}){1,2})|:((25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])(\.(25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])){3})|:))|(([0-9a-f]{1,4}:){4}(((:[0-9a-f]{1,4}){1,3})|((:[0-9a-f]{1,4})?:((25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])(\.(25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])){3}))|:))|(([0-9a-f]{1,4}:){3}(((:[0-9a-f]{1,4}){1,4})|((:[0-9a-f]{1,4}){0,2}:((25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])(\.(25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])){3}))|:))|(([0-9a-f]{1,4}:){2}(((:[0-9a-f]{1,4}){1,5})|((:[0-9a-f]{1,4}){0,3}:((25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])(\.(25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])){3}))|:))|(([0-9a-f]{1,4}:){1}(((:[0-9a-f]{1,4}){1,6})|((:[0-9a-f]{1,4}){0,4}:((25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])(\.(25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])){3}))|:))|(:(((:[0-9a-f]{1,4}){1,7})|((:[0-9a-f]{1,4}){0,5}:((25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])(\.(25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])){3}))|:)))(%.+)?";
+
+/// MAC addresses separated by colons or dashes, or in the dotted form used by Cisco
+pub const MAC_PATTERN: &str = r"(?i)\b(?:[0-9a-f]{2}[:-]){5}[0-9a-f]{2}\b|\b(?:[0-9a-f]{4}\.){2}[0-9a-f]{4}\b";
+
+/// How long to wait before asking a `LineSource` again that had nothing new
+const PENDING_WAIT: Duration = Duration::from_millis(100);
+
+/// Called periodically by `process_file` with the stats collected so far
+pub type FlushFn<'a> = dyn FnMut(&Stats) -> std::result::Result<(), Error> + 'a;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// One line per IP, formatted according to --format
+    Text,
+    /// GraphViz digraph with one node per IP, sized by count
+    Dot,
+    /// Bare IPs, one per line, for use with `nmap -iL`
+    Nmap,
+    /// The --fields joined by --delimiter without any quoting, with space separated counts right aligned
+    Ssv,
+    /// JSON Lines, preceded by a line declaring the type of every field
+    JsonlSchema,
+    /// PTR records for a DNS zone file
+    Zone,
+    /// Binary Parquet file, for analytics tools like DuckDB
+    Parquet,
+    /// GitHub Flavored Markdown table, for reports and issues
+    Markdown,
+    /// LaTeX table, for papers and formal reports
+    Latex,
+    /// nginx `geo` block setting a variable for the reported IPs, e.g. to block them
+    NginxMap,
+    /// HAProxy `acl` lines matching the reported IPs, for a frontend section
+    HaproxyAcl,
+    /// Apache httpd directives denying access to the reported IPs
+    ApacheDeny,
+    /// `/etc/hosts` entries pointing the reported IPs at --hosts-domain
+    Hosts,
+    /// RFC 5424 syslog messages with the IP, count and hostname as structured data and --format as message
+    SyslogRfc5424,
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApacheVersion {
+    /// `Deny from` lines, for mod_authz_host of httpd 2.2
+    V22,
+    /// `Require not ip` lines in a `RequireAll` block, for httpd 2.4 and later
+    V24,
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tiebreak {
+    /// Sort entries with the same count by IP
+    Ip,
+    /// Sort entries with the same count alphabetically by hostname, clustering e.g. CDN nodes
+    Host,
+}
+
+
+/// What to count when a line has fewer matches than --key asks for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyFallback {
+    /// Count nothing for the line, as if it had no IP at all
+    None,
+    /// Count the first match instead
+    First,
+    /// Count the last match instead
+    Last,
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Firewall {
+    /// `iptables`/`ip6tables` commands appending DROP rules to the INPUT chain
+    Iptables,
+    /// `nft` commands adding drop rules to the input chain of the `inet filter` table
+    Nftables,
+    /// `add` lines for `ipset restore`, IPv6 addresses go to a set with a `6` suffix
+    Ipset,
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HumanFormat {
+    /// Abbreviate using SI suffixes, e.g. 1.2M
+    Si,
+    /// Group thousands with commas, e.g. 1,234,567
+    Comma,
+}
+
+
+/// Returns a reader transparently decompressing gzipped input, and whether it does
+pub fn get_reader(file: &mut impl Read) -> std::result::Result<(Box<dyn BufRead + '_>, bool), Error> {
+    let mut reader = BufReader::new(file);
+    if tree_magic_mini::match_u8(
+        "application/gzip",
+        reader.fill_buf().context("Could not peek into buffer to check for compression")?,
+    ) {
+        return Ok((Box::new(BufReader::new(GzDecoder::new(reader))), true));
+    }
+    Ok((Box::new(reader), false))
+}
+
+/// Randomly picks lines to process with a fixed probability.
+///
+/// Uses a small splitmix64 generator, which is plenty for sampling and keeps the
+/// sampling reproducible for a given seed.
+pub struct Sampler {
+    rate: f64,
+    state: Cell<u64>,
+}
+
+impl Sampler {
+    pub fn new(rate: f64, seed: Option<u64>) -> Self {
+        let seed = seed.unwrap_or_else(|| {
+            SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_nanos() as u64).unwrap_or_default()
+        });
+        Sampler { rate, state: Cell::new(seed) }
+    }
+
+    fn next(&self) -> u64 {
+        let state = self.state.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+        self.state.set(state);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn keep(&self) -> bool {
+        // Use the upper 53 bits to get a uniformly distributed float in [0, 1)
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < self.rate
+    }
+}
+
+/// The IPs of the last lines for --dedup-window, every occurrence keeps an IP in the window,
+/// whether it was counted or not
+struct DedupWindow {
+    size: u64,
+    lines: VecDeque<(u64, String)>,
+    /// Looked up for every counted IP, so hashed with FxHash like `Stats`
+    last_seen: HashMap<String, u64, FxBuildHasher>,
+}
+
+impl DedupWindow {
+    fn new(size: u64) -> Self {
+        DedupWindow { size, lines: VecDeque::new(), last_seen: HashMap::default() }
+    }
+
+    /// Records `ip` on line `line`, returns whether it was already seen in the lines before that
+    /// are still in the window
+    fn seen(&mut self, ip: &str, line: u64) -> bool {
+        while let Some((oldest, _)) = self.lines.front() {
+            if line - oldest < self.size {
+                break;
+            }
+            if let Some((oldest, ip)) = self.lines.pop_front() {
+                // Only forget the IP if this was its latest occurrence
+                if self.last_seen.get(&ip) == Some(&oldest) {
+                    self.last_seen.remove(&ip);
+                }
+            }
+        }
+        let seen = self.last_seen.insert(ip.to_string(), line).is_some();
+        self.lines.push_back((line, ip.to_string()));
+        seen
+    }
+}
+
+/// Everything that controls how IPs are extracted from the input
+pub struct ProcessOptions {
+    pub pattern: Regex,
+    pub key: usize,
+    /// Count every match from `key` up to this one, instead of only the `key`th
+    pub key_end: Option<usize>,
+    pub key_fallback: KeyFallback,
+    pub pedantic: bool,
+    pub fixed_ips: bool,
+    pub flush_interval: Option<Duration>,
+    pub head: Option<u64>,
+    pub head_total: Option<u64>,
+    pub slow_match: Option<Duration>,
+    pub sample: Option<Sampler>,
+    pub ipv4_as_ipv6: bool,
+    /// Count IPv4 addresses like `010.001.002.003` as 10.1.2.3, see `normalize_key`
+    pub normalize_octets: bool,
+    pub mac: bool,
+    /// Count the matches as they are, without stripping `::ffff:` from them
+    pub verbatim: bool,
+    /// Take the Nth field of the line instead of matching the pattern, split at `field_delimiter`
+    /// or at whitespace
+    pub field: Option<usize>,
+    pub field_delimiter: Option<char>,
+    /// Counted as `<label>:<ip>`, see `strip_label`
+    pub prepend_label: Option<String>,
+    /// Take the IP from this dotted path of lines that are JSON objects, instead of matching the pattern
+    pub json_field: Option<String>,
+    /// Read the input as XML documents and count the text of the elements at this path
+    pub xml_path: Option<XmlPath>,
+    pub max_unique: Option<usize>,
+    pub max_distinct: Option<usize>,
+    pub replacements: Vec<(Regex, String)>,
+    pub first_n_chars: Option<usize>,
+    /// Anchored to the end, see `normalize_key`
+    pub trim_suffix: Option<Regex>,
+    pub count_field: Option<usize>,
+    pub weight_pattern: Option<Regex>,
+    pub weight_key: usize,
+    /// Collected into `Details::ports`
+    pub port_pattern: Option<Regex>,
+    pub skip_lines: Option<u64>,
+    /// Only count the last N lines of every input, see `process_file`. With offsets, the last N of
+    /// what was appended since they were saved
+    pub tail_lines: Option<u64>,
+    /// Count an IP at most once per this many lines of an input
+    pub dedup_window: Option<u64>,
+    pub max_line_length: Option<usize>,
+    pub merge_reports: bool,
+    /// Skip malformed --merge-reports lines and files that cannot be opened, instead of failing
+    pub keep_going: bool,
+    /// Give up on an input after a read error, instead of failing altogether
+    pub skip_errors: bool,
+    pub bitmap: Option<Bitmap>,
+}
+
+impl ProcessOptions {
+    /// Counts every first match of `pattern` per line, with all other options turned off except
+    /// for `normalize_octets`
+    pub fn new(pattern: Regex) -> Self {
+        ProcessOptions {
+            pattern,
+            key: 1,
+            key_end: None,
+            key_fallback: KeyFallback::None,
+            pedantic: false,
+            fixed_ips: false,
+            flush_interval: None,
+            head: None,
+            head_total: None,
+            slow_match: None,
+            sample: None,
+            ipv4_as_ipv6: false,
+            normalize_octets: true,
+            mac: false,
+            verbatim: false,
+            field: None,
+            field_delimiter: None,
+            prepend_label: None,
+            json_field: None,
+            xml_path: None,
+            max_unique: None,
+            max_distinct: None,
+            replacements: Vec::new(),
+            first_n_chars: None,
+            trim_suffix: None,
+            count_field: None,
+            weight_pattern: None,
+            weight_key: 1,
+            port_pattern: None,
+            skip_lines: None,
+            tail_lines: None,
+            dedup_window: None,
+            max_line_length: None,
+            merge_reports: false,
+            keep_going: false,
+            skip_errors: false,
+            bitmap: None,
+        }
+    }
+
+    /// Picks the IP out of a line, either the --key th match of the pattern or the whole line
+    pub fn extract<'a>(&self, text: &'a str) -> Option<&'a str> {
+        self.locate(text).map(|range| &text[range])
+    }
+
+    /// Where in the line `extract` finds the IP
+    pub fn locate(&self, text: &str) -> Option<Range<usize>> {
+        self.locate_key(text).map(|(range, _)| range)
+    }
+
+    /// Like `locate`, and whether the line had too few matches for `key` so `key_fallback` was used
+    pub fn locate_key(&self, text: &str) -> Option<(Range<usize>, bool)> {
+        if self.fixed_ips {
+            let start = text.len() - text.trim_start().len();
+            Some((start..start + text.trim().len(), false))
+        } else if let Some(field) = self.field {
+            let line = text.trim_end_matches(['\r', '\n']);
+            match self.field_delimiter {
+                Some(delimiter) => line.split(delimiter).nth(field - 1),
+                None => line.split_whitespace().nth(field - 1),
+            }.filter(|value| !value.is_empty()).map(|value| {
+                // The field is a slice of the line, so its offset is where it starts
+                let start = value.as_ptr() as usize - text.as_ptr() as usize;
+                (start..start + value.len(), false)
+            })
+        } else {
+            self.nth_match(text)
+        }.map(|(range, fallback)| (self.truncate(text, range), fallback))
+    }
+
+    /// The `key`th match of the pattern, or the one `key_fallback` picks if there are fewer
+    fn nth_match(&self, text: &str) -> Option<(Range<usize>, bool)> {
+        let mut first = None;
+        let mut last = None;
+        for (i, m) in self.pattern.find_iter(text).enumerate() {
+            if i + 1 == self.key {
+                return Some((m.range(), false));
+            }
+            first.get_or_insert(m.range());
+            last = Some(m.range());
+        }
+        match self.key_fallback {
+            KeyFallback::None => None,
+            KeyFallback::First => first.map(|range| (range, true)),
+            KeyFallback::Last => last.map(|range| (range, true)),
+        }
+    }
+
+    /// Like `extract`, but with `key_end` every match from the `key`th to the `key_end`th, as far
+    /// as the line has them
+    pub fn extract_all<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        match self.key_end {
+            Some(end) if !self.fixed_ips && self.field.is_none() => {
+                self.pattern.find_iter(text)
+                    .skip(self.key - 1)
+                    .take(end + 1 - self.key)
+                    .map(|m| &text[self.truncate(text, m.range())])
+                    .collect()
+            }
+            _ => self.extract(text).into_iter().collect(),
+        }
+    }
+
+    /// Shortens the match at `range` to --first-n-chars
+    fn truncate(&self, text: &str, range: Range<usize>) -> Range<usize> {
+        match self.first_n_chars {
+            Some(n) => {
+                let length = text[range.clone()].char_indices().nth(n).map_or(range.len(), |(end, _)| end);
+                range.start..range.start + length
+            }
+            None => range,
+        }
+    }
+
+    /// Number of distinct IPs seen so far
+    pub fn unique(&self, stats: &Stats) -> usize {
+        self.bitmap.as_ref().map_or(stats.len(), Bitmap::len)
+    }
+
+    /// Returns the limit causing reading to stop altogether, if any limit across all inputs was hit
+    fn global_limit(&self, stats: &Stats, summary: &Summary) -> Option<Limit> {
+        if self.head_total.is_some_and(|head| summary.lines >= head) {
+            Some(Limit::HeadTotal)
+        } else if self.max_unique.is_some_and(|max| self.unique(stats) >= max) {
+            Some(Limit::MaxUnique)
+        } else {
+            None
+        }
+    }
+}
+
+/// Turns a match into the key it is counted under
+pub fn normalize_key(m: &str, opts: &ProcessOptions) -> String {
+    if opts.mac {
+        return normalize_mac(m);
+    }
+    // We Strip ::ffff: from the start of the collected IP since it is used to
+    // express mappable addresses like ::ffff:192.168.1.1, which only seem to properly
+    // resolve when the prefix is stripped, since we accept a custom regex we cannot
+    // rely on the regex matching things the right way, so we always make sure we
+    // strip that off the match. With --ipv4-as-ipv6 it is added back consistently.
+    // Values counted with --count-field-values are no IPs, so they are left alone.
+    let mut key = if opts.verbatim { m } else { m.strip_prefix("::ffff:").unwrap_or(m) };
+    if let Some(suffix) = &opts.trim_suffix {
+        // The pattern is anchored to the end, so this is only ever the trailing match
+        if let Some(found) = suffix.find(key) {
+            key = &key[..found.start()];
+        }
+    }
+    let mut key = Cow::Borrowed(key);
+    if opts.normalize_octets && !opts.verbatim {
+        if let Some(ip) = strip_octet_zeros(&key) {
+            key = Cow::Owned(ip.to_string());
+        }
+    }
+    if opts.ipv4_as_ipv6 {
+        if let Ok(ip) = key.parse::<Ipv4Addr>() {
+            key = Cow::Owned(format!("::ffff:{ip}"));
+        }
+    }
+    match &opts.prepend_label {
+        Some(label) => format!("{label}:{key}"),
+        None => key.into_owned(),
+    }
+}
+
+/// Parses IPv4 addresses with leading zeros in their octets, like `010.001.002.003` from some
+/// embedded devices, which `Ipv4Addr` refuses. The octets are always read as decimal, while
+/// inet_aton would take them as octal.
+fn strip_octet_zeros(key: &str) -> Option<Ipv4Addr> {
+    let octets: Vec<&str> = key.split('.').collect();
+    if octets.len() != 4 || !octets.iter().any(|octet| octet.len() > 1 && octet.starts_with('0')) {
+        return None;
+    }
+    let mut parsed = [0; 4];
+    for (octet, value) in octets.iter().zip(parsed.iter_mut()) {
+        if octet.is_empty() || octet.len() > 3 || !octet.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        *value = octet.parse().ok()?;
+    }
+    Some(Ipv4Addr::from(parsed))
+}
+
+/// The address of a key without the label added by --prepend-label, keys that are an IP on their
+/// own are never split, no matter how many colons they have
+pub fn strip_label(key: &str) -> &str {
+    match key.split_once(':') {
+        Some((label, ip)) if !label.is_empty() && key.parse::<IpAddr>().is_err() && ip.parse::<IpAddr>().is_ok() => ip,
+        _ => key,
+    }
+}
+
+/// Brings a MAC address into the lowercase, colon separated form, e.g. 00:1a:2b:3c:4d:5e
+/// for 001A.2B3C.4D5E, anything not made of exactly 12 hex digits is kept as is
+fn normalize_mac(m: &str) -> String {
+    let digits: Vec<char> = m.chars()
+        .filter(|c| !matches!(c, ':' | '-' | '.'))
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    if digits.len() != 12 || !digits.iter().all(char::is_ascii_hexdigit) {
+        return m.to_string();
+    }
+    digits.chunks(2).map(|pair| pair.iter().collect::<String>()).collect::<Vec<_>>().join(":")
+}
+
+/// Counts every pair of different keys of one line once, the earlier one first
+fn count_pairs(pairs: &mut PairCounts, keys: &[String]) {
+    for (i, a) in keys.iter().enumerate() {
+        // Later occurrences of a key were already paired up at its first one
+        if keys[..i].contains(a) {
+            continue;
+        }
+        for (j, b) in keys.iter().enumerate().skip(i + 1) {
+            if b != a && !keys[..j].contains(b) {
+                *pairs.entry((a.clone(), b.clone())).or_insert(0) += 1;
+            }
+        }
+    }
+}
+
+/// The value at the dotted `path` of a line holding a JSON object, e.g. `request.client.ip`, if it is
+/// a string or a number
+fn json_field(text: &str, path: &str) -> serde_json::Result<Option<String>> {
+    let object: serde_json::Map<String, serde_json::Value> = serde_json::from_str(text)?;
+    let mut keys = path.split('.');
+    let mut value = keys.next().and_then(|key| object.get(key));
+    for key in keys {
+        value = value.and_then(|value| value.get(key));
+    }
+    Ok(match value {
+        Some(serde_json::Value::String(value)) => Some(value.clone()),
+        Some(serde_json::Value::Number(value)) => Some(value.to_string()),
+        _ => None,
+    })
+}
+
+/// Finds the Nth (starting at 1) match of the pattern and parses it, or its first capture group
+/// if it has one, e.g. as the line's repeat count
+fn extract_number<T: std::str::FromStr>(pattern: &Regex, key: usize, text: &str) -> Option<T> {
+    let captures = pattern.captures_iter(text).nth(key - 1)?;
+    let m = captures.get(1).or_else(|| captures.get(0))?;
+    m.as_str().parse().ok()
+}
+
+/// Parses a line of a previous report, either `{cnt} {ip}` (the --numeric default) or
+/// `{cnt} {host} ({ip})` (the default), into its count and IP
+pub fn parse_report_line(line: &str) -> Option<(u32, &str)> {
+    let mut fields = line.split_whitespace();
+    let count = fields.next()?.parse().ok()?;
+    match fields.collect::<Vec<_>>().as_slice() {
+        [ip] => Some((count, ip)),
+        [_host, ip] => ip.strip_prefix('(')?.strip_suffix(')').map(|ip| (count, ip)),
+        _ => None,
+    }
+}
+
+/// The limit that stopped reading before the end of the input
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    /// `head` lines were read from one input, the others are still read
+    Head,
+    /// `head_total` lines were read across all inputs
+    HeadTotal,
+    /// `max_unique` distinct IPs were seen
+    MaxUnique,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Limit::Head => "--head",
+            Limit::HeadTotal => "--head-total",
+            Limit::MaxUnique => "--max-unique",
+        })
+    }
+}
+
+/// Bookkeeping about the processed input, printed to stderr with --summary
+#[derive(Default)]
+pub struct Summary {
+    pub lines: u64,
+    pub sampled: Option<u64>,
+    pub too_long: Option<u64>,
+    pub not_ipv4: Option<u64>,
+    /// Lines with fewer matches than `key`, counted with the one `key_fallback` picked
+    pub key_fallbacks: Option<u64>,
+    pub deduplicated: Option<u64>,
+    pub truncated_by: Option<Limit>,
+    /// Inputs abandoned after a read error with `skip_errors`
+    pub incomplete: Vec<String>,
+    /// Files that could not be opened with `keep_going`
+    pub skipped: Vec<String>,
+    /// Wall-clock time spent reading the input
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Lines read: {}", self.lines)?;
+        if let Some(sampled) = self.sampled {
+            writeln!(f, "Lines sampled: {sampled}")?;
+        }
+        if let Some(too_long) = self.too_long {
+            writeln!(f, "Lines skipped for exceeding --max-line-length: {too_long}")?;
+        }
+        if let Some(not_ipv4) = self.not_ipv4 {
+            writeln!(f, "Matches ignored for not being IPv4 with --bitmap: {not_ipv4}")?;
+        }
+        if let Some(fallbacks) = self.key_fallbacks {
+            writeln!(f, "Lines counted with the --key-fallback match: {fallbacks}")?;
+        }
+        if let Some(deduplicated) = self.deduplicated {
+            writeln!(f, "Matches skipped by --dedup-window: {deduplicated}")?;
+        }
+        if let Some(limit) = self.truncated_by {
+            writeln!(f, "Input was truncated by {limit}")?;
+        }
+        if !self.skipped.is_empty() {
+            writeln!(f, "Files that could not be opened: {}", self.skipped.join(", "))?;
+        }
+        if !self.incomplete.is_empty() {
+            writeln!(f, "Incomplete after read errors: {}", self.incomplete.join(", "))?;
+        }
+        let throughput = self.lines as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON);
+        writeln!(f, "Time elapsed: {:.2?}", self.elapsed)?;
+        writeln!(f, "Throughput: {throughput:.0} lines/sec")
+    }
+}
+
+/// Counts the IPs in `file` into `stats`, gzipped input is decompressed on the fly. Returns the
+/// number of bytes consumed, unless the input was compressed.
+///
+/// `name` is only used in messages. Whatever `details` asks for is collected into it, if given,
+/// and `flush` is handed the stats so far every `opts.flush_interval`.
+///
+/// ```
+/// use std::io::Cursor;
+/// use ipstats::{ DEFAULT_PATTERN, ProcessOptions, Stats, Summary, process_file };
+///
+/// let opts = ProcessOptions::new(regex::Regex::new(DEFAULT_PATTERN).unwrap());
+/// let mut input = Cursor::new("GET / from ::ffff:192.0.2.1\nGET /x from ::ffff:192.0.2.1\n");
+/// let mut stats = Stats::default();
+/// process_file(&mut input, "example", &mut stats, &opts, &mut Summary::default(), None, None)?;
+/// assert_eq!(stats["192.0.2.1"], 2);
+/// # Ok::<(), ipstats::Error>(())
+/// ```
+pub fn process_file(
+    file: &mut impl Read,
+    name: &str,
+    stats: &mut Stats,
+    opts: &ProcessOptions,
+    summary: &mut Summary,
+    details: Option<&mut Details>,
+    flush: Option<&mut FlushFn>,
+) -> std::result::Result<Option<u64>, Error> {
+    count_lines(file, name, stats, opts, summary, details, flush, Layout::of(opts)).map_err(Error::from)
+}
+
+/// Counts the IPs of every line of `source` into `stats`, just like `process_file` does for a
+/// reader. When the source has nothing new yet, it is asked again after a short pause until it
+/// reports the end of its input or one of the limits in `opts` is hit.
+pub fn process_source(
+    source: &mut impl LineSource,
+    stats: &mut Stats,
+    opts: &ProcessOptions,
+    summary: &mut Summary,
+    details: Option<&mut Details>,
+    flush: Option<&mut FlushFn>,
+) -> std::result::Result<(), Error> {
+    count_source(source, stats, opts, summary, details, flush, opts.skip_lines).map_err(Error::from)
+}
+
+/// Which lines of an input `count_lines` reads, which differs from what the options ask for once
+/// `process_files` positioned the file
+#[derive(Clone, Copy)]
+struct Layout {
+    /// None once the file was moved to where its last lines start
+    tail_lines: Option<u64>,
+    /// None when resuming with --state-dir, the header was left behind on the first run
+    skip_lines: Option<u64>,
+    /// Leave an unterminated last line for the next run with --state-dir, it may still be written
+    whole_lines: bool,
+}
+
+impl Layout {
+    fn of(opts: &ProcessOptions) -> Self {
+        Layout { tail_lines: opts.tail_lines, skip_lines: opts.skip_lines, whole_lines: false }
+    }
+}
+
+/// Like `process_file`, reading the lines given by `layout` instead of those in `opts`
+#[allow(clippy::too_many_arguments)]
+fn count_lines(
+    mut file: &mut impl Read,
+    name: &str,
+    stats: &mut Stats,
+    opts: &ProcessOptions,
+    summary: &mut Summary,
+    details: Option<&mut Details>,
+    flush: Option<&mut FlushFn>,
+    layout: Layout,
+) -> Result<Option<u64>> {
+    let (reader, compressed) = get_reader(&mut file).context("Failed getting reader")?;
+    if compressed {
+        info!("Decompressing {name} as gzip");
+    }
+    if let Some(path) = &opts.xml_path {
+        let raw = details.and_then(|details| details.raw.as_mut());
+        path.count(reader, name, stats, opts, summary, raw, layout.skip_lines)?;
+        // Documents do not line up with lines, there is no telling where to resume
+        return Ok(None);
+    }
+    let mut source = ReaderSource::new(reader, name, opts.max_line_length);
+    if layout.whole_lines {
+        source = source.whole_lines();
+    }
+    if let Some(lines) = layout.tail_lines {
+        // Everything has to be read to know what the last lines are, so there is nothing to resume
+        let mut source = TailSource::new(source, lines as usize);
+        count_source(&mut source, stats, opts, summary, details, flush, layout.skip_lines)?;
+        return Ok(None);
+    }
+    count_source(&mut source, stats, opts, summary, details, flush, layout.skip_lines)?;
+    Ok((!compressed).then_some(source.consumed()))
+}
+
+fn count_source(
+    source: &mut impl LineSource,
+    stats: &mut Stats,
+    opts: &ProcessOptions,
+    summary: &mut Summary,
+    mut details: Option<&mut Details>,
+    mut flush: Option<&mut FlushFn>,
+    skip_lines: Option<u64>,
+) -> Result<()> {
+    let name = &source.name().to_string();
+    if let Some(size) = source.len_hint() {
+        debug!("Expecting {size} bytes from {name}");
+    }
+    let mut line = String::new();
+    let mut last_flush = Instant::now();
+    let mut lines: u64 = 0;
+    let mut matched: u64 = 0;
+    let mut position: u64 = 0;
+    let mut window = opts.dedup_window.map(DedupWindow::new);
+
+    loop {
+        let limit = if opts.head.is_some_and(|head| lines >= head) {
+            Some(Limit::Head)
+        } else {
+            opts.global_limit(stats, summary)
+        };
+        if let Some(limit) = limit {
+            // Only a limit that left something unread truncated the input, a read error
+            // past it does not change that
+            if !source.is_exhausted().unwrap_or(false) {
+                summary.truncated_by = Some(limit);
+            }
+            break;
+        }
+
+        let next = match source.next_line(&mut line) {
+            Ok(next) => next,
+            Err(err) if opts.skip_errors => {
+                warn!(file = name.as_str(); "Could not read {name} after {position} bytes, skipping the rest of it: {err}");
+                summary.incomplete.push(name.to_string());
+                break;
+            }
+            Err(err) => return Err(err).context("Reading next line"),
+        };
+        match next {
+            Next::Eof => { break }
+            Next::Pending => {
+                std::thread::sleep(PENDING_WAIT);
+                continue;
+            }
+            Next::Line { bytes: bytes_read, too_long } => {
+                // Sources that do not skip overly long lines themselves hand them over in full
+                let content = line.trim_end_matches(['\r', '\n']);
+                let too_long = too_long || opts.max_line_length.is_some_and(|max| content.len() > max);
+                position += bytes_read as u64;
+                lines += 1;
+                summary.lines += 1;
+
+                // Skip header lines at the start of every input
+                if skip_lines.is_some_and(|skip| lines <= skip) {
+                    line.clear();
+                    continue;
+                }
+
+                // Overly long lines are usually garbage and only slow the regex down
+                if too_long {
+                    if opts.pedantic {
+                        let message = format!("Line {lines} of {name} is {bytes_read} bytes long, exceeding --max-line-length");
+                        bail!(Error::input(name, lines, message));
+                    }
+                    *summary.too_long.get_or_insert(0) += 1;
+                    line.clear();
+                    continue;
+                }
+
+                // Sampling happens before anything else, so skipped lines are as cheap as possible
+                if let Some(sample) = &opts.sample {
+                    if !sample.keep() {
+                        line.clear();
+                        continue;
+                    }
+                    *summary.sampled.get_or_insert(0) += 1;
+                }
+
+                // Previously generated reports already carry the count and the IP
+                if opts.merge_reports {
+                    match parse_report_line(&line) {
+                        Some((count, ip)) => {
+                            matched += 1;
+                            stats.entry(normalize_key(ip, opts))
+                                .and_modify(|counter: &mut u32| *counter = counter.saturating_add(count))
+                                .or_insert(count);
+                        }
+                        None if line.trim().is_empty() => {}
+                        None if opts.keep_going => {
+                            warn!(file = name.as_str(), line = lines; "Skipping malformed report line {lines} of {name}: {line:?}");
+                        }
+                        None => bail!(Error::input(name, lines, format!("Malformed report line {lines}: {line:?}"))),
+                    }
+                    line.clear();
+                    continue;
+                }
+
+                // Rewrite the line with all --replace-pattern substitutions, in order
+                let text = if opts.replacements.is_empty() {
+                    Cow::Borrowed(line.as_str())
+                } else {
+                    let mut text = line.clone();
+                    for (pattern, replacement) in opts.replacements.iter() {
+                        text = pattern.replace_all(&text, replacement.as_str()).into_owned();
+                    }
+                    Cow::Owned(text)
+                };
+
+                // Structured logs carry the IP in a field of their own
+                let value = match &opts.json_field {
+                    Some(path) => match json_field(&text, path) {
+                        Ok(value) => value,
+                        Err(err) if opts.pedantic => {
+                            bail!(Error::input(name, lines, format!("Line {lines} is not a JSON object: {err}")))
+                        }
+                        Err(err) => {
+                            warn!(file = name.as_str(), line = lines; "Skipping line {lines} of {name}, it is not a JSON object: {err}");
+                            line.clear();
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
+
+                // Either use the line almost as-is, or apply the pattern to exract IPs
+                let started = opts.slow_match.map(|_| Instant::now());
+                let found = if opts.json_field.is_some() {
+                    value.as_deref().into_iter().collect()
+                } else if opts.key_end.is_some() {
+                    opts.extract_all(&text)
+                } else {
+                    let located = opts.locate_key(&text);
+                    if located.as_ref().is_some_and(|(_, fallback)| *fallback) {
+                        *summary.key_fallbacks.get_or_insert(0) += 1;
+                    }
+                    located.map(|(range, _)| &text[range]).into_iter().collect()
+                };
+                let m = found.first().copied();
+                if let (Some(started), Some(slow_match)) = (started, opts.slow_match) {
+                    let elapsed = started.elapsed();
+                    if elapsed > slow_match {
+                        warn!(
+                            file = name.as_str(), line = lines;
+                            "Matching line {lines} of {name} took {elapsed:.1?}: {:?}",
+                            line.chars().take(200).collect::<String>(),
+                        );
+                    }
+                }
+
+                // Pre-aggregated input carries its own count, lines without a usable one
+                // are treated like lines without an IP, while a missing --weight-pattern
+                // match just counts the line once
+                let weight = if let Some(pattern) = &opts.weight_pattern {
+                    Some(extract_number(pattern, opts.weight_key, &text).unwrap_or(1))
+                } else if let Some(field) = opts.count_field {
+                    text.split_whitespace().nth(field - 1).and_then(|count| count.parse().ok())
+                } else {
+                    Some(1)
+                };
+
+                // Either increment the counter for the IP or bail out if none was found and we are
+                // running in pedantic mode.
+                if let (Some(_), Some(bitmap)) = (m, &opts.bitmap) {
+                    for &m in &found {
+                        matched += 1;
+                        match normalize_key(m, opts).parse::<Ipv4Addr>() {
+                            Ok(ip) => bitmap.insert(ip),
+                            Err(_) if opts.pedantic => {
+                                bail!(Error::input(name, lines, format!("--bitmap only supports IPv4, got {m:?}")))
+                            }
+                            Err(_) => *summary.not_ipv4.get_or_insert(0) += 1,
+                        }
+                    }
+                } else if let (Some(_), Some(weight)) = (m, weight) {
+                    let port = opts.port_pattern.as_ref().and_then(|pattern| extract_number(pattern, 1, &text));
+                    let mut on_line = Vec::new();
+                    for &m in &found {
+                        matched += 1;
+                        let ip = normalize_key(m, opts);
+                        if details.as_ref().is_some_and(|details| details.pairs.is_some()) && found.len() > 1 {
+                            on_line.push(ip.clone());
+                        }
+                        if window.as_mut().is_some_and(|window| window.seen(&ip, lines)) {
+                            *summary.deduplicated.get_or_insert(0) += 1;
+                            continue;
+                        }
+                        if let Some(details) = details.as_mut() {
+                            if let Some(raw) = details.raw.as_mut().filter(|raw| !raw.contains_key(&ip)) {
+                                raw.insert(ip.clone(), m.to_string());
+                            }
+                            if let (Some(ports), Some(port)) = (details.ports.as_mut(), port) {
+                                ports.entry(ip.clone()).or_default().insert(port);
+                            }
+                        }
+                        stats.entry(ip)
+                            .and_modify(|counter: &mut u32| *counter = counter.saturating_add(weight))
+                            .or_insert(weight);
+                        // Better to give up with a clear message than to get killed for running out of memory
+                        if let Some(max) = opts.max_distinct.filter(|max| stats.len() > *max) {
+                            bail!(Error::TooManyDistinct {
+                                context: format!("Found more than {max} distinct IPs, giving up (use --max-unique to print a partial report instead)"),
+                                max,
+                            });
+                        }
+                    }
+                    if let Some(pairs) = details.as_mut().and_then(|details| details.pairs.as_mut()) {
+                        count_pairs(pairs, &on_line);
+                    }
+                } else if opts.pedantic && weight.is_none() {
+                    bail!(Error::input(name, lines, format!("Could not extract count from line: {line:?}")));
+                } else if opts.pedantic {
+                    bail!(Error::input(name, lines, format!("Could not extract IP from line: {line:?}")));
+                } else if m.is_none() {
+                    debug!("No IP in line {lines} of {name}: {:?}", line.chars().take(200).collect::<String>());
+                } else {
+                    debug!("No count in line {lines} of {name}: {:?}", line.chars().take(200).collect::<String>());
+                }
+
+                line.clear();
+
+                // Periodically report what we have so far, the interval is only checked
+                // as lines come in, so a stalled input will not produce any snapshots
+                if let (Some(flush), Some(interval)) = (flush.as_mut(), opts.flush_interval) {
+                    if last_flush.elapsed() >= interval {
+                        flush(stats)?;
+                        last_flush = Instant::now();
+                    }
+                }
+            }
+        };
+    }
+    info!("Finished {name}: {lines} lines, {matched} matches");
+    Ok(())
+}
+
+/// Everything that controls how the collected stats are filtered and rendered
+pub struct ReportOptions {
+    pub max_results: Option<usize>,
+    /// Keys reported no matter the threshold and max_results, see `select`
+    pub always_show: HashSet<String>,
+    pub reverse: bool,
+    pub tiebreak: Tiebreak,
+    pub numeric: bool,
+    pub threshold: Option<u32>,
+    pub format: String,
+    pub output_format: OutputFormat,
+    pub human: Option<HumanFormat>,
+    pub geoip: Option<GeoIp>,
+    pub geo_city: Option<GeoIp>,
+    pub country_filter: Option<Vec<String>>,
+    pub country_exclude: Option<Vec<String>>,
+    pub network_class: bool,
+    pub class_filter: Option<NetworkClass>,
+    pub nmap_cidr: bool,
+    pub whois: Option<Whois>,
+    pub sample_rate: Option<f64>,
+    pub baseline: Option<Stats>,
+    pub only_new: bool,
+    pub min_delta: Option<i64>,
+    /// Adds {cumulative} and {cumulative_percent} to the records of `jsonl-schema`
+    pub running_total: bool,
+    pub fields: Vec<String>,
+    pub delimiter: char,
+    pub escape: String,
+    pub header: bool,
+    /// Spread the objects of `jsonl-schema` over multiple indented lines
+    pub json_pretty: bool,
+    /// The file being reported on with --separate
+    pub file: Option<String>,
+    /// The --label of the records being reported
+    pub source: Option<String>,
+    /// Only collected when the format uses {raw}
+    pub raw: Option<RawMatches>,
+    /// Only collected with --distinct-ports
+    pub ports: Option<PortSets>,
+    pub sort_by_ports: bool,
+    /// Drawn as edges by `--output-format dot`
+    pub pairs: Option<PairCounts>,
+    pub zone_ttl: Option<u32>,
+    pub latex_caption: Option<String>,
+    pub latex_label: Option<String>,
+    pub nginx_map_var: String,
+    pub nginx_map_value: String,
+    pub haproxy_acl_name: String,
+    pub haproxy_action: Option<String>,
+    pub apache_version: ApacheVersion,
+    /// Written as comment lines at the top of `apache-deny`, e.g. when and how it was generated
+    pub apache_comment: Option<String>,
+    pub hosts_domain: String,
+    pub emit_rules: Option<Firewall>,
+    pub ipset_name: String,
+    pub syslog_facility: Facility,
+    pub syslog_severity: Severity,
+    pub parquet_compression: Compression,
+    pub hosts: HostCache,
+}
+
+impl ReportOptions {
+    /// Reports every IP as text according to `format`, like the command line does by default,
+    /// but without looking up any hostnames
+    pub fn new(format: impl Into<String>) -> Self {
+        ReportOptions {
+            max_results: None,
+            always_show: HashSet::new(),
+            reverse: false,
+            tiebreak: Tiebreak::Ip,
+            numeric: true,
+            threshold: None,
+            format: format.into(),
+            output_format: OutputFormat::Text,
+            human: None,
+            geoip: None,
+            geo_city: None,
+            country_filter: None,
+            country_exclude: None,
+            network_class: false,
+            class_filter: None,
+            nmap_cidr: false,
+            whois: None,
+            sample_rate: None,
+            baseline: None,
+            only_new: false,
+            min_delta: None,
+            running_total: false,
+            fields: vec![String::from("cnt"), String::from("ip")],
+            delimiter: ' ',
+            escape: String::from("_"),
+            header: false,
+            json_pretty: false,
+            file: None,
+            source: None,
+            raw: None,
+            ports: None,
+            sort_by_ports: false,
+            pairs: None,
+            zone_ttl: None,
+            latex_caption: None,
+            latex_label: None,
+            nginx_map_var: String::from("blocked"),
+            nginx_map_value: String::from("1"),
+            haproxy_acl_name: String::from("blocked_ips"),
+            haproxy_action: None,
+            apache_version: ApacheVersion::V24,
+            apache_comment: None,
+            hosts_domain: String::from("blocked.local"),
+            emit_rules: None,
+            ipset_name: String::from("blocked_ips"),
+            syslog_facility: Facility::Local0,
+            syslog_severity: Severity::Info,
+            parquet_compression: Compression::Snappy,
+            hosts: HostCache::default(),
+        }
+    }
+}
+
+/// Remembers resolved hostnames, as the same IPs get reported again with --flush-interval
+/// or when writing additional outputs like --ioc-output
+#[derive(Default)]
+pub struct HostCache {
+    skip_v4: bool,
+    skip_v6: bool,
+    hosts: RefCell<HashMap<IpAddr, String>>,
+    pub hits: Cell<u64>,
+    pub misses: Cell<u64>,
+    /// Minimum time between two lookups, from --dns-rate
+    interval: Option<Duration>,
+    last_lookup: Cell<Option<Instant>>,
+}
+
+impl HostCache {
+    /// Skips the lookups for IPv4 or IPv6 addresses as requested, and does at most `rate`
+    /// lookups per second if given
+    pub fn new(skip_v4: bool, skip_v6: bool, rate: Option<u32>) -> Self {
+        HostCache {
+            skip_v4,
+            skip_v6,
+            interval: rate.map(|rate| Duration::from_secs(1) / rate),
+            ..HostCache::default()
+        }
+    }
+
+    /// Waits until the next lookup is allowed by --dns-rate. Lookups happen one after the
+    /// other, so spacing them out evenly is all it takes to stay below the rate.
+    fn throttle(&self) {
+        if let (Some(interval), Some(last)) = (self.interval, self.last_lookup.get()) {
+            if let Some(wait) = interval.checked_sub(last.elapsed()) {
+                std::thread::sleep(wait);
+            }
+        }
+        self.last_lookup.set(Some(Instant::now()));
+    }
+
+    /// Looks up the hostname of an IP, or hands back the IP itself if there is none or the
+    /// lookup failed. Only an unparsable IP is an error.
+    pub fn lookup(&self, key: &str) -> std::result::Result<String, Error> {
+        self.lookup_with(key, lookup_addr).map_err(Error::from)
+    }
+
+    /// `lookup` with the actual resolver passed in
+    fn lookup_with(&self, key: &str, resolve: impl FnOnce(&IpAddr) -> io::Result<String>) -> Result<String> {
+        let key = strip_label(key);
+        let ip: IpAddr = key.parse().with_context(|| format!("Could not parse IP: {key}"))?;
+        // IPv4-mapped addresses (see --ipv4-as-ipv6) only resolve in their plain IPv4 form
+        let ip = ip.to_canonical();
+        // Just like a missing PTR record, a skipped lookup results in the IP itself
+        let skip = match ip {
+            IpAddr::V4(_) => self.skip_v4,
+            IpAddr::V6(_) => self.skip_v6,
+        };
+        if skip {
+            return Ok(key.to_string());
+        }
+        if let Some(host) = self.hosts.borrow().get(&ip) {
+            self.hits.set(self.hits.get() + 1);
+            debug!("DNS cache hit for {ip}: {host}");
+            return Ok(host.clone());
+        }
+        self.misses.set(self.misses.get() + 1);
+        self.throttle();
+        let started = Instant::now();
+        // A failure is remembered like NXDOMAIN, which already comes back as the IP, so a
+        // struggling resolver is not asked again for every flush and every extra output
+        let host = match resolve(&ip) {
+            Ok(host) => {
+                debug!("Resolved {ip} to {host} in {:.1?}", started.elapsed());
+                host
+            }
+            Err(err) => {
+                debug!("Could not lookup host for IP {key}: {err}");
+                key.to_string()
+            }
+        };
+        self.hosts.borrow_mut().insert(ip, host.clone());
+        Ok(host)
+    }
+}
+
+/// Filters, sorts and limits the stats according to the options, in the order they are reported.
+///
+/// The keys in `always_show` skip the threshold and max_results, but not the other filters. When
+/// they would have been cut, they are added back in their place in the sort order, so the report
+/// can be longer than max_results. Keys that were never counted are not shown.
+pub fn select<'a>(stats: &'a Stats, opts: &ReportOptions) -> Vec<(&'a String, &'a u32)> {
+    // If a threshold is passed, drop all values below threshold, the keys to always show are added back
+    let mut sorted: Vec<_> = if let Some(threshold) = opts.threshold {
+        let shown = opts.always_show.iter().filter_map(|key| stats.get_key_value(key));
+        stats.iter().collect::<Entries>()
+            .above(threshold)
+            .filter(|entry| !opts.always_show.contains(entry.ip))
+            .filter_map(|entry| stats.get_key_value(entry.ip))
+            .chain(shown)
+            .collect()
+    } else {
+        stats.iter().collect()
+    };
+
+    // Drop everything not matching the country filters, IPs without a known country
+    // never match an inclusive filter
+    if let Some(geoip) = &opts.geoip {
+        if opts.country_filter.is_some() || opts.country_exclude.is_some() {
+            sorted.retain(|(key, _)| {
+                let country = strip_label(key).parse().ok().and_then(|ip| geoip.country(ip));
+                let included = opts.country_filter.as_ref().is_none_or(|filter| {
+                    country.as_ref().is_some_and(|country| filter.contains(country))
+                });
+                let excluded = opts.country_exclude.as_ref().is_some_and(|exclude| {
+                    country.as_ref().is_some_and(|country| exclude.contains(country))
+                });
+                included && !excluded
+            });
+        }
+    }
+
+    if let Some(class) = opts.class_filter {
+        sorted.retain(|(key, _)| strip_label(key).parse().is_ok_and(|ip| NetworkClass::of(ip) == class));
+    }
+
+    // Compare against the baseline, if any, and sort by the change instead of the count
+    if let Some(baseline) = &opts.baseline {
+        if opts.only_new {
+            sorted.retain(|(key, _)| !baseline.contains_key(*key));
+        }
+        if let Some(min_delta) = opts.min_delta {
+            sorted.retain(|(key, value)| delta(baseline, key, **value) >= min_delta);
+        }
+    }
+
+    // Sort by the distinct ports, the change or the count, in printed order. Ties are listed alphabetically
+    // either way, instead of in whatever order the map happens to hand them out.
+    let primary = |key: &str, value: u32| match (&opts.ports, &opts.baseline) {
+        (Some(ports), _) if opts.sort_by_ports => ports.get(key).map_or(0, HashSet::len) as i64,
+        (_, Some(baseline)) => delta(baseline, key, value),
+        _ => value as i64,
+    };
+    let by_primary = |a: &(&String, &u32), b: &(&String, &u32)| {
+        let order = primary(a.0, *a.1).cmp(&primary(b.0, *b.1));
+        if opts.reverse { order.reverse() } else { order }
+    };
+    sorted.sort_by(|a, b| by_primary(a, b).then_with(|| compare_keys(a.0, b.0)));
+
+    // Only what is actually reported gets looked up, the results are cached for printing
+    let by_host = opts.tiebreak == Tiebreak::Host && !opts.numeric;
+    let sort_by_host = |entries: &mut [(&'a String, &'a u32)]| {
+        let hosts: HashMap<&String, String> = entries.iter()
+            .map(|(key, _)| (*key, opts.hosts.lookup(key).unwrap_or_else(|_| key.to_string())))
+            .collect();
+        entries.sort_by(|a, b| by_primary(a, b).then_with(|| hosts[a.0].cmp(&hosts[b.0])));
+    };
+
+    // Which of the entries tied at the cutoff make it is up to their hosts as well
+    if let Some(max_results) = opts.max_results.filter(|max| by_host && *max > 0 && *max < sorted.len()) {
+        let cut = if opts.reverse { max_results } else { sorted.len() - max_results };
+        let boundary = primary(sorted[cut].0, *sorted[cut].1);
+        let tied = |entry: &(&String, &u32)| primary(entry.0, *entry.1) == boundary;
+        let start = sorted[..cut].iter().rposition(|entry| !tied(entry)).map_or(0, |i| i + 1);
+        let end = sorted[cut..].iter().position(|entry| !tied(entry)).map_or(sorted.len(), |i| cut + i);
+        if start < cut {
+            sort_by_host(&mut sorted[start..end]);
+        }
+    }
+
+    // Apply limit if `max_results` is passed, the heavy hitters are at the end unless reversed
+    let forced: Vec<_> = sorted.iter().filter(|(key, _)| opts.always_show.contains(*key)).copied().collect();
+    let mut selected: Vec<_> = match opts.max_results {
+        Some(max_results) if opts.reverse => sorted.into_iter().take(max_results).collect(),
+        Some(max_results) => sorted.split_off(sorted.len().saturating_sub(max_results)),
+        None => sorted,
+    };
+    let missing: Vec<_> = forced.into_iter().filter(|entry| !selected.contains(entry)).collect();
+    if !missing.is_empty() {
+        selected.extend(missing);
+        selected.sort_by(|a, b| by_primary(a, b).then_with(|| compare_keys(a.0, b.0)));
+    }
+
+    if by_host {
+        sort_by_host(&mut selected);
+    }
+    selected
+}
+
+/// Orders IPs numerically, IPv4 before IPv6, and anything else after them as text
+fn compare_keys(a: &str, b: &str) -> Ordering {
+    match (a.parse::<IpAddr>(), b.parse::<IpAddr>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        (Ok(_), Err(_)) => Ordering::Less,
+        (Err(_), Ok(_)) => Ordering::Greater,
+        (Err(_), Err(_)) => a.cmp(b),
+    }
+}
+
+/// How much the count of `key` went up compared to the baseline
+fn delta(baseline: &Stats, key: &str, value: u32) -> i64 {
+    value as i64 - baseline.get(key).copied().unwrap_or(0) as i64
+}
+
+/// Sums up the counts per autonomous system for --by-asn, keyed like `AS15169 Google LLC`. IPs
+/// missing from the database, and keys that are no IP at all, are counted as `unknown`.
+pub fn aggregate_by_asn(stats: &Stats, asn_db: &GeoIp) -> Stats {
+    let mut networks = Stats::default();
+    for (key, count) in stats {
+        let asn = strip_label(key).parse().ok().and_then(|ip: IpAddr| asn_db.asn(ip.to_canonical()));
+        let network = match asn {
+            Some((number, organization)) if organization.is_empty() => format!("AS{number}"),
+            Some((number, organization)) => format!("AS{number} {organization}"),
+            None => String::from("unknown"),
+        };
+        networks.entry(network)
+            .and_modify(|counter: &mut u32| *counter = counter.saturating_add(*count))
+            .or_insert(*count);
+    }
+    networks
+}
+
+/// Loads a baseline to compare against, either a state file written by --save-state
+/// or a previously generated report as accepted by --merge-reports
+pub fn load_baseline(path: &str) -> std::result::Result<Stats, Error> {
+    let data = std::fs::read_to_string(path).with_context(|| format!("Could not read baseline: {path}"))?;
+    if data.trim_start().starts_with('{') {
+        return Ok(State::load(path)?.stats);
+    }
+
+    let mut baseline = Stats::default();
+    for (number, line) in data.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (count, ip) = parse_report_line(line)
+            .with_context(|| format!("Malformed line {} in baseline {path}: {line:?}", number + 1))?;
+        *baseline.entry(ip.strip_prefix("::ffff:").unwrap_or(ip).to_string()).or_insert(0) += count;
+    }
+    Ok(baseline)
+}
+
+/// A single entry of the report with everything looked up about it. The optional fields are only
+/// filled in if the `ReportOptions` ask for them, `var` renders them like the format variables.
+pub struct Record<'a> {
+    pub ip: &'a str,
+    pub count: u32,
+    /// 1 for the highest count
+    pub rank: usize,
+    /// The sum of the counts of this entry and all entries reported before it
+    pub cumulative: u64,
+    /// The sum of all counts, reported or not, which the percentages are relative to
+    pub total: u64,
+    pub host: Option<String>,
+    /// With `network_class`, None if the key is no IP
+    pub class: Option<NetworkClass>,
+    /// With `geoip`, None if the IP was not found
+    pub country: Option<String>,
+    pub city: Option<City>,
+    pub whois: Option<WhoisInfo>,
+    /// The count in the baseline, 0 if the key was not in there
+    pub prev: Option<u32>,
+    pub file: Option<&'a str>,
+    pub source: Option<&'a str>,
+    pub raw: Option<&'a str>,
+    pub distinct_ports: Option<usize>,
+    opts: &'a ReportOptions,
+}
+
+impl Record<'_> {
+    /// The change in count since the baseline
+    pub fn delta(&self) -> Option<i64> {
+        self.prev.map(|prev| self.count as i64 - prev as i64)
+    }
+
+    /// The count scaled back up to the full input if it was sampled
+    pub fn estimated_count(&self) -> u32 {
+        (self.count as f64 / self.opts.sample_rate.unwrap_or(1.0)).round() as u32
+    }
+
+    /// The value of the format variable `name`, e.g. `cnt` or `host`, None if it is unknown or
+    /// was not asked for
+    pub fn var(&self, name: &str) -> Option<String> {
+        let count = |count: u32| match self.opts.human {
+            Some(human) => humanize(count, human),
+            None => count.to_string(),
+        };
+        let value = match name {
+            "ip" => self.ip.to_string(),
+            "cnt" => count(self.count),
+            "est_cnt" => count(self.estimated_count()),
+            "percent" => format_percent(self.count as u64, self.total),
+            "rank" => self.rank.to_string(),
+            // --running-total asked for these under the other names, which are kept as aliases
+            "cumulative" | "running_total" => self.cumulative.to_string(),
+            "cumulative_percent" | "running_pct" => format_percent(self.cumulative, self.total),
+            "host" => self.host.clone()?,
+            "class" if self.opts.network_class => self.class.map(|class| class.to_string()).unwrap_or_default(),
+            "country" if self.opts.geoip.is_some() => self.country.clone().unwrap_or_default(),
+            "city" => self.city.as_ref()?.city.clone(),
+            "region" => self.city.as_ref()?.region.clone(),
+            "postal" => self.city.as_ref()?.postal.clone(),
+            "lat" => self.city.as_ref()?.lat.clone(),
+            "lon" => self.city.as_ref()?.lon.clone(),
+            "abuse_contact" => self.whois.as_ref()?.abuse_contact.clone(),
+            "netname" => self.whois.as_ref()?.netname.clone(),
+            "prev" => self.prev?.to_string(),
+            "delta" => format!("{:+}", self.delta()?),
+            "file" => self.file?.to_string(),
+            "source" => self.source?.to_string(),
+            "raw" => self.raw?.to_string(),
+            "distinct_ports" => self.distinct_ports?.to_string(),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Renders a format string like `{cnt} {ip}` with the variables of `var`
+    pub fn format(&self, format: &str) -> std::result::Result<String, Error> {
+        let formatter = |mut fmt: strfmt::Formatter| match self.var(fmt.key) {
+            Some(value) => fmt.str(&value),
+            None => Err(strfmt::FmtError::KeyError(format!("Invalid key: {}", fmt.key))),
+        };
+        strfmt::strfmt_map(format, &formatter)
+            .context("Error while formatting record")
+            .map_err(Error::from)
+    }
+}
+
+/// Hands every entry of the report to `callback` in report order, so the results
+/// can be routed anywhere without going through a format string
+pub fn report_each<'a>(
+    stats: &'a Stats,
+    opts: &'a ReportOptions,
+    mut callback: impl FnMut(Record<'a>) -> std::result::Result<(), Error>,
+) -> std::result::Result<(), Error> {
+    // Percentages are relative to everything counted, not just what is shown
+    let total: u64 = stats.values().map(|value| *value as u64).sum();
+
+    // Look up everything asked for about the selected elements
+    let entries: Entries = select(stats, opts).into_iter().collect();
+    let len = entries.len();
+    let mut cumulative: u64 = 0;
+    for (index, entry) in entries.resolve_with(&opts.hosts).enumerate() {
+        // Running totals follow the printed order, not the ranking
+        cumulative += entry.count as u64;
+        // Everything looked up about the IP goes by the address alone
+        let addr = entry.addr();
+        let host = if opts.numeric { None } else { Some(entry.host()?.to_string()) };
+        let key = entry.ip;
+        callback(Record {
+            ip: key,
+            count: entry.count,
+            // Unless reversed, the report is in ascending order, so the top entry comes last
+            rank: if opts.reverse { index + 1 } else { len - index },
+            cumulative,
+            total,
+            host,
+            class: addr.filter(|_| opts.network_class).map(NetworkClass::of),
+            country: opts.geoip.as_ref().zip(addr).and_then(|(geoip, ip)| geoip.country(ip)),
+            city: opts.geo_city.as_ref().map(|geo_city| addr.map(|ip| geo_city.city(ip)).unwrap_or_default()),
+            whois: opts.whois.as_ref().map(|whois| addr.map(|ip| whois.lookup(ip)).unwrap_or_default()),
+            prev: opts.baseline.as_ref().map(|baseline| baseline.get(key).copied().unwrap_or(0)),
+            file: opts.file.as_deref(),
+            source: opts.source.as_deref(),
+            // Snapshots printed with --flush-interval do not have the raw matches yet
+            raw: opts.raw.as_ref().map(|raw| raw.get(key).map_or(key, String::as_str)),
+            distinct_ports: opts.ports.as_ref().map(|ports| ports.get(key).map_or(0, HashSet::len)),
+            opts,
+        })?;
+    }
+    Ok(())
+}
+
+/// Writes the report for `stats` to `out`, in the output format of `opts`
+///
+/// ```
+/// use ipstats::{ ReportOptions, Stats, print_stats };
+///
+/// let stats: Stats = [("192.0.2.1".to_string(), 2), ("198.51.100.7".to_string(), 5)].into_iter().collect();
+/// let mut out = Vec::new();
+/// print_stats(&mut out, &stats, &ReportOptions::new("{cnt} {ip}"))?;
+/// assert_eq!(String::from_utf8_lossy(&out), "2 192.0.2.1\n5 198.51.100.7\n");
+/// # Ok::<(), ipstats::Error>(())
+/// ```
+pub fn print_stats(
+    out: &mut dyn Write,
+    stats: &Stats,
+    opts: &ReportOptions,
+) -> std::result::Result<(), Error> {
+    if let Some(firewall) = opts.emit_rules {
+        return print_rules(out, &select(stats, opts), firewall, opts);
+    }
+    match opts.output_format {
+        OutputFormat::Text => report_each(stats, opts, |record| {
+            writeln!(out, "{}", record.format(&opts.format)?)?;
+            Ok(())
+        }),
+        OutputFormat::Dot => print_dot(out, stats, opts),
+        OutputFormat::Nmap => print_nmap(out, &select(stats, opts), opts.nmap_cidr),
+        OutputFormat::Ssv => print_ssv(out, stats, opts),
+        OutputFormat::JsonlSchema => print_jsonl_schema(out, stats, opts),
+        OutputFormat::Zone => print_zone(out, stats, opts),
+        OutputFormat::Parquet => print_parquet(out, stats, opts),
+        OutputFormat::Markdown => print_markdown(out, stats, opts),
+        OutputFormat::Latex => print_latex(out, stats, opts),
+        OutputFormat::NginxMap => print_nginx_map(out, stats, opts),
+        OutputFormat::HaproxyAcl => print_haproxy_acl(out, &select(stats, opts), opts),
+        OutputFormat::ApacheDeny => print_apache_deny(out, &select(stats, opts), opts),
+        OutputFormat::Hosts => print_hosts(out, &select(stats, opts), &opts.hosts_domain),
+        OutputFormat::SyslogRfc5424 => print_syslog(out, stats, opts),
+    }
+}
+
+fn print_parquet(out: &mut dyn Write, stats: &Stats, opts: &ReportOptions) -> std::result::Result<(), Error> {
+    // Like with jsonl-schema, optional columns are only there if they are filled in
+    let mut names = Vec::new();
+    if !opts.numeric {
+        names.push("host");
+    }
+    if opts.network_class {
+        names.push("class");
+    }
+    if opts.geoip.is_some() {
+        names.push("country");
+    }
+    let mut table = Table {
+        ips: Vec::new(),
+        counts: Vec::new(),
+        columns: names.iter().map(|name| (*name, Vec::new())).collect(),
+    };
+    report_each(stats, opts, |record| {
+        table.ips.push(record.ip.to_string());
+        table.counts.push(record.count as i64);
+        for (name, values) in table.columns.iter_mut() {
+            values.push(record.var(name).unwrap_or_default());
+        }
+        Ok(())
+    })?;
+    columnar::write_parquet(out, table, opts.parquet_compression)
+}
+
+fn print_zone(out: &mut dyn Write, stats: &Stats, opts: &ReportOptions) -> std::result::Result<(), Error> {
+    let ttl = opts.zone_ttl.map(|ttl| format!(" {ttl}")).unwrap_or_default();
+    report_each(stats, opts, |record| {
+        let Ok(ip) = record.ip.parse::<IpAddr>() else {
+            writeln!(out, "; Not an IP: {}", record.ip)?;
+            return Ok(());
+        };
+        // Without a PTR record the lookup just hands back the IP itself
+        let host = match &record.host {
+            Some(host) if host.parse::<IpAddr>().is_err() => host.trim_end_matches('.'),
+            _ => "unknown",
+        };
+        writeln!(out, "{}{ttl} IN PTR {host}.", reverse_name(ip))?;
+        Ok(())
+    })
+}
+
+fn print_markdown(out: &mut dyn Write, stats: &Stats, opts: &ReportOptions) -> std::result::Result<(), Error> {
+    let mut rows = vec![vec![String::from("Count"), String::from("IP"), String::from("Host")]];
+    report_each(stats, opts, |record| {
+        let host = record.host.as_deref().unwrap_or_default().replace('|', "\\|");
+        rows.push(vec![record.var("cnt").unwrap_or_default(), record.ip.to_string(), host]);
+        Ok(())
+    })?;
+    let columns = if opts.numeric { 2 } else { 3 };
+
+    // Pad every column to its widest value, so the table also reads well as plain text
+    let widths: Vec<usize> = (0..columns)
+        .map(|column| rows.iter().map(|row| row[column].chars().count()).max().unwrap_or(0).max(3))
+        .collect();
+    let line = |cells: Vec<String>| format!("| {} |", cells.join(" | "));
+    for (index, row) in rows.iter().enumerate() {
+        // The count is right aligned, everything else left aligned
+        let cells = (0..columns).map(|column| match column {
+            0 => format!("{:>width$}", row[column], width = widths[column]),
+            _ => format!("{:<width$}", row[column], width = widths[column]),
+        }).collect();
+        writeln!(out, "{}", line(cells))?;
+        if index == 0 {
+            let separator = widths.iter().enumerate().map(|(column, width)| match column {
+                0 => format!("{}:", "-".repeat(width - 1)),
+                _ => format!(":{}", "-".repeat(width - 1)),
+            }).collect();
+            writeln!(out, "{}", line(separator))?;
+        }
+    }
+    Ok(())
+}
+
+fn print_latex(out: &mut dyn Write, stats: &Stats, opts: &ReportOptions) -> std::result::Result<(), Error> {
+    writeln!(out, "\\begin{{table}}")?;
+    writeln!(out, "\\centering")?;
+    if opts.numeric {
+        writeln!(out, "\\begin{{tabular}}{{r | l}}")?;
+        writeln!(out, "Count & IP \\\\")?;
+    } else {
+        writeln!(out, "\\begin{{tabular}}{{r | l | l}}")?;
+        writeln!(out, "Count & IP & Host \\\\")?;
+    }
+    writeln!(out, "\\hline")?;
+    report_each(stats, opts, |record| {
+        // With a custom --pattern the keys are not necessarily IPs, so escape every cell
+        let mut cells = vec![latex_escape(&record.var("cnt").unwrap_or_default()), latex_escape(record.ip)];
+        if let Some(host) = &record.host {
+            cells.push(latex_escape(host));
+        }
+        writeln!(out, "{} \\\\", cells.join(" & "))?;
+        Ok(())
+    })?;
+    writeln!(out, "\\end{{tabular}}")?;
+    // Both are passed through as is, so the caption may contain markup
+    if let Some(caption) = &opts.latex_caption {
+        writeln!(out, "\\caption{{{caption}}}")?;
+    }
+    if let Some(label) = &opts.latex_label {
+        writeln!(out, "\\label{{{label}}}")?;
+    }
+    writeln!(out, "\\end{{table}}")?;
+    Ok(())
+}
+
+fn print_nginx_map(out: &mut dyn Write, stats: &Stats, opts: &ReportOptions) -> std::result::Result<(), Error> {
+    let var = opts.nginx_map_var.trim_start_matches('$');
+    let value = nginx_quote(&opts.nginx_map_value);
+    writeln!(out, "geo $remote_addr ${var} {{")?;
+    writeln!(out, "    default 0;")?;
+    report_each(stats, opts, |record| {
+        // Anything else would make nginx refuse the whole file
+        match record.ip.parse::<IpAddr>() {
+            Ok(ip) => writeln!(out, "    {} {value};", ip.to_canonical())?,
+            Err(_) => writeln!(out, "    # Not an IP: {}", record.ip)?,
+        }
+        Ok(())
+    })?;
+    writeln!(out, "}}")?;
+    Ok(())
+}
+
+/// Quotes a value for nginx configuration if it would otherwise be split up or end the directive
+fn nginx_quote(value: &str) -> String {
+    if !value.is_empty() && !value.contains(|c: char| c.is_whitespace() || matches!(c, ';' | '{' | '}' | '"' | '\'' | '\\')) {
+        return value.to_string();
+    }
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Escapes the characters LaTeX treats specially in text, e.g. the `_` common in hostnames
+fn latex_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '_' | '&' | '%' | '#' | '$' | '{' | '}' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\\' => escaped.push_str("\\textbackslash{}"),
+            '~' => escaped.push_str("\\textasciitilde{}"),
+            '^' => escaped.push_str("\\textasciicircum{}"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The name of the PTR record for `ip`, e.g. 4.3.2.1.in-addr.arpa. for 1.2.3.4
+fn reverse_name(ip: IpAddr) -> String {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let octets: Vec<_> = ip.octets().iter().rev().map(|octet| octet.to_string()).collect();
+            format!("{}.in-addr.arpa.", octets.join("."))
+        }
+        IpAddr::V6(ip) => {
+            let nibbles: Vec<_> = format!("{:032x}", u128::from(ip)).chars().rev().map(String::from).collect();
+            format!("{}.ip6.arpa.", nibbles.join("."))
+        }
+    }
+}
+
+/// A JSON object keeping its keys in the given order, which `serde_json::Map` does not
+struct JsonObject<'a, V>(&'a [(&'a str, V)]);
+
+impl<V: Serialize> Serialize for JsonObject<'_, V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (key, value) in self.0 {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+}
+
+/// Writes `value` on a line of its own, or spread over indented lines with `pretty`
+fn write_json(out: &mut dyn Write, value: &impl Serialize, pretty: bool) -> std::result::Result<(), Error> {
+    if pretty {
+        serde_json::to_writer_pretty(&mut *out, value).map_err(io::Error::from)?;
+    } else {
+        serde_json::to_writer(&mut *out, value).map_err(io::Error::from)?;
+    }
+    writeln!(out)?;
+    Ok(())
+}
+
+fn print_jsonl_schema(out: &mut dyn Write, stats: &Stats, opts: &ReportOptions) -> std::result::Result<(), Error> {
+    // Only fields that are actually filled in end up in the schema and the records
+    let mut schema = vec![("ip", "string"), ("count", "integer")];
+    if opts.file.is_some() {
+        schema.push(("file", "string"));
+    }
+    if opts.source.is_some() {
+        schema.push(("source", "string"));
+    }
+    if !opts.numeric {
+        schema.push(("host", "string"));
+    }
+    if opts.network_class {
+        schema.push(("class", "string"));
+    }
+    if opts.geoip.is_some() {
+        schema.push(("country", "string"));
+    }
+    if opts.geo_city.is_some() {
+        schema.extend([("city", "string"), ("region", "string"), ("postal", "string"), ("lat", "number"), ("lon", "number")]);
+    }
+    if opts.whois.is_some() {
+        schema.extend([("abuse_contact", "string"), ("netname", "string")]);
+    }
+    if opts.baseline.is_some() {
+        schema.extend([("prev", "integer"), ("delta", "integer")]);
+    }
+    if opts.ports.is_some() {
+        schema.push(("distinct_ports", "integer"));
+    }
+    if opts.running_total {
+        schema.extend([("cumulative", "integer"), ("cumulative_percent", "number")]);
+    }
+    write_json(out, &JsonObject(&[("schema", JsonObject(&schema))]), opts.json_pretty)?;
+
+    report_each(stats, opts, |record| {
+        let entries: Vec<_> = schema.iter().map(|(name, _)| {
+            let value = match *name {
+                "ip" => serde_json::Value::from(record.ip),
+                "count" => serde_json::Value::from(record.count),
+                "prev" => serde_json::Value::from(record.prev),
+                "delta" => serde_json::Value::from(record.delta()),
+                "distinct_ports" => serde_json::Value::from(record.distinct_ports),
+                "cumulative" => serde_json::Value::from(record.cumulative),
+                "lat" | "lon" | "cumulative_percent" => {
+                    let coordinate = record.var(name).and_then(|value| value.parse::<f64>().ok());
+                    serde_json::Value::from(coordinate)
+                }
+                name => serde_json::Value::from(record.var(name).unwrap_or_default()),
+            };
+            (*name, value)
+        }).collect();
+        write_json(out, &JsonObject(&entries), opts.json_pretty)
+    })
+}
+
+fn print_ssv(out: &mut dyn Write, stats: &Stats, opts: &ReportOptions) -> std::result::Result<(), Error> {
+    let delimiter = opts.delimiter.to_string();
+    if opts.header {
+        writeln!(out, "{}", opts.fields.join(&delimiter))?;
+    }
+    let mut rows = Vec::new();
+    report_each(stats, opts, |record| {
+        // There is no quoting, so the delimiter must not show up inside any value, e.g. a hostname
+        rows.push(opts.fields
+            .iter()
+            .map(|field| record.var(field).map(|value| value.replace(opts.delimiter, &opts.escape)).unwrap_or_default())
+            .collect::<Vec<_>>());
+        Ok(())
+    })?;
+    // Space separated counts are right aligned to the widest one as printed, e.g. shortened by
+    // --human, so even a plain `sort` without -n puts them in the right order
+    let is_count = |i: usize| opts.delimiter == ' ' && opts.fields[i] == "cnt";
+    let width = rows.iter()
+        .flat_map(|values| values.iter().enumerate().filter(|(i, _)| is_count(*i)))
+        .map(|(_, value)| value.chars().count())
+        .max()
+        .unwrap_or(0);
+    for values in rows {
+        let values: Vec<_> = values.into_iter()
+            .enumerate()
+            .map(|(i, value)| if is_count(i) { format!("{value:>width$}") } else { value })
+            .collect();
+        writeln!(out, "{}", values.join(&delimiter))?;
+    }
+    Ok(())
+}
+
+/// Formats the share of `value` in `total` with one decimal.
+///
+/// Like all numbers in the output this goes through Rust's own formatting, which never looks
+/// at the locale: the decimal separator is always `.` and ties are rounded the same way everywhere.
+fn format_percent(value: u64, total: u64) -> String {
+    if total == 0 {
+        return String::from("0.0");
+    }
+    format!("{:.1}", value as f64 * 100.0 / total as f64)
+}
+
+/// Formats a count for --human, independent of the locale: SI suffixes always use `.` as
+/// the decimal separator and comma grouping always uses `,`
+fn humanize(value: u32, human: HumanFormat) -> String {
+    match human {
+        HumanFormat::Comma => {
+            let digits = value.to_string();
+            let mut grouped = String::new();
+            for (i, digit) in digits.chars().enumerate() {
+                if i > 0 && (digits.len() - i).is_multiple_of(3) {
+                    grouped.push(',');
+                }
+                grouped.push(digit);
+            }
+            grouped
+        }
+        HumanFormat::Si => {
+            let mut scaled = value as f64;
+            for suffix in ["", "k", "M", "G"] {
+                // Rounding could bump e.g. 999950 to "1000.0k", so check the rounded value
+                if (scaled * 10.0).round() < 10000.0 {
+                    return if suffix.is_empty() {
+                        value.to_string()
+                    } else {
+                        format!("{scaled:.1}{suffix}")
+                    };
+                }
+                scaled /= 1000.0;
+            }
+            format!("{scaled:.1}T")
+        }
+    }
+}
+
+fn print_dot(out: &mut dyn Write, stats: &Stats, opts: &ReportOptions) -> std::result::Result<(), Error> {
+    let mut records = Vec::new();
+    report_each(stats, opts, |record| {
+        records.push(record);
+        Ok(())
+    })?;
+
+    // Node width is scaled relative to the biggest hitter, with a lower bound so
+    // that small nodes still fit their label
+    let max = records.iter().map(|record| record.count).max().unwrap_or(1);
+
+    writeln!(out, "digraph ipstats {{")?;
+    writeln!(out, "    node [shape=ellipse];")?;
+    for record in records.iter() {
+        let (ip, count) = (dot_escape(record.ip), record.count);
+        let label = match &record.host {
+            Some(host) => format!("{}\\n{ip}\\n{count}", dot_escape(host)),
+            None => format!("{ip}\\n{count}"),
+        };
+        let width = 0.75 + 2.25 * (count as f64 / max as f64);
+        writeln!(out, "    \"{ip}\" [label=\"{label}\", width={width:.2}];")?;
+    }
+
+    // Edges between the reported keys found on the same line, thicker the more often they were
+    if let Some(pairs) = &opts.pairs {
+        let reported: HashSet<&str> = records.iter().map(|record| record.ip).collect();
+        let mut edges: Vec<_> = pairs.iter()
+            .filter(|((a, b), _)| reported.contains(a.as_str()) && reported.contains(b.as_str()))
+            .collect();
+        edges.sort();
+        let max = edges.iter().map(|(_, count)| **count).max().unwrap_or(1);
+        for ((a, b), count) in edges {
+            let penwidth = 1.0 + 4.0 * (*count as f64 / max as f64);
+            writeln!(
+                out,
+                "    \"{}\" -> \"{}\" [label=\"{count}\", penwidth={penwidth:.2}];",
+                dot_escape(a),
+                dot_escape(b),
+            )?;
+        }
+    }
+    writeln!(out, "}}")?;
+    Ok(())
+}
+
+/// Escapes a string for a quoted GraphViz ID or label
+fn dot_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+pub fn print_nmap(out: &mut dyn Write, sorted: &[(&String, &u32)], cidr: bool) -> std::result::Result<(), Error> {
+    if !cidr {
+        for (key, _) in sorted.iter() {
+            writeln!(out, "{key}")?;
+        }
+        return Ok(());
+    }
+
+    // Anything that does not parse as an IP is passed through as-is
+    let mut ips = Vec::new();
+    for (key, _) in sorted.iter() {
+        match key.parse::<IpAddr>() {
+            Ok(ip) => ips.push(ip),
+            Err(_) => writeln!(out, "{key}")?,
+        }
+    }
+    for network in collapse_cidrs(ips) {
+        writeln!(out, "{network}")?;
+    }
+    Ok(())
+}
+
+/// The IPs or, with --cidr, networks to put into a server config, anything that is not an IP
+/// is written out as a `#` comment right away, as it would make the server reject the config
+fn config_entries(out: &mut dyn Write, sorted: &[(&String, &u32)], cidr: bool) -> std::result::Result<Vec<String>, Error> {
+    let mut ips = Vec::new();
+    for (key, _) in sorted.iter() {
+        match key.parse::<IpAddr>() {
+            Ok(ip) => ips.push(ip.to_canonical()),
+            Err(_) => writeln!(out, "# Not an IP: {key}")?,
+        }
+    }
+    // Like with nmap, --cidr trades the report order for fewer entries
+    Ok(if cidr { collapse_cidrs(ips) } else { ips.iter().map(IpAddr::to_string).collect() })
+}
+
+fn print_haproxy_acl(out: &mut dyn Write, sorted: &[(&String, &u32)], opts: &ReportOptions) -> std::result::Result<(), Error> {
+    let name = &opts.haproxy_acl_name;
+    for entry in config_entries(out, sorted, opts.nmap_cidr)? {
+        writeln!(out, "acl {name} src {entry}")?;
+    }
+    if let Some(action) = &opts.haproxy_action {
+        writeln!(out, "http-request {action} if {name}")?;
+    }
+    Ok(())
+}
+
+fn print_apache_deny(out: &mut dyn Write, sorted: &[(&String, &u32)], opts: &ReportOptions) -> std::result::Result<(), Error> {
+    for line in opts.apache_comment.iter().flat_map(|comment| comment.lines()) {
+        writeln!(out, "# {line}")?;
+    }
+    let entries = config_entries(out, sorted, opts.nmap_cidr)?;
+    match opts.apache_version {
+        ApacheVersion::V22 => {
+            for entry in entries {
+                writeln!(out, "Deny from {entry}")?;
+            }
+        }
+        ApacheVersion::V24 => {
+            // A negated Require only works inside of a RequireAll that grants everything else
+            writeln!(out, "<RequireAll>")?;
+            writeln!(out, "    Require all granted")?;
+            for entry in entries {
+                writeln!(out, "    Require not ip {entry}")?;
+            }
+            writeln!(out, "</RequireAll>")?;
+        }
+    }
+    Ok(())
+}
+
+fn print_rules(out: &mut dyn Write, sorted: &[(&String, &u32)], firewall: Firewall, opts: &ReportOptions) -> std::result::Result<(), Error> {
+    let set = &opts.ipset_name;
+    for entry in config_entries(out, sorted, opts.nmap_cidr)? {
+        let v6 = entry.contains(':');
+        match firewall {
+            Firewall::Iptables if v6 => writeln!(out, "ip6tables -A INPUT -s {entry} -j DROP")?,
+            Firewall::Iptables => writeln!(out, "iptables -A INPUT -s {entry} -j DROP")?,
+            Firewall::Nftables if v6 => writeln!(out, "nft add rule inet filter input ip6 saddr {entry} drop")?,
+            Firewall::Nftables => writeln!(out, "nft add rule inet filter input ip saddr {entry} drop")?,
+            // A set only holds addresses of a single family
+            Firewall::Ipset if v6 => writeln!(out, "add {set}6 {entry}")?,
+            Firewall::Ipset => writeln!(out, "add {set} {entry}")?,
+        }
+    }
+    Ok(())
+}
+
+fn print_syslog(out: &mut dyn Write, stats: &Stats, opts: &ReportOptions) -> std::result::Result<(), Error> {
+    let syslog = Rfc5424::new(opts.syslog_facility, opts.syslog_severity);
+    report_each(stats, opts, |record| {
+        let count = record.count.to_string();
+        let mut params = vec![("ip", record.ip), ("count", count.as_str())];
+        if let Some(host) = &record.host {
+            params.push(("host", host));
+        }
+        let message = record.format(&opts.format)?;
+        // A line break would end the message early
+        writeln!(out, "{}", syslog.message(&params, &message.replace('\n', " ")))?;
+        Ok(())
+    })
+}
+
+fn print_hosts(out: &mut dyn Write, sorted: &[(&String, &u32)], domain: &str) -> std::result::Result<(), Error> {
+    for (key, count) in sorted.iter() {
+        match key.parse::<IpAddr>() {
+            Ok(ip) => writeln!(out, "{} {domain} # count: {count}", ip.to_canonical())?,
+            Err(_) => writeln!(out, "# Not an IP: {key}")?,
+        }
+    }
+    Ok(())
+}
+
+/// Collapses a list of IPs into the smallest list of CIDR networks covering exactly those IPs
+fn collapse_cidrs(mut ips: Vec<IpAddr>) -> Vec<String> {
+    ips.sort();
+    ips.dedup();
+
+    // Work on plain integers, `bits` being the address width of the family
+    let as_int = |ip: &IpAddr| match ip {
+        IpAddr::V4(ip) => (u32::from(*ip) as u128, 32),
+        IpAddr::V6(ip) => (u128::from(*ip), 128),
+    };
+    let to_ip = |value: u128, bits: u32| -> IpAddr {
+        if bits == 32 {
+            IpAddr::V4((value as u32).into())
+        } else {
+            IpAddr::V6(value.into())
+        }
+    };
+
+    // Merge consecutive addresses into ranges first...
+    let mut ranges: Vec<(u128, u128, u32)> = Vec::new();
+    for ip in ips.iter() {
+        let (value, bits) = as_int(ip);
+        match ranges.last_mut() {
+            Some((_, end, range_bits)) if *range_bits == bits && end.checked_add(1) == Some(value) => *end = value,
+            _ => ranges.push((value, value, bits)),
+        }
+    }
+
+    // ...then split every range into the biggest aligned blocks that fit
+    let mut networks = Vec::new();
+    for (mut start, end, bits) in ranges {
+        loop {
+            let alignment = if start == 0 { bits } else { start.trailing_zeros().min(bits) };
+            let mut size = alignment;
+            while size > 0 && (size >= 128 || start + ((1u128 << size) - 1) > end) {
+                size -= 1;
+            }
+            networks.push(format!("{}/{}", to_ip(start, bits), bits - size));
+            let last = start + ((1u128 << size) - 1);
+            if last >= end {
+                break;
+            }
+            start = last + 1;
+        }
+    }
+    networks
+}
+
+/// Counts the IPs in all `files` into `stats`, continuing where the last run left off with --state-dir.
+/// URLs among them are fetched, see `source::open_url`.
+pub fn process_files(
+    files: &[String],
+    stats: &mut Stats,
+    opts: &ProcessOptions,
+    summary: &mut Summary,
+    mut offsets: Option<&mut Offsets>,
+    mut details: Option<&mut Details>,
+) -> std::result::Result<(), Error> {
+    for path in files {
+        // No need to even open the remaining files once a global limit cut the input short. One
+        // reached right at the end of a file only counts as truncation if there is more to read.
+        if summary.truncated_by.is_some_and(|limit| limit != Limit::Head) {
+            break;
+        }
+        // A URL is read once in full, there is no telling where to resume in it
+        if source::is_url(path) {
+            let mut body = match source::open_url(path) {
+                Ok(body) => body,
+                Err(err) if opts.keep_going => {
+                    warn!(file = path.as_str(); "Skipping {path}, it could not be fetched: {err}");
+                    summary.skipped.push(path.clone());
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            info!("Reading {path}");
+            count_lines(&mut body, path, stats, opts, summary, details.as_deref_mut(), None, Layout::of(opts))
+                .context(format!("Failed processing file: {path}"))?;
+            continue;
+        }
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if opts.keep_going => {
+                warn!(file = path.as_str(); "Skipping {path}, it could not be opened: {err}");
+                summary.skipped.push(path.clone());
+                continue;
+            }
+            Err(err) => Err(err).context(format!("Could not open file: {path}"))?,
+        };
+        if log::log_enabled!(log::Level::Info) {
+            let size = file.metadata().map(|meta| meta.len()).unwrap_or_default();
+            info!("Reading {path} ({size} bytes)");
+        }
+        let mut start = match &offsets {
+            Some(offsets) => offsets.resume(path, &file)?,
+            None => 0,
+        };
+        let mut layout = Layout { whole_lines: offsets.is_some(), ..Layout::of(opts) };
+        if start > 0 {
+            info!("Resuming {path} at byte {start}");
+            file.seek(io::SeekFrom::Start(start)).context(format!("Could not seek in file: {path}"))?;
+            layout.skip_lines = None;
+        }
+        // Plain files can be read from where their last lines start, anything else is read in full.
+        // When resuming, these are the last lines of what was appended since the last run.
+        if let Some(lines) = layout.tail_lines {
+            if let Some(offset) = tail_offset(&mut file, lines, start).context(format!("Could not read file: {path}"))? {
+                debug!("The last {lines} lines of {path} start at byte {offset}");
+                file.seek(io::SeekFrom::Start(offset)).context(format!("Could not seek in file: {path}"))?;
+                layout.tail_lines = None;
+                start = offset;
+            }
+        }
+        let consumed = count_lines(
+            &mut file,
+            path,
+            stats,
+            opts,
+            summary,
+            details.as_deref_mut(),
+            None,
+            layout,
+        ).context(format!("Failed processing file: {path}"))?;
+
+        // Compressed files cannot be resumed in the middle, they are read in full every time
+        if let (Some(offsets), Some(consumed)) = (offsets.as_mut(), consumed) {
+            offsets.update(path, &file, start + consumed)?;
+        }
+    }
+    Ok(())
+}
+
+/// Finds where the last `lines` lines of a file after byte `from` start, by scanning blocks
+/// backwards from the end for line breaks, so the rest of the file is never read. None for gzipped
+/// files, which can only be read from the start, the file is left at `from` then.
+fn tail_offset(file: &mut File, lines: u64, from: u64) -> io::Result<Option<u64>> {
+    const BLOCK: u64 = 64 * 1024;
+
+    let mut magic = [0; 2];
+    file.rewind()?;
+    let is_gzip = file.read(&mut magic)? == 2 && magic == [0x1f, 0x8b];
+    if is_gzip || lines == 0 {
+        file.seek(io::SeekFrom::Start(from))?;
+        return Ok(None);
+    }
+    let mut end = file.metadata()?.len();
+    let mut buf = vec![0; BLOCK as usize];
+    let mut found = 0;
+    // A line break at the very end finishes the last line instead of starting another one
+    let mut skip_last = true;
+    while end > from {
+        let start = end.saturating_sub(BLOCK).max(from);
+        let block = &mut buf[..(end - start) as usize];
+        file.seek(io::SeekFrom::Start(start))?;
+        file.read_exact(block)?;
+        for (i, byte) in block.iter().enumerate().rev() {
+            if std::mem::take(&mut skip_last) && *byte == b'\n' {
+                continue;
+            }
+            if *byte == b'\n' {
+                found += 1;
+                if found == lines {
+                    return Ok(Some(start + i as u64 + 1));
+                }
+            }
+        }
+        end = start;
+    }
+    Ok(Some(from))
+}
+
+/// Hands every line of the files or URLs, or of stdin if there are none, to `callback`
+pub fn for_each_line(
+    files: &[String],
+    mut callback: impl FnMut(&str) -> std::result::Result<(), Error>,
+) -> std::result::Result<(), Error> {
+    if files.is_empty() {
+        return read_each_line(io::stdin(), "stdin", &mut callback);
+    }
+    for path in files {
+        if source::is_url(path) {
+            read_each_line(source::open_url(path)?, path, &mut callback)?;
+            continue;
+        }
+        read_each_line(File::open(path).context(format!("Could not open file: {path}"))?, path, &mut callback)?;
+    }
+    Ok(())
+}
+
+fn read_each_line(
+    mut file: impl Read,
+    name: &str,
+    callback: &mut impl FnMut(&str) -> std::result::Result<(), Error>,
+) -> std::result::Result<(), Error> {
+    let (mut reader, _) = get_reader(&mut file).context(format!("Failed getting reader for {name}"))?;
+    let mut line = String::new();
+    while reader.read_line(&mut line).context(format!("Reading next line of {name}"))? > 0 {
+        callback(&line)?;
+        line.clear();
+    }
+    Ok(())
+}
+
+/// Prints the final report for `stats`
+pub fn report(out: &mut dyn Write, stats: &Stats, opts: &ReportOptions) -> std::result::Result<(), Error> {
+    print_stats(out, stats, opts).context("Failed printing stats")?;
+    out.flush().context("Failed flushing output")?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_cache_remembers_failed_lookups() {
+        let hosts = HostCache::default();
+        let failing = |_: &IpAddr| Err(io::Error::other("SERVFAIL"));
+        assert_eq!(hosts.lookup_with("192.0.2.1", failing).unwrap(), "192.0.2.1");
+        let unreachable = |_: &IpAddr| -> io::Result<String> { panic!("looked up again") };
+        assert_eq!(hosts.lookup_with("192.0.2.1", unreachable).unwrap(), "192.0.2.1");
+        assert_eq!((hosts.hits.get(), hosts.misses.get()), (1, 1));
+    }
+
+    #[test]
+    fn host_cache_resolves_mapped_addresses_once() {
+        let hosts = HostCache::default();
+        let resolve = |ip: &IpAddr| {
+            assert_eq!(ip.to_string(), "192.0.2.1");
+            Ok(String::from("host.example"))
+        };
+        assert_eq!(hosts.lookup_with("::ffff:192.0.2.1", resolve).unwrap(), "host.example");
+        assert_eq!(hosts.lookup_with("192.0.2.1", |_: &IpAddr| unreachable!()).unwrap(), "host.example");
+        assert!(hosts.lookup_with("not an ip", |_: &IpAddr| unreachable!()).is_err());
+    }
+
+    #[test]
+    fn host_cache_skips_lookups_per_family() {
+        let hosts = HostCache::new(true, false, None);
+        let resolve = |ip: &IpAddr| Ok(format!("host-{ip}"));
+        assert_eq!(hosts.lookup_with("192.0.2.1", |_: &IpAddr| unreachable!()).unwrap(), "192.0.2.1");
+        assert_eq!(hosts.lookup_with("::ffff:192.0.2.1", |_: &IpAddr| unreachable!()).unwrap(), "::ffff:192.0.2.1");
+        assert_eq!(hosts.lookup_with("2001:db8::1", resolve).unwrap(), "host-2001:db8::1");
+        let hosts = HostCache::new(false, true, None);
+        assert_eq!(hosts.lookup_with("2001:db8::1", |_: &IpAddr| unreachable!()).unwrap(), "2001:db8::1");
+        assert_eq!(hosts.lookup_with("192.0.2.1", resolve).unwrap(), "host-192.0.2.1");
+    }
+
+    fn ipv4_options() -> ProcessOptions {
+        ProcessOptions::new(Regex::new(r"([0-9]+\.){3}[0-9]+").unwrap())
+    }
+
+    /// A fresh directory below the system's temporary one, named after the test
+    fn scratch(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("ipstats-lib-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Appends `data` to the log and counts what is new since the last run, like a run with --state-dir
+    fn count_new(dir: &std::path::Path, data: &str, opts: &ProcessOptions) -> Stats {
+        let log = dir.join("access.log");
+        std::fs::OpenOptions::new().create(true).append(true).open(&log).unwrap().write_all(data.as_bytes()).unwrap();
+        let mut offsets = Offsets::load(dir.join("state").to_str().unwrap(), false).unwrap();
+        let mut stats = Stats::default();
+        let files = [log.display().to_string()];
+        process_files(&files, &mut stats, opts, &mut Summary::default(), Some(&mut offsets), None).unwrap();
+        offsets.save().unwrap();
+        stats
+    }
+
+    #[test]
+    fn offsets_leave_an_unterminated_line_for_later() {
+        let dir = scratch("unterminated");
+        let opts = ipv4_options();
+        let stats = count_new(&dir, "192.0.2.1\n192.0.2.", &opts);
+        assert_eq!(stats.into_iter().collect::<Vec<_>>(), [(String::from("192.0.2.1"), 1)]);
+        let stats = count_new(&dir, "2\n", &opts);
+        assert_eq!(stats.into_iter().collect::<Vec<_>>(), [(String::from("192.0.2.2"), 1)]);
+    }
+
+    #[test]
+    fn offsets_skip_the_header_only_once() {
+        let dir = scratch("skip");
+        let opts = ProcessOptions { skip_lines: Some(1), ..ipv4_options() };
+        let stats = count_new(&dir, "10.0.0.1 header\n192.0.2.1\n", &opts);
+        assert_eq!(stats.into_iter().collect::<Vec<_>>(), [(String::from("192.0.2.1"), 1)]);
+        let stats = count_new(&dir, "192.0.2.2\n", &opts);
+        assert_eq!(stats.into_iter().collect::<Vec<_>>(), [(String::from("192.0.2.2"), 1)]);
+    }
+
+
+    /// Counts `input` like stdin
+    fn count(input: &str, opts: &ProcessOptions) -> std::result::Result<Stats, Error> {
+        let mut stats = Stats::default();
+        process_file(&mut input.as_bytes(), "input", &mut stats, opts, &mut Summary::default(), None, None)?;
+        Ok(stats)
+    }
+
+    #[test]
+    fn key_fallback_picks_from_short_lines() {
+        let opts = |key_fallback| ProcessOptions { key: 3, key_fallback, ..ipv4_options() };
+        let line = "192.0.2.1 -> 192.0.2.2";
+        assert_eq!(opts(KeyFallback::None).locate_key(line), None);
+        assert_eq!(opts(KeyFallback::First).locate_key(line), Some((0..9, true)));
+        assert_eq!(opts(KeyFallback::Last).locate_key(line), Some((13..22, true)));
+        assert_eq!(opts(KeyFallback::Last).locate_key("a 192.0.2.1 b 192.0.2.2 c 192.0.2.3"), Some((26..35, false)));
+    }
+
+    #[test]
+    fn dedup_window_forgets_ips_that_left_it() {
+        let mut window = DedupWindow::new(3);
+        assert!(!window.seen("192.0.2.1", 1));
+        assert!(window.seen("192.0.2.1", 3));
+        assert!(!window.seen("192.0.2.2", 4));
+        // Line 3 is still within the window, line 1 is not
+        assert!(window.seen("192.0.2.1", 5));
+        assert!(!window.seen("192.0.2.1", 9));
+        let mut window = DedupWindow::new(1);
+        assert!(!window.seen("192.0.2.1", 1));
+        assert!(!window.seen("192.0.2.1", 2));
+    }
+
+    #[test]
+    fn zero_padded_octets_are_read_as_decimal() {
+        assert_eq!(strip_octet_zeros("010.001.002.003"), Some(Ipv4Addr::new(10, 1, 2, 3)));
+        assert_eq!(strip_octet_zeros("192.168.001.010"), Some(Ipv4Addr::new(192, 168, 1, 10)));
+        assert_eq!(strip_octet_zeros("192.0.2.1"), None);
+        for invalid in ["300.001.002.003", "0001.2.3.4", "010.1.2", "010.1.2.x", "010..2.3"] {
+            assert_eq!(strip_octet_zeros(invalid), None, "{invalid}");
+        }
+        let opts = ipv4_options();
+        assert_eq!(normalize_key("::ffff:010.000.002.001", &opts), "10.0.2.1");
+        assert_eq!(normalize_key("010.000.002.001", &ProcessOptions { normalize_octets: false, ..ipv4_options() }), "010.000.002.001");
+        assert_eq!(normalize_key("010.000.002.001", &ProcessOptions { ipv4_as_ipv6: true, ..ipv4_options() }), "::ffff:10.0.2.1");
+    }
+
+    #[test]
+    fn mac_addresses_are_counted_in_one_spelling() {
+        assert_eq!(normalize_mac("00-1A-2B-3C-4D-5E"), "00:1a:2b:3c:4d:5e");
+        assert_eq!(normalize_mac("001a.2b3c.4d5e"), "00:1a:2b:3c:4d:5e");
+        assert_eq!(normalize_mac("00:1a:2b:3c:4d"), "00:1a:2b:3c:4d");
+        let opts = ProcessOptions { mac: true, ..ProcessOptions::new(Regex::new(MAC_PATTERN).unwrap()) };
+        let stats = count("from 00:1A:2B:3C:4D:5E\nfrom 001a.2b3c.4d5e\nfrom 192.0.2.1\n", &opts).unwrap();
+        assert_eq!(stats.into_iter().collect::<Vec<_>>(), [(String::from("00:1a:2b:3c:4d:5e"), 2)]);
+    }
+
+    #[test]
+    fn field_values_are_counted_verbatim() {
+        let opts = ProcessOptions { field: Some(2), verbatim: true, ..ipv4_options() };
+        let stats = count("GET /a 200\nGET ::ffff:/b 200\nGET /a 404\nGET\n", &opts).unwrap();
+        assert_eq!((stats["/a"], stats["::ffff:/b"], stats.len()), (2, 1, 2));
+        let opts = ProcessOptions { field: Some(2), field_delimiter: Some(','), verbatim: true, ..ipv4_options() };
+        let stats = count("a,,c\na,b c,d\n", &opts).unwrap();
+        assert_eq!(stats.into_iter().collect::<Vec<_>>(), [(String::from("b c"), 1)]);
+    }
+
+    #[test]
+    fn skipped_lines_are_neither_counted_nor_pedantic() {
+        let opts = ProcessOptions { skip_lines: Some(2), pedantic: true, ..ipv4_options() };
+        let stats = count("ip,hits
+10.0.0.1,header
+192.0.2.1,1
+", &opts).unwrap();
+        assert_eq!(stats.into_iter().collect::<Vec<_>>(), [(String::from("192.0.2.1"), 1)]);
+        assert!(count("ip,hits
+10.0.0.1,header
+no ip
+", &opts).is_err());
+    }
+
+    #[test]
+    fn max_distinct_gives_up_only_past_the_limit() {
+        let input = "192.0.2.1\n192.0.2.2\n192.0.2.1\n";
+        let opts = ProcessOptions { max_distinct: Some(2), ..ipv4_options() };
+        assert_eq!(count(input, &opts).unwrap().len(), 2);
+        let opts = ProcessOptions { max_distinct: Some(1), ..ipv4_options() };
+        let err = count(input, &opts).unwrap_err();
+        assert!(matches!(err, Error::TooManyDistinct { max: 1, .. }), "{err}");
+        assert!(err.to_string().contains("Found more than 1 distinct IPs"), "{err}");
+    }
+
+    /// Hands out its lines one by one, with `None` standing for a moment without a new line
+    struct Script(VecDeque<Option<&'static str>>);
+
+    impl LineSource for Script {
+        fn next_line(&mut self, line: &mut String) -> io::Result<source::Next> {
+            Ok(match self.0.pop_front() {
+                Some(Some(text)) => {
+                    line.push_str(text);
+                    source::Next::Line { bytes: text.len(), too_long: false }
+                }
+                Some(None) => source::Next::Pending,
+                None => source::Next::Eof,
+            })
+        }
+
+        fn name(&self) -> &str {
+            "script"
+        }
+    }
+
+    #[test]
+    fn sources_are_asked_again_until_they_end() {
+        let mut source = Script(VecDeque::from([Some("192.0.2.1\n"), None, Some("192.0.2.1\n"), None]));
+        let mut stats = Stats::default();
+        let mut summary = Summary::default();
+        process_source(&mut source, &mut stats, &ipv4_options(), &mut summary, None, None).unwrap();
+        assert_eq!((stats["192.0.2.1"], summary.lines), (2, 2));
+    }
+
+    #[test]
+    fn sources_stop_at_a_limit() {
+        let mut source = Script(VecDeque::from([Some("192.0.2.1\n"), Some("192.0.2.2\n"), Some("192.0.2.3\n")]));
+        let opts = ProcessOptions { head: Some(2), ..ipv4_options() };
+        let mut stats = Stats::default();
+        let mut summary = Summary::default();
+        process_source(&mut source, &mut stats, &opts, &mut summary, None, None).unwrap();
+        assert_eq!((stats.len(), summary.truncated_by), (2, Some(Limit::Head)));
+        assert_eq!(source.0.len(), 1);
+    }
+
+    #[test]
+    fn tail_offset_finds_the_last_lines() {
+        let dir = scratch("tail-offset");
+        let offset = |data: &[u8], lines| {
+            let path = dir.join("log");
+            std::fs::write(&path, data).unwrap();
+            tail_offset(&mut File::open(&path).unwrap(), lines, 0).unwrap()
+        };
+        assert_eq!(offset(b"a\nb\nc\n", 2), Some(2));
+        assert_eq!(offset(b"a\nb\nc", 2), Some(2));
+        assert_eq!(offset(b"a\nb\nc\n", 5), Some(0));
+        assert_eq!(offset(b"a\nb\nc\n", 0), None);
+        assert_eq!(offset(&[0x1f, 0x8b, 8, 0], 1), None);
+        // The line breaks are more than a block apart
+        let long = format!("{}\n{}\nlast\n", "x".repeat(100_000), "y".repeat(100_000));
+        assert_eq!(offset(long.as_bytes(), 2), Some(100_001));
+        // Nothing before the resume offset is counted
+        let path = dir.join("log");
+        std::fs::write(&path, b"a\nb\nc\n").unwrap();
+        assert_eq!(tail_offset(&mut File::open(&path).unwrap(), 5, 4).unwrap(), Some(4));
+        assert_eq!(tail_offset(&mut File::open(&path).unwrap(), 1, 6).unwrap(), Some(6));
+    }
+
+    #[test]
+    fn tail_lines_are_taken_from_what_was_appended() {
+        let dir = scratch("tail-resume");
+        let opts = ProcessOptions { tail_lines: Some(2), ..ipv4_options() };
+        let stats = count_new(&dir, "192.0.2.1\n192.0.2.2\n192.0.2.3\n", &opts);
+        assert_eq!(stats.len(), 2);
+        let stats = count_new(&dir, "192.0.2.4\n", &opts);
+        assert_eq!(stats.into_iter().collect::<Vec<_>>(), [(String::from("192.0.2.4"), 1)]);
+        // The offset points behind what was read, so nothing is counted twice
+        assert!(count_new(&dir, "", &opts).is_empty());
+        let mut stats = count_new(&dir, "192.0.2.5\n192.0.2.6\n192.0.2.7\n", &opts).into_iter().collect::<Vec<_>>();
+        stats.sort();
+        assert_eq!(stats, [(String::from("192.0.2.6"), 1), (String::from("192.0.2.7"), 1)]);
+    }
+
+    /// Counts `input` like stdin and returns what truncated it, if anything
+    fn truncated_by(input: &str, opts: ProcessOptions) -> Option<Limit> {
+        let mut summary = Summary::default();
+        process_file(&mut input.as_bytes(), "input", &mut Stats::default(), &opts, &mut summary, None, None).unwrap();
+        summary.truncated_by
+    }
+
+    #[test]
+    fn details_collect_only_what_is_asked_for() {
+        let opts = ProcessOptions { port_pattern: Some(Regex::new(r":(\d+)").unwrap()), ..ipv4_options() };
+        let input = "192.0.2.1:80\n192.0.2.1:443\n192.0.2.1:80\n";
+        let mut details = Details { raw: None, ports: Some(PortSets::new()), pairs: None };
+        let mut stats = Stats::default();
+        process_file(&mut input.as_bytes(), "input", &mut stats, &opts, &mut Summary::default(), Some(&mut details), None)
+            .unwrap();
+        assert!(details.raw.is_none());
+        assert_eq!(details.ports.unwrap()["192.0.2.1"].len(), 2);
+    }
+
+    #[test]
+    fn raw_keeps_the_first_match_before_normalization() {
+        let opts = ProcessOptions::new(Regex::new(DEFAULT_PATTERN).unwrap());
+        let input = "::ffff:192.0.2.1\n::ffff:192.0.2.1\n::ffff:010.000.002.003\n";
+        let mut details = Details { raw: Some(RawMatches::default()), ports: None, pairs: None };
+        let mut stats = Stats::default();
+        process_file(&mut input.as_bytes(), "input", &mut stats, &opts, &mut Summary::default(), Some(&mut details), None)
+            .unwrap();
+        let raw = details.raw.unwrap();
+        assert_eq!(raw["192.0.2.1"], "::ffff:192.0.2.1");
+        assert_eq!(raw["10.0.2.3"], "::ffff:010.000.002.003");
+    }
+
+    #[test]
+    fn limits_reached_at_the_end_do_not_truncate() {
+        let input: String = (1..=10).map(|i| format!("192.0.2.{i}\n")).collect();
+        assert_eq!(truncated_by(&input, ProcessOptions { head: Some(10), ..ipv4_options() }), None);
+        assert_eq!(truncated_by(&input, ProcessOptions { head_total: Some(10), ..ipv4_options() }), None);
+        assert_eq!(truncated_by(&input, ProcessOptions { max_unique: Some(10), ..ipv4_options() }), None);
+    }
+
+    #[test]
+    fn limits_before_the_end_truncate() {
+        let input: String = (1..=10).map(|i| format!("192.0.2.{i}\n")).collect();
+        assert_eq!(truncated_by(&input, ProcessOptions { head: Some(9), ..ipv4_options() }), Some(Limit::Head));
+        assert_eq!(truncated_by(&input, ProcessOptions { head_total: Some(9), ..ipv4_options() }), Some(Limit::HeadTotal));
+        assert_eq!(truncated_by(&input, ProcessOptions { max_unique: Some(9), ..ipv4_options() }), Some(Limit::MaxUnique));
+    }
+
+    #[test]
+    fn head_total_spans_files() {
+        let dir = scratch("head-total");
+        let files: Vec<String> = ["a", "b", "c"].iter().map(|name| {
+            let path = dir.join(name);
+            std::fs::write(&path, if *name == "c" { "" } else { "192.0.2.1\n192.0.2.2\n" }).unwrap();
+            path.display().to_string()
+        }).collect();
+        let count = |head_total, files: &[String]| {
+            let opts = ProcessOptions { head_total: Some(head_total), ..ipv4_options() };
+            let mut summary = Summary::default();
+            let mut stats = Stats::default();
+            process_files(files, &mut stats, &opts, &mut summary, None, None).unwrap();
+            (stats.values().sum::<u32>(), summary.truncated_by)
+        };
+        assert_eq!(count(4, &files), (4, None));
+        assert_eq!(count(2, &files), (2, Some(Limit::HeadTotal)));
+        assert_eq!(count(3, &files), (3, Some(Limit::HeadTotal)));
+    }
+
+
+    #[test]
+    fn host_tiebreak_decides_the_cutoff() {
+        let stats: Stats = [("192.0.2.1", 1), ("192.0.2.2", 1), ("192.0.2.3", 1), ("192.0.2.4", 5)]
+            .into_iter()
+            .map(|(ip, count)| (ip.to_string(), count))
+            .collect();
+        let opts = ReportOptions {
+            max_results: Some(2),
+            tiebreak: Tiebreak::Host,
+            numeric: false,
+            ..ReportOptions::new("{cnt} {host}")
+        };
+        for (ip, host) in [("192.0.2.1", "z.example"), ("192.0.2.2", "a.example"), ("192.0.2.3", "m.example")] {
+            opts.hosts.hosts.borrow_mut().insert(ip.parse().unwrap(), host.to_string());
+        }
+        let keys = |opts: &ReportOptions| select(&stats, opts).into_iter().map(|(key, _)| key.as_str()).collect::<Vec<_>>();
+        assert_eq!(keys(&opts), ["192.0.2.1", "192.0.2.4"]);
+        let opts = ReportOptions { reverse: true, ..opts };
+        assert_eq!(keys(&opts), ["192.0.2.4", "192.0.2.2"]);
+    }
+
+    #[test]
+    fn threshold_spares_the_keys_to_always_show() {
+        let stats: Stats = [("192.0.2.1", 1), ("192.0.2.2", 2), ("192.0.2.3", 3), ("192.0.2.4", 1)]
+            .into_iter()
+            .map(|(ip, count)| (ip.to_string(), count))
+            .collect();
+        let opts = ReportOptions {
+            threshold: Some(2),
+            always_show: HashSet::from(["192.0.2.1".to_string(), "192.0.2.3".to_string(), "192.0.2.9".to_string()]),
+            ..ReportOptions::new("{cnt} {ip}")
+        };
+        let keys = select(&stats, &opts).into_iter().map(|(key, _)| key.as_str()).collect::<Vec<_>>();
+        assert_eq!(keys, ["192.0.2.1", "192.0.2.3"]);
+    }
+
+    #[test]
+    fn ssv_aligns_the_counts_as_printed() {
+        let stats: Stats = [("192.0.2.1", 1234567), ("192.0.2.2", 5), ("192.0.2.3", 999)]
+            .into_iter()
+            .map(|(ip, count)| (ip.to_string(), count))
+            .collect();
+        let opts = ReportOptions { output_format: OutputFormat::Ssv, human: Some(HumanFormat::Si), ..ReportOptions::new("") };
+        let mut out = Vec::new();
+        print_stats(&mut out, &stats, &opts).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "   5 192.0.2.2\n 999 192.0.2.3\n1.2M 192.0.2.1\n");
+    }
+
+    #[test]
+    fn numbers_never_use_the_locale() {
+        assert_eq!(format_percent(1, 3), "33.3");
+        assert_eq!(format_percent(2, 3), "66.7");
+        assert_eq!(format_percent(1, 0), "0.0");
+        assert_eq!(humanize(1234567, HumanFormat::Comma), "1,234,567");
+        assert_eq!(humanize(1234567, HumanFormat::Si), "1.2M");
+        assert_eq!(humanize(999950, HumanFormat::Si), "1.0M");
+    }
+
+    #[test]
+    fn distinct_ports_can_rank_the_report() {
+        let stats: Stats = [("192.0.2.1".to_string(), 5), ("192.0.2.2".to_string(), 2)].into_iter().collect();
+        let ports: PortSets = [("192.0.2.2".to_string(), HashSet::from([22, 80, 443]))].into_iter().collect();
+        let opts = ReportOptions { ports: Some(ports), ..ReportOptions::new("{distinct_ports} {ip}") };
+        let mut lines = Vec::new();
+        report_each(&stats, &opts, |record| {
+            lines.push(record.format(&opts.format)?);
+            Ok(())
+        }).unwrap();
+        assert_eq!(lines, ["3 192.0.2.2", "0 192.0.2.1"]);
+        let opts = ReportOptions { sort_by_ports: true, ..opts };
+        let keys = select(&stats, &opts).into_iter().map(|(key, _)| key.as_str()).collect::<Vec<_>>();
+        assert_eq!(keys, ["192.0.2.1", "192.0.2.2"]);
+    }
+
+    #[test]
+    fn jsonl_schema_keeps_the_field_order() {
+        let stats: Stats = [("192.0.2.1".to_string(), 2)].into_iter().collect();
+        let opts = ReportOptions { output_format: OutputFormat::JsonlSchema, ..ReportOptions::new("") };
+        let render = |opts: &ReportOptions| {
+            let mut out = Vec::new();
+            print_stats(&mut out, &stats, opts).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(render(&opts), "{\"schema\":{\"ip\":\"string\",\"count\":\"integer\"}}\n{\"ip\":\"192.0.2.1\",\"count\":2}\n");
+        let pretty = render(&ReportOptions { json_pretty: true, ..opts });
+        // Every object still parses on its own, even though it spans several lines
+        let objects = serde_json::Deserializer::from_str(&pretty).into_iter::<serde_json::Value>();
+        assert_eq!(objects.map(|object| object.unwrap()).collect::<Vec<_>>()[1], serde_json::json!({"ip": "192.0.2.1", "count": 2}));
+        assert!(pretty.contains("\n  \"ip\": \"192.0.2.1\",\n"));
+    }
+
+    #[test]
+    fn cidrs_are_the_biggest_aligned_blocks() {
+        let ips = |list: &[&str]| list.iter().map(|ip| ip.parse().unwrap()).collect::<Vec<IpAddr>>();
+        let input = ips(&["192.0.2.6", "192.0.2.0", "192.0.2.1", "192.0.2.2", "192.0.2.3", "192.0.2.4", "192.0.2.5", "192.0.2.1"]);
+        assert_eq!(collapse_cidrs(input), ["192.0.2.0/30", "192.0.2.4/31", "192.0.2.6/32"]);
+        // Neighbours across the families or with a gap stay apart
+        let input = ips(&["2001:db8::1", "192.0.2.255", "192.0.3.0", "192.0.3.2", "2001:db8::"]);
+        assert_eq!(collapse_cidrs(input), ["192.0.2.255/32", "192.0.3.0/32", "192.0.3.2/32", "2001:db8::/127"]);
+        assert_eq!(collapse_cidrs(ips(&["0.0.0.0", "0.0.0.1"])), ["0.0.0.0/31"]);
+    }
+
+    #[test]
+    fn pairs_are_counted_once_per_line_in_order() {
+        let mut pairs = PairCounts::new();
+        let keys = |list: &[&str]| list.iter().map(|key| key.to_string()).collect::<Vec<_>>();
+        count_pairs(&mut pairs, &keys(&["a", "b", "a", "c"]));
+        count_pairs(&mut pairs, &keys(&["a", "b"]));
+        count_pairs(&mut pairs, &keys(&["a"]));
+        let mut counted: Vec<_> = pairs.into_iter().collect();
+        counted.sort();
+        let pair = |a: &str, b: &str, count| ((a.to_string(), b.to_string()), count);
+        assert_eq!(counted, [pair("a", "b", 2), pair("a", "c", 1), pair("b", "c", 1)]);
+    }
+
+    #[test]
+    fn dot_draws_edges_between_reported_keys() {
+        let opts = ProcessOptions { key_end: Some(2), ..ipv4_options() };
+        let input = "192.0.2.1 -> 192.0.2.2\n192.0.2.1 -> 192.0.2.2\n192.0.2.1 -> 192.0.2.3\n";
+        let mut details = Details { pairs: Some(PairCounts::new()), ..Details::default() };
+        let mut stats = Stats::default();
+        process_file(&mut input.as_bytes(), "input", &mut stats, &opts, &mut Summary::default(), Some(&mut details), None)
+            .unwrap();
+        let opts = ReportOptions {
+            output_format: OutputFormat::Dot,
+            max_results: Some(2),
+            pairs: details.pairs,
+            ..ReportOptions::new("")
+        };
+        let mut out = Vec::new();
+        print_stats(&mut out, &stats, &opts).unwrap();
+        let graph = String::from_utf8(out).unwrap();
+        assert!(graph.contains("\"192.0.2.1\" -> \"192.0.2.2\" [label=\"2\", penwidth=5.00];"), "{graph}");
+        // 192.0.2.3 is not reported, so neither is its edge
+        assert!(!graph.contains("192.0.2.3"), "{graph}");
+    }
+
+    #[test]
+    fn apache_deny_starts_with_the_given_comment() {
+        let stats: Stats = [(String::from("192.0.2.1"), 3)].into_iter().collect();
+        let opts = ReportOptions {
+            output_format: OutputFormat::ApacheDeny,
+            apache_version: ApacheVersion::V22,
+            apache_comment: Some(String::from("Generated for a test\nipstats --output-format apache-deny")),
+            ..ReportOptions::new("")
+        };
+        let mut out = Vec::new();
+        print_stats(&mut out, &stats, &opts).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "# Generated for a test\n# ipstats --output-format apache-deny\nDeny from 192.0.2.1\n");
+    }
+
+    #[test]
+    fn dot_escapes_keys() {
+        let stats: Stats = [(String::from("say \"hi\" \\o/"), 1)].into_iter().collect();
+        let opts = ReportOptions { output_format: OutputFormat::Dot, ..ReportOptions::new("") };
+        let mut out = Vec::new();
+        print_stats(&mut out, &stats, &opts).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("    \"say \\\"hi\\\" \\\\o/\" [label="));
+    }
+
+    #[test]
+    fn records_carry_typed_fields() {
+        let stats: Stats = [("192.0.2.1".to_string(), 3), ("192.0.2.2".to_string(), 1)].into_iter().collect();
+        let baseline: Stats = [("192.0.2.1".to_string(), 5)].into_iter().collect();
+        let opts = ReportOptions { reverse: true, baseline: Some(baseline), ..ReportOptions::new("{cnt} {ip} {delta}") };
+        let mut records = Vec::new();
+        report_each(&stats, &opts, |record| {
+            assert!(record.host.is_none() && record.class.is_none());
+            records.push((record.ip, record.rank, record.cumulative, record.prev, record.delta(), record.format(&opts.format)?));
+            Ok(())
+        }).unwrap();
+        // With a baseline the report is ranked by the change
+        assert_eq!(records, [
+            ("192.0.2.2", 1, 1, Some(0), Some(1), String::from("1 192.0.2.2 +1")),
+            ("192.0.2.1", 2, 4, Some(5), Some(-2), String::from("3 192.0.2.1 -2")),
+        ]);
+    }
+
+    #[test]
+    fn records_only_have_the_variables_asked_for() {
+        let stats: Stats = [("192.0.2.1".to_string(), 1)].into_iter().collect();
+        let opts = ReportOptions::new("{cnt} {country}");
+        report_each(&stats, &opts, |record| {
+            assert_eq!(record.var("percent").as_deref(), Some("100.0"));
+            assert_eq!(record.var("country"), None);
+            assert!(record.format(&opts.format).is_err());
+            Ok(())
+        }).unwrap();
+    }
+}
@@ -1,227 +1,2012 @@
 use std::fs::File;
 use std::io;
-use std::io::BufReader;
+use std::io::IsTerminal;
 use std::io::prelude::*;
 use std::net::IpAddr;
-use std::collections::HashMap;
+use std::path::{ Path, PathBuf };
+use std::collections::{ HashMap, HashSet };
+use std::time::{ Duration, Instant, SystemTime };
 
-use strfmt;
-use clap::Parser;
+use clap::{ CommandFactory, Parser };
 use regex::Regex;
-use tree_magic_mini;
-use flate2::bufread::GzDecoder;
-use dns_lookup::lookup_addr;
+use walkdir::WalkDir;
 use anyhow::{ Context, Result, bail };
+use log::{ Level, LevelFilter, debug, info, warn };
 
+mod config;
 
-type Stats = HashMap<String, u32>;
+use ipstats::{
+    DEFAULT_PATTERN, Details, Error, HostCache, Limit, PairCounts, PortSets, ProcessOptions, RawMatches, ReportOptions,
+    Sampler, Stats, StatsCollector, Summary,
+    aggregate_by_asn, for_each_line, get_reader, load_baseline, normalize_key, parse_report_line, print_nmap, print_stats,
+    process_file, process_files, report, select,
+};
+use ipstats::bitmap::Bitmap;
+use ipstats::error;
+use ipstats::geoip::GeoIp;
+use ipstats::offsets::Offsets;
+use ipstats::redact::{ self, Redactor };
+use ipstats::serve::MetricsServer;
+use ipstats::state::State;
+use ipstats::stix;
+use ipstats::syslog::SyslogWriter;
+use ipstats::whois::Whois;
+use ipstats::xml::XmlPath;
+use config::Config;
 
 
-fn get_reader(file: &mut impl Read) -> Result<Box<dyn BufRead + '_>> {
-    let mut reader = BufReader::new(file);
-    if tree_magic_mini::match_u8(
-        "application/gzip",
-        reader.fill_buf().context("Could not peek into buffer to check for compression")?,
-    ) {
-        return Ok(Box::new(BufReader::new(GzDecoder::new(reader))));
+/// All variables that may be used in a format string or selected with --fields
+const FORMAT_VARIABLES: &[&str] = &[
+    "cnt", "est_cnt", "percent", "rank", "cumulative", "cumulative_percent", "ip", "host", "class", "country", "abuse_contact", "netname", "prev", "delta", "file", "source", "raw",
+    "city", "distinct_ports", "region", "postal", "lat", "lon",
+];
+
+/// Other names accepted for some of the `FORMAT_VARIABLES`
+const VARIABLE_ALIASES: &[(&str, &str)] = &[("running_total", "cumulative"), ("running_pct", "cumulative_percent")];
+
+/// Exit status when --skip-errors or --keep-going skipped part of the input
+const EXIT_INCOMPLETE: i32 = 3;
+
+/// Variables that need a reverse DNS lookup, so they cannot be used with --numeric
+const DNS_VARIABLES: &[&str] = &["host"];
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum FormatPreset {
+    /// `{rank}. {cnt} {host} ({ip}) {percent}%`
+    Detailed,
+    /// `{ip}`
+    Ips,
+    /// `{cnt} {ip} {country} {netname} {abuse_contact}`, implies --whois, {country} needs --geoip-db
+    Abuse,
+}
+
+impl FormatPreset {
+    fn template(self, numeric: bool, geoip: bool) -> String {
+        match self {
+            FormatPreset::Detailed if numeric => String::from("{rank}. {cnt} {ip} {percent}%"),
+            FormatPreset::Detailed => String::from("{rank}. {cnt} {host} ({ip}) {percent}%"),
+            FormatPreset::Ips => String::from("{ip}"),
+            FormatPreset::Abuse if geoip => String::from("{cnt} {ip} {country} {netname} {abuse_contact}"),
+            FormatPreset::Abuse => String::from("{cnt} {ip} {netname} {abuse_contact}"),
+        }
     }
-    Ok(Box::new(reader))
 }
 
-fn process_file(
-    mut file: &mut impl Read,
-    stats: &mut Stats,
-    pattern: &Regex,
-    key: usize,
-    pedantic: bool,
-    fixed_ips: bool,
-) -> Result<()> {
-    let mut line = String::new();
-    let mut reader = get_reader(&mut file).context("Failed getting reader")?;
-    let key = key - 1;
-
-    loop {
-        match reader.read_line(&mut line).context("Reading next line")? {
-            0 => { break }
-            _bytes_read => {
-                // Either use the line almost as-is, or apply the pattern to exract IPs
-                let m = if fixed_ips {
-                    Some(line.trim())
-                } else if let Some(m) = pattern.find_iter(&line).nth(key) {
-                    Some(m.as_str())
-                } else {
-                    None
-                };
 
-                // Either increment the counter for the IP or bail out if none was found and we are
-                // running in pedantic mode.
-                // We also Strip ::ffff: from the start of the collected IP since it is used to
-                // express mappable addresses like ::ffff:192.168.1.1, which only seem to properly
-                // resolve when the prefix is stripped, since we accept a custom regex we cannot
-                // rely on the regex matching things the right way, so we always make sure we
-                // strip that off the match
-                if let Some(m) = m {
-                    stats.entry(
-                        m.to_string()
-                            .strip_prefix("::ffff:")
-                            .unwrap_or(&m.to_string())
-                            .into()
-                    )
-                    .and_modify(|counter| *counter += 1)
-                    .or_insert(1);
-                } else if pedantic {
-                    bail!("Could not extract IP from line: {:?}", line);
-                }
+/// Declares the command line side of a library enum: a clap value enum with the same variants,
+/// converting into the library one, so the library itself does not depend on clap
+macro_rules! value_enum {
+    ($(#[$meta:meta])* enum $name:ident => $lib:ty { $($(#[$variant_meta:meta])* $variant:ident,)* }) => {
+        $(#[$meta])*
+        #[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+        enum $name {
+            $($(#[$variant_meta])* $variant,)*
+        }
 
-                line.clear();
+        impl From<$name> for $lib {
+            fn from(value: $name) -> Self {
+                match value {
+                    $($name::$variant => <$lib>::$variant,)*
+                }
             }
-        };
+        }
+    };
+}
+
+value_enum! {
+    enum OutputFormat => ipstats::OutputFormat {
+        /// One line per IP, formatted according to --format
+        Text,
+        /// GraphViz digraph with one node per IP, sized by count
+        Dot,
+        /// Bare IPs, one per line, for use with `nmap -iL`
+        Nmap,
+        /// The --fields joined by --delimiter without any quoting, with space separated counts right aligned
+        Ssv,
+        /// JSON Lines, preceded by a line declaring the type of every field
+        JsonlSchema,
+        /// PTR records for a DNS zone file
+        Zone,
+        /// Binary Parquet file, for analytics tools like DuckDB
+        Parquet,
+        /// GitHub Flavored Markdown table, for reports and issues
+        Markdown,
+        /// LaTeX table, for papers and formal reports
+        Latex,
+        /// nginx `geo` block setting a variable for the reported IPs, e.g. to block them
+        NginxMap,
+        /// HAProxy `acl` lines matching the reported IPs, for a frontend section
+        HaproxyAcl,
+        /// Apache httpd directives denying access to the reported IPs
+        ApacheDeny,
+        /// `/etc/hosts` entries pointing the reported IPs at --hosts-domain
+        Hosts,
+        /// RFC 5424 syslog messages with the IP, count and hostname as structured data and --format as message
+        SyslogRfc5424,
     }
-    Ok(())
 }
 
-fn print_stats(
-    stats: Stats,
-    max_results: Option<usize>,
-    numeric: bool,
-    threshold: Option<u32>,
-    format: &str,
-) -> Result<()> {
-    // If a threshold is passed, drop all values below threshold
-    let mut sorted: Vec<_> = if let Some(threshold) = threshold {
-        stats.iter().filter(|v| v.1 > &threshold).collect()
+value_enum! {
+    enum ApacheVersion => ipstats::ApacheVersion {
+        /// `Deny from` lines, for mod_authz_host of httpd 2.2
+        #[clap(name = "2.2")]
+        V22,
+        /// `Require not ip` lines in a `RequireAll` block, for httpd 2.4 and later
+        #[clap(name = "2.4")]
+        V24,
+    }
+}
+
+value_enum! {
+    enum Tiebreak => ipstats::Tiebreak {
+        /// Sort entries with the same count by IP
+        Ip,
+        /// Sort entries with the same count alphabetically by hostname, clustering e.g. CDN nodes
+        Host,
+    }
+}
+
+value_enum! {
+    enum KeyFallback => ipstats::KeyFallback {
+        /// Count nothing for the line, as if it had no IP at all
+        None,
+        /// Count the first match instead
+        First,
+        /// Count the last match instead
+        Last,
+    }
+}
+
+value_enum! {
+    enum Firewall => ipstats::Firewall {
+        /// `iptables`/`ip6tables` commands appending DROP rules to the INPUT chain
+        Iptables,
+        /// `nft` commands adding drop rules to the input chain of the `inet filter` table
+        Nftables,
+        /// `add` lines for `ipset restore`, IPv6 addresses go to a set with a `6` suffix
+        Ipset,
+    }
+}
+
+value_enum! {
+    enum HumanFormat => ipstats::HumanFormat {
+        /// Abbreviate using SI suffixes, e.g. 1.2M
+        Si,
+        /// Group thousands with commas, e.g. 1,234,567
+        Comma,
+    }
+}
+
+value_enum! {
+    enum NetworkClass => ipstats::class::NetworkClass {
+        /// 127.0.0.0/8 and ::1
+        Loopback,
+        /// RFC 1918 and unique local (fc00::/7) addresses
+        Private,
+        /// 169.254.0.0/16 and fe80::/10
+        LinkLocal,
+        /// 224.0.0.0/4 and ff00::/8
+        Multicast,
+        /// RFC 5737 and RFC 3849 documentation ranges
+        Documentation,
+        /// Everything else
+        Public,
+    }
+}
+
+value_enum! {
+    enum Compression => ipstats::columnar::Compression {
+        Uncompressed,
+        Snappy,
+        Gzip,
+        Zstd,
+    }
+}
+
+value_enum! {
+    enum RedactStyle => ipstats::redact::RedactStyle {
+        /// Replace every IP with `[REDACTED]`
+        Token,
+        /// Zero everything but the network prefix given by --mask, e.g. 192.0.2.0 for 192.0.2.55
+        Mask,
+        /// Replace every IP with a pseudonym like `ip-3f2a9c0b61de`, the same for every occurrence
+        Hash,
+    }
+}
+
+value_enum! {
+    enum Facility => ipstats::syslog::Facility {
+        Kern,
+        User,
+        Mail,
+        Daemon,
+        Auth,
+        Syslog,
+        Lpr,
+        News,
+        Uucp,
+        Cron,
+        Authpriv,
+        Ftp,
+        Local0,
+        Local1,
+        Local2,
+        Local3,
+        Local4,
+        Local5,
+        Local6,
+        Local7,
+    }
+}
+
+value_enum! {
+    enum Severity => ipstats::syslog::Severity {
+        Emerg,
+        Alert,
+        Crit,
+        Err,
+        Warning,
+        Notice,
+        Info,
+        Debug,
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ErrorFormat {
+    /// Human readable messages
+    Text,
+    /// One JSON object per error or warning, with `kind`, `message`, `file` and `line`
+    Json,
+}
+
+
+fn parse_sample_rate(value: &str) -> Result<f64, String> {
+    let rate: f64 = value.parse().map_err(|err| format!("{err}"))?;
+    if rate > 0.0 && rate <= 1.0 {
+        Ok(rate)
     } else {
-        stats.iter().collect()
-    };
+        Err(String::from("must be greater than 0 and at most 1"))
+    }
+}
+
+fn parse_replacement(value: &str) -> Result<(Regex, String), String> {
+    // Split at the last colon, since the pattern is much more likely to contain
+    // colons (think IPv6) than the replacement
+    let (pattern, replacement) = value.rsplit_once(':').ok_or("expected <regex>:<replacement>")?;
+    let pattern = Regex::new(pattern).map_err(|err| format!("{err}"))?;
+    Ok((pattern, replacement.to_string()))
+}
 
-    // Sort by count
-    sorted.sort_by_key(|n| n.1);
+/// Labels are split off at the first colon again, so they cannot contain one
+fn parse_prepend_label(value: &str) -> Result<String, String> {
+    if value.is_empty() || value.contains(|c: char| c == ':' || c.is_whitespace()) {
+        return Err(String::from("must be non-empty, without colons or whitespace"));
+    }
+    Ok(value.to_string())
+}
 
-    // Apply limit if `max_results` is passed, not sure what is the
-    // best method here, but since `take` seems to express what
-    // we actually want to do, we need to `rev` the vec twice
-    // to cut off the correct portion of elements, there is probably
-    // a better when if you know what you're doing. :-(
-    let sorted: Vec<_> = if let Some(max_results) = max_results {
-        sorted.iter().rev().take(max_results).rev().collect()
+/// Parses the `<start>:<end>` of --key-range, both counting from 1 and inclusive
+fn parse_key_range(value: &str) -> Result<(usize, usize), String> {
+    let (start, end) = value.split_once(':').ok_or("expected <start>:<end>, e.g. 2:4")?;
+    let start: usize = start.parse().map_err(|err| format!("invalid start {start:?}: {err}"))?;
+    let end: usize = end.parse().map_err(|err| format!("invalid end {end:?}: {err}"))?;
+    if start == 0 || end < start {
+        return Err(String::from("the range starts at 1 at the earliest and cannot end before it starts"));
+    }
+    Ok((start, end))
+}
+
+/// Anchors the --trim-suffix-regex to the end of the match
+fn parse_suffix(value: &str) -> Result<Regex, String> {
+    // Compile it as given first, so errors point at what the user wrote
+    Regex::new(value).and_then(|_| Regex::new(&format!("(?:{value})$"))).map_err(|err| format!("{err}"))
+}
+
+/// Stops serving metrics on Ctrl-C before exiting like the interrupt would have, reading stdin
+/// cannot be interrupted to print a final report
+#[cfg(feature = "serve")]
+fn stop_on_interrupt(metrics: &MetricsServer) -> Result<()> {
+    let stop = metrics.stopper();
+    ctrlc::set_handler(move || {
+        stop();
+        std::process::exit(130);
+    }).context("Could not handle Ctrl-C")
+}
+
+/// Without the `serve` feature there is never a server to stop
+#[cfg(not(feature = "serve"))]
+fn stop_on_interrupt(_metrics: &MetricsServer) -> Result<()> {
+    Ok(())
+}
+
+/// Where --output-dir puts the report of one input file, named after its label or its path with an
+/// extension matching the output format
+fn report_path(dir: &str, path: &str, label: Option<&str>, opts: &ReportOptions) -> PathBuf {
+    use ipstats::OutputFormat::*;
+
+    let extension = match opts.output_format {
+        _ if opts.emit_rules.is_some() => "rules",
+        Text | Nmap | Ssv => "txt",
+        Dot => "dot",
+        JsonlSchema => "jsonl",
+        Zone => "zone",
+        Parquet => "parquet",
+        Markdown => "md",
+        Latex => "tex",
+        NginxMap | ApacheDeny => "conf",
+        HaproxyAcl => "cfg",
+        Hosts => "hosts",
+        SyslogRfc5424 => "log",
+    };
+    let name = label.unwrap_or(path).trim_start_matches("./").trim_start_matches('/').replace(['/', '\\'], "_");
+    Path::new(dir).join(format!("{name}.{extension}"))
+}
+
+/// Writes just the reported IPs, one per line, to `path` or stdout for `-`
+fn write_ip_list(path: &str, selected: &[(&String, &u32)]) -> Result<()> {
+    let mut list: Box<dyn Write> = if path == "-" {
+        Box::new(io::stdout().lock())
     } else {
-        sorted.iter().collect()
+        Box::new(io::BufWriter::new(File::create(path).context(format!("Could not create IP list: {path}"))?))
     };
+    print_nmap(&mut list, selected, false)?;
+    list.flush().context(format!("Could not write IP list: {path}"))
+}
 
-    // Runtime format print all elements, optionally lookup the hostnames
-    for (key, value) in sorted.iter() {
-        let mut vars: HashMap<String, String> = HashMap::new();
-        vars.insert("cnt".to_string(), value.to_string());
-        vars.insert("ip".to_string(), key.to_string());
-        if ! numeric {
-            let ip: IpAddr = key.parse().with_context(|| format!("Could not parse IP: {key}"))?;
-            let host = lookup_addr(&ip).with_context(|| format!("Could not lookup host for IP: {key}"))?;
-            vars.insert("host".to_string(), host.clone());
+/// Exports the reported IPs as STIX 2.1 indicators, named after their hostname if known. The
+/// hostnames come from the cache filled while printing the report, nothing is looked up twice.
+fn write_iocs(path: &str, selected: &[(&String, &u32)], opts: &ReportOptions, confidence: Option<u8>) -> Result<()> {
+    let mut entries = Vec::new();
+    for (key, _) in selected {
+        if let Ok(ip) = key.parse::<IpAddr>() {
+            let host = if opts.numeric { Ok(key.to_string()) } else { opts.hosts.lookup(key) };
+            let name = match host {
+                Ok(host) if host.parse::<IpAddr>().is_err() => host,
+                _ => key.to_string(),
+            };
+            entries.push((ip.to_canonical(), name));
         }
-        println!("{}", strfmt::strfmt(&format, &vars).context("Error while formatting record")?);
+    }
+    Ok(stix::write_bundle(path, &entries, confidence)?)
+}
+
+/// Reads lines from `file` into `input` until it holds `lines` lines or the file ends
+fn read_lines(mut file: impl Read, name: &str, lines: usize, input: &mut Vec<String>) -> Result<()> {
+    let (reader, _) = get_reader(&mut file).context(format!("Failed getting reader for {name}"))?;
+    for line in reader.lines().take(lines - input.len()) {
+        input.push(line.context(format!("Reading next line of {name}"))?);
     }
     Ok(())
 }
 
+/// Times the default and the custom pattern against the first `lines` lines of the input
+/// and prints how they compare
+fn benchmark_patterns(files: &[String], lines: usize, default: &Regex, custom: &Regex) -> Result<()> {
+    let mut input = Vec::new();
+    if files.is_empty() {
+        read_lines(io::stdin(), "stdin", lines, &mut input)?;
+    } else {
+        for path in files.iter() {
+            if input.len() >= lines {
+                break;
+            }
+            read_lines(File::open(path).context(format!("Could not open file: {path}"))?, path, lines, &mut input)?;
+        }
+    }
+    if input.is_empty() {
+        bail!("No input to benchmark the patterns against");
+    }
+
+    let mut results = Vec::new();
+    for (name, pattern) in [("default", default), ("custom", custom)] {
+        let started = Instant::now();
+        let matches: usize = input.iter().map(|line| pattern.find_iter(line).count()).sum();
+        let elapsed = started.elapsed();
+        let throughput = input.len() as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+        println!("{name:<8} {matches} matches in {elapsed:.2?}, {throughput:.0} lines/sec");
+        results.push((name, matches, elapsed));
+    }
+
+    let (fastest, slowest) = if results[0].2 <= results[1].2 { (&results[0], &results[1]) } else { (&results[1], &results[0]) };
+    println!(
+        "The {} pattern is {:.1}x faster on {} lines",
+        fastest.0,
+        slowest.2.as_secs_f64() / fastest.2.as_secs_f64().max(f64::EPSILON),
+        input.len(),
+    );
+    if results[0].1 != results[1].1 {
+        println!("The patterns found a different number of matches, make sure the custom one catches all IPs you need");
+    }
+    Ok(())
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Print a completion script for the given shell to stdout
+    Completions {
+        #[clap(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// Print the man page in roff format to stdout, e.g. for `man -l -`
+    Man,
+    /// Count the IPs in the given files or stdin, the same as passing no subcommand
+    Count(Box<CountArgs>),
+    /// Print every distinct IP in the input with its hostname, in the order they show up
+    Resolve(ResolveArgs),
+    /// Print only the lines of the input an IP was found in, optionally only those of certain IPs
+    Filter(FilterArgs),
+    /// Compare two runs saved with --save-state (or two reports) and print the change per IP
+    Diff(DiffArgs),
+}
+
+/// Quickly find and sum up occurences of IPs in text
+///
+/// Uses a regex to extract IPs from stdin or a number of files (optionally gzipped) and counts their
+/// occurences per IP. IPs are resolved to hostnames unless --numeric is passed. The output is sorted
+/// by number of occurences, so the heavy hitters are at the bottom, and can be limited to the top
+/// results (--max-results) or to IPs above a threshold (--threshold). If there are multiple IPs per
+/// line, the desired one is selected with --key.
+///
+/// Subcommands are only recognized as the first argument, anything after `--` or after an option of
+/// the counting mode is taken as a file, e.g. `ipstats -- diff` counts the IPs in a file called diff.
 #[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
+#[clap(author, version, args_conflicts_with_subcommands = true)]
 struct Args {
-    /// Files to scan for IPs, otherwise stdin is used
+    /// Without a subcommand, count the IPs in the given files or stdin
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    #[clap(flatten)]
+    count: CountArgs,
+
+    /// Hide warnings on stderr, pass twice to also hide the --summary, errors are always printed
+    #[clap(long, short, global = true, action = clap::ArgAction::Count, conflicts_with = "verbose")]
+    quiet: u8,
+
+    /// Print more details about what is going on to stderr, -v for files, patterns and DNS cache
+    /// stats, -vv also for every line without an IP and every DNS lookup. RUST_LOG takes precedence,
+    /// e.g. RUST_LOG=ipstats=debug.
+    #[clap(long, short, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// How to print errors and warnings to stderr, `json` gives one object per line for other
+    /// programs to parse
+    #[clap(long, global = true, value_enum, default_value_t = ErrorFormat::Text, value_name = "FORMAT")]
+    errors: ErrorFormat,
+}
+
+/// Where to read from and how to find the IPs, shared by all subcommands
+#[derive(clap::Args, Debug)]
+struct InputArgs {
+    /// Files to scan for IPs, otherwise stdin is used. http:// and https:// URLs are fetched
+    /// instead, when built with the `url` feature. A first file named like a subcommand, e.g.
+    /// `man` or `diff`, has to come after `--`, as in `ipstats -- man`
     files: Vec<String>,
 
+    /// Provide a custom regex pattern to match the IP
+    #[clap(long, short, value_name = "REGEX", env = "IPSTATS_PATTERN")]
+    pattern: Option<String>,
+
+    /// If multiple IPs per line are found, use the Nth hit, starts at 1
+    #[clap(long, short, default_value_t = 1, value_name = "N")]
+    key: usize,
+
+    /// Assume the line contains a single IP without anything else in it
+    #[clap(long)]
+    fixed_ips: bool,
+
+    /// Count IPv4 addresses in their IPv4-mapped IPv6 form, e.g. ::ffff:192.0.2.1
+    #[clap(long)]
+    ipv4_as_ipv6: bool,
+
+    /// Keep IPv4 addresses with leading zeros like `010.001.002.003` as they are, instead of counting
+    /// them as 10.1.2.3. The zeros are always taken as decimal padding, although inet_aton and some
+    /// tools would read such octets as octal (010 being 8).
+    #[clap(long)]
+    no_normalize_octets: bool,
+
+    /// Read every regular file below the directories given as files, in name order. Unreadable
+    /// files are skipped with a warning, unless running with --pedantic.
+    #[clap(long, short, requires = "files")]
+    recursive: bool,
+
+    /// With --recursive, only read files whose name matches this glob, e.g. `access.log*`
+    #[clap(long, requires = "recursive", value_parser = glob::Pattern::new, value_name = "GLOB")]
+    glob: Option<glob::Pattern>,
+}
+
+impl InputArgs {
+    /// Replaces the directories among the files with the files below them, for --recursive
+    fn expand_dirs(&mut self, pedantic: bool) -> Result<()> {
+        if !self.recursive {
+            return Ok(());
+        }
+        let mut files = Vec::new();
+        for path in self.files.drain(..) {
+            if !Path::new(&path).is_dir() {
+                files.push(path);
+                continue;
+            }
+            for entry in WalkDir::new(&path).sort_by_file_name() {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(err) if pedantic => return Err(err).context(format!("Could not read directory: {path}")),
+                    Err(err) => {
+                        warn!("Skipping {err}");
+                        continue;
+                    }
+                };
+                if !entry.file_type().is_file() {
+                    continue;
+                }
+                if self.glob.as_ref().is_some_and(|glob| !glob.matches(&entry.file_name().to_string_lossy())) {
+                    continue;
+                }
+                let file = entry.path().display().to_string();
+                // Better to find out now than halfway through the archive
+                if let Err(err) = File::open(entry.path()) {
+                    if pedantic {
+                        return Err(err).context(format!("Could not open file: {file}"));
+                    }
+                    warn!(file = file.as_str(); "Skipping unreadable file {file}: {err}");
+                    continue;
+                }
+                files.push(file);
+            }
+        }
+        info!("Found {} files to read", files.len());
+        self.files = files;
+        Ok(())
+    }
+
+    /// Options for reading the input with nothing but the extraction configured, failing on
+    /// contradicting settings like --fixed-ips with a --key
+    fn process_options(&self, mac: bool) -> Result<ProcessOptions> {
+        let mut collector = StatsCollector::new()
+            .key(self.key)
+            .fixed_ips(self.fixed_ips)
+            .mac(mac)
+            .ipv4_as_ipv6(self.ipv4_as_ipv6)
+            .normalize_octets(!self.no_normalize_octets);
+        if self.pattern.is_some() {
+            collector = collector.pattern(compile_pattern(self)?);
+        }
+        Ok(collector.into_options()?)
+    }
+}
+
+/// Controls for the reverse DNS lookups
+#[derive(clap::Args, Debug)]
+struct DnsArgs {
+    /// Do not look up hosts for IPv4 addresses (including IPv4-mapped ones), {host} is the IP instead
+    #[clap(
+        long,
+        env = "IPSTATS_SKIP_V4_RDNS",
+        value_parser = clap::builder::BoolishValueParser::new(),
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true",
+        value_name = "BOOL",
+    )]
+    skip_v4_rdns: Option<bool>,
+
+    /// Do not look up hosts for IPv6 addresses, which rarely have PTR records, {host} is the IP instead
+    #[clap(
+        long,
+        env = "IPSTATS_SKIP_V6_RDNS",
+        value_parser = clap::builder::BoolishValueParser::new(),
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true",
+        value_name = "BOOL",
+    )]
+    skip_v6_rdns: Option<bool>,
+
+    /// Do at most this many host lookups per second, for resolvers that throttle bursts.
+    /// Cached and skipped lookups do not count.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..), value_name = "PER_SEC", env = "IPSTATS_DNS_RATE")]
+    dns_rate: Option<u32>,
+}
+
+impl DnsArgs {
+    fn host_cache(&self) -> HostCache {
+        HostCache::new(self.skip_v4_rdns.unwrap_or(false), self.skip_v6_rdns.unwrap_or(false), self.dns_rate)
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct ResolveArgs {
+    #[clap(flatten)]
+    input: InputArgs,
+
+    #[clap(flatten)]
+    dns: DnsArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct FilterArgs {
+    #[clap(flatten)]
+    input: InputArgs,
+
+    /// Only pass through the lines of the N most frequent IPs, counted in a first pass over the
+    /// input (stdin is kept in memory for that). IPs with the same count are picked in IP order,
+    /// like the report of --max-results does.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), value_name = "N")]
+    top: Option<u64>,
+
+    /// Only pass through the lines of IPs with more than this many occurences, counted in the same
+    /// first pass as --top
+    #[clap(long, short, value_name = "N")]
+    threshold: Option<u32>,
+
+    /// Only pass through the lines of the IPs listed in this file, either one per line (see
+    /// --ip-list-output) or a previous report
+    #[clap(long, conflicts_with_all = &["top", "threshold"], value_name = "FILE")]
+    ips_from: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct DiffArgs {
+    /// The earlier state file or report
+    old: String,
+
+    /// The later state file or report
+    new: String,
+
+    /// Format used per IP, may contain {ip}, {old}, {new}, {delta} and {status}, which is one of
+    /// `new`, `gone`, `up` or `down`
+    #[clap(long, short, default_value = "{delta} {old} {new} {ip} {status}")]
+    format: String,
+
+    /// Only show the N biggest changes
+    #[clap(long, short, value_name = "N")]
+    max_results: Option<usize>,
+}
+
+/// Counts the IPs in the input, what `ipstats` without a subcommand does
+#[derive(clap::Args, Debug)]
+struct CountArgs {
+    #[clap(flatten)]
+    input: InputArgs,
+
+    #[clap(flatten)]
+    dns: DnsArgs,
+
+    /// Count and report every file on its own, with a `== path ==` line before each report in text
+    /// output and {file} available in the format
+    #[clap(long, requires = "files", conflicts_with_all = &["load-state", "save-state"])]
+    separate: bool,
+
+    /// With --separate, write the report of every file to its own file in this directory instead of
+    /// stdout, named after its --label or its path, e.g. `logs_access.log.txt`. The directory is created
+    /// if missing.
+    #[clap(long, requires = "separate", conflicts_with_all = &["syslog", "syslog-server"], value_name = "DIR")]
+    output_dir: Option<String>,
+
+    /// Label the records with where they came from, available as {source} and added to machine readable
+    /// output. Either a single label for everything, or with --separate one label per file, in order.
+    #[clap(long)]
+    label: Vec<String>,
+
+    /// Count every IP as `<LABEL>:<ip>`, e.g. `web:192.0.2.1`, so the same IP from different sources stays
+    /// apart when their state files are combined with --load-state. Lookups still use the bare IP.
+    #[clap(long, value_parser = parse_prepend_label, conflicts_with_all = &["mac", "bitmap", "emit-rules"], value_name = "LABEL")]
+    prepend_label: Option<String>,
+
+    /// Do not read defaults from ~/.config/ipstats/config.toml and ./.ipstats.toml
+    #[clap(long)]
+    no_config: bool,
+
+    /// Process the given files in reverse order, e.g. newest first for rotated logs named
+    /// oldest to newest. Lines within a file are still read front to back.
+    #[clap(long)]
+    reverse_input: bool,
+
     /// Limit the number of results to show
-    #[clap(long, short)]
+    #[clap(long, short, value_name = "N", env = "IPSTATS_MAX_RESULTS")]
     max_results: Option<usize>,
 
-    /// Do not do any host lookups
-    #[clap(long, short)]
-    numeric: bool,
+    /// Always report this IP if it was seen at all, no matter --threshold and --max-results. Cut off
+    /// IPs are added back where they belong in the sort order. Can be given multiple times.
+    #[clap(long, value_name = "IP")]
+    always_show: Vec<String>,
 
-    /// If multiple IPs per line are found, use the Nth hit, starts at 1
-    #[clap(long, short, default_value_t = 1)]
-    key: usize,
+    /// Like --always-show, for the IPs in this file, one per line or a previous report
+    #[clap(long, value_name = "FILE")]
+    always_show_file: Option<String>,
+
+    /// Print the heavy hitters first instead of last
+    #[clap(long)]
+    reverse: bool,
+
+    /// How entries with the same count are ordered, `host` falls back to the IP with --numeric
+    #[clap(long, value_enum, default_value_t = Tiebreak::Ip)]
+    tiebreak: Tiebreak,
+
+    /// Do not do any host lookups, `--numeric=false` does them even if the config file turns them off
+    #[clap(
+        name = "numeric",
+        long = "numeric",
+        short = 'n',
+        env = "IPSTATS_NUMERIC",
+        value_parser = clap::builder::BoolishValueParser::new(),
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true",
+        value_name = "BOOL",
+    )]
+    numeric_arg: Option<bool>,
+
+    /// --numeric as given, from the config file or implied by the other options
+    #[clap(skip)]
+    numeric: bool,
 
     /// Only show IPs with at least this many occurences
-    #[clap(long, short)]
+    #[clap(long, short, value_name = "N", env = "IPSTATS_THRESHOLD")]
     threshold: Option<u32>,
 
     /// Bail out as soon as we hit a line without any IP in it
     #[clap(long)]
     pedantic: bool,
 
-    /// Provide a custom regex pattern to match the IP
-    #[clap(long, short)]
-    pattern: Option<String>,
+    /// What to count when a line has fewer matches than --key asks for, instead of skipping it. Lines
+    /// counted this way do not fail --pedantic.
+    #[clap(long, value_enum, default_value_t = KeyFallback::None, conflicts_with_all = &[
+        "key-range", "fixed-ips", "field", "json-input", "xml-input",
+    ], value_name = "MATCH")]
+    key_fallback: KeyFallback,
 
-    /// Assume the line contains a single IP without anything else in it
+    /// Count every match from the Nth to the Mth hit of each line instead of only one, e.g. `2:4`,
+    /// starting at 1 and inclusive. Lines with fewer matches count the ones they have.
+    #[clap(long, value_parser = parse_key_range, conflicts_with_all = &[
+        "key", "fixed-ips", "field", "json-input", "xml-input", "merge-reports", "annotate", "redact",
+    ], value_name = "N:M")]
+    key_range: Option<(usize, usize)>,
+
+    /// Rewrite each line with `<regex>:<replacement>` before extracting IPs, may be given multiple
+    /// times and is applied in order. The replacement may refer to groups, e.g. `$1`, but cannot
+    /// contain colons.
+    #[clap(long, value_parser = parse_replacement, value_name = "REGEX:REPLACEMENT")]
+    replace_pattern: Vec<(Regex, String)>,
+
+    /// Only count the first N characters of every match, e.g. 7 to get 1.2.3.4 out of `1.2.3.4/32`,
+    /// for inputs where the pattern cannot easily leave the rest out
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), value_name = "N")]
+    first_n_chars: Option<u64>,
+
+    /// Strip a trailing match of this regex from every IP before counting, e.g. `/\d+` to count
+    /// `1.2.3.4/32` as 1.2.3.4
+    #[clap(long, value_parser = parse_suffix, value_name = "REGEX")]
+    trim_suffix_regex: Option<Regex>,
+
+    /// Add the number in this whitespace separated field (starts at 1) to the IP's count instead
+    /// of counting the line once, for merging pre-aggregated input like `1234 192.0.2.1`
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), value_name = "N")]
+    count_field: Option<u64>,
+
+    /// Regex extracting a repeat count from each line, e.g. `(\d+) requests from`, which is added to
+    /// the IP's count instead of counting the line once. Uses the first capture group if there is one,
+    /// otherwise the whole match. Lines without a match count once. Only the IP selected with --key
+    /// gets the weight.
+    #[clap(long, conflicts_with = "count-field", value_name = "REGEX")]
+    weight_pattern: Option<Regex>,
+
+    /// If the weight pattern matches multiple times per line, use the Nth hit, starts at 1
+    #[clap(long, default_value_t = 1, requires = "weight-pattern", value_parser = clap::value_parser!(u64).range(1..), value_name = "N")]
+    weight_key: u64,
+
+    /// Regex extracting the destination port from each line, e.g. `DPT=(\d+)`, to count the distinct ports
+    /// of every IP as {distinct_ports}, a high number hints at a port scan. Uses the first capture group if
+    /// there is one, otherwise the whole match.
+    #[clap(long, conflicts_with_all = &["bitmap", "merge-reports"], value_name = "REGEX")]
+    distinct_ports: Option<Regex>,
+
+    /// Sort by the number of distinct ports instead of the count
+    #[clap(long, requires = "distinct-ports", conflicts_with = "baseline")]
+    sort_by_ports: bool,
+
+    /// Instead of counting, echo every line with ` [host=...]` inserted after its IP as soon as it is
+    /// read, looking up every IP only once
+    #[clap(long, conflicts_with_all = &[
+        "numeric", "mac", "count-field-values", "json-input", "bitmap", "separate", "benchmark-patterns", "format",
+        "fields", "format-preset", "output-format", "save-state", "load-state", "baseline", "ioc-output",
+        "ip-list-output", "summary",
+    ])]
+    annotate: bool,
+
+    /// Instead of counting, echo the input with every IP on every line replaced according to
+    /// --redact-style, e.g. to share log excerpts
+    #[clap(long, conflicts_with_all = &[
+        "annotate", "mac", "count-field-values", "json-input", "bitmap", "separate", "benchmark-patterns", "format",
+        "fields", "format-preset", "output-format", "save-state", "load-state", "baseline", "ioc-output",
+        "ip-list-output", "summary",
+    ])]
+    redact: bool,
+
+    /// How --redact replaces the IPs
+    #[clap(long, value_enum, default_value_t = RedactStyle::Token, requires = "redact")]
+    redact_style: RedactStyle,
+
+    /// Prefix lengths to keep with `--redact-style mask`, for IPv4 and IPv6
+    #[clap(long, use_value_delimiter = true, default_value = "24,48", value_name = "V4,V6")]
+    mask: Vec<u32>,
+
+    /// Secret mixed into the pseudonyms of `--redact-style hash`, the same key gives the same
+    /// pseudonyms across runs
+    #[clap(long, env = "IPSTATS_HASH_KEY", hide_env_values = true, value_name = "KEY")]
+    hash_key: Option<String>,
+
+    /// Count MAC addresses instead of IPs, normalized to lowercase and colon separated. Implies
+    /// --numeric, as there are no hostnames to look up.
+    #[clap(long, conflicts_with_all = &[
+        "pattern", "ipv4-as-ipv6", "bitmap", "benchmark-patterns", "geoip-db", "geo-city", "network-class", "whois",
+        "ioc-output",
+    ])]
+    mac: bool,
+
+    /// Read every line as a JSON object and take the IP from this field, e.g. `remote_ip`, or from a
+    /// nested one like `request.client.ip`. --pattern and --fixed-ips are ignored. Lines that are not
+    /// JSON objects are skipped with a warning, unless running with --pedantic.
+    #[clap(long, value_name = "FIELD")]
+    json_input: Option<String>,
+
+    /// Read the input as XML documents and count the text of every element at this path, e.g.
+    /// `log/entry/clientIP`, or `entry/clientIP` to match wherever an `entry` holds a `clientIP`.
+    /// Every document counts as one line, e.g. for --pedantic, --head or --dedup-window.
+    #[clap(long, value_parser = XmlPath::parse, conflicts_with_all = &[
+        "json-input", "merge-reports", "bitmap", "count-field", "weight-pattern", "distinct-ports", "annotate", "redact",
+        "max-line-length", "state-dir",
+    ], value_name = "PATH")]
+    xml_input: Option<XmlPath>,
+
+    /// Count arbitrary values instead of IPs, e.g. URL paths or user agents, taken from --field or
+    /// matched by --pattern and counted exactly as they appear. Implies --numeric.
+    #[clap(long, conflicts_with_all = &[
+        "mac", "ipv4-as-ipv6", "bitmap", "benchmark-patterns", "geoip-db", "geo-city", "network-class", "whois",
+        "ioc-output", "emit-rules",
+    ])]
+    count_field_values: bool,
+
+    /// With --count-field-values, count the value of this whitespace separated field, starts at 1.
+    /// Takes precedence over --pattern.
+    #[clap(long, requires = "count-field-values", conflicts_with = "fixed-ips", value_parser = clap::value_parser!(u64).range(1..), value_name = "N")]
+    field: Option<u64>,
+
+    /// Split the line into fields at this character instead of at whitespace, e.g. `,` for CSV
+    #[clap(long, requires = "field", value_name = "CHAR")]
+    field_delimiter: Option<char>,
+
+    /// Instead of counting, time the default pattern and --pattern against the first N lines of the
+    /// input and report matches and throughput of both
+    #[clap(long, requires = "pattern", value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    benchmark_patterns: Option<u64>,
+
+    /// Warn on stderr about lines the pattern takes unusually long to match, e.g. due to backtracking
     #[clap(long)]
-    fixed_ips: bool,
+    warn_on_regex_backtracking: bool,
+
+    /// Matching time in milliseconds above which --warn-on-regex-backtracking warns about a line
+    #[clap(long, default_value_t = 10, value_name = "MILLISECONDS")]
+    backtracking_threshold: u64,
+
+    /// Only process this fraction of lines (0 < RATE <= 1), chosen randomly, use {est_cnt} for estimated totals
+    #[clap(long, value_parser = parse_sample_rate, value_name = "RATE")]
+    sample: Option<f64>,
+
+    /// Seed for --sample, to make the sampling reproducible
+    #[clap(long, requires = "sample")]
+    seed: Option<u64>,
+
+    /// When reading stdin, print a report of everything seen so far every N seconds, separated by a `--` line
+    #[clap(long, conflicts_with = "files", value_name = "SECONDS")]
+    flush_interval: Option<u64>,
+
+    /// While reading stdin, e.g. from `tail -F`, answer `GET /metrics` on this address with the counts
+    /// so far in the Prometheus exposition format. They are updated at most every second, as lines
+    /// come in. Ctrl-C stops the server and exits. Requires the `serve` feature.
+    #[clap(long, conflicts_with = "files", value_name = "ADDR:PORT")]
+    serve: Option<String>,
+
+    /// Only expose the IPs with the N highest counts via --serve, so the number of series stays bounded
+    #[clap(long, requires = "serve", default_value_t = 100, value_name = "N")]
+    serve_top: usize,
+
+    /// Read previously generated reports (`{cnt} {ip}` or the default format) and sum up their counts
+    #[clap(long)]
+    merge_reports: bool,
+
+    /// Warn about and skip files that cannot be opened, e.g. for their permissions, and malformed lines
+    /// in --merge-reports input instead of bailing out. The exit status is 3 if a file was skipped.
+    #[clap(long)]
+    keep_going: bool,
+
+    /// Warn about a read error, e.g. from a flaky network mount, and skip the rest of that file instead
+    /// of bailing out. The report covers everything that could be read, and the exit status is 3.
+    #[clap(long)]
+    skip_errors: bool,
+
+    /// Ignore the first N lines of each file (or of stdin), e.g. to skip headers
+    #[clap(long, value_name = "N")]
+    skip_lines: Option<u64>,
+
+    /// Skip lines longer than this many bytes (without the line break), or bail out with --pedantic.
+    /// The rest of such a line is read in chunks and thrown away, so it never has to fit into memory.
+    #[clap(long, visible_alias = "max-line-bytes", value_name = "BYTES")]
+    max_line_length: Option<usize>,
+
+    /// Stop reading each file after this many lines
+    #[clap(long, value_name = "N")]
+    head: Option<u64>,
+
+    /// Only count the last N lines of each file (or of stdin), like `tail -n`. Plain files are read
+    /// backwards from the end to find them, gzipped files and stdin are read in full.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), conflicts_with_all = &[
+        "skip-lines", "state-dir", "flush-interval", "xml-input", "annotate", "redact",
+    ], value_name = "N")]
+    tail_lines: Option<u64>,
+
+    /// Count an IP at most once per N lines, e.g. for a persistent connection showing up on every
+    /// line. Every occurrence keeps the IP in the window, so it is only counted again after N lines
+    /// without it. The window starts over with each file.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), conflicts_with_all = &[
+        "bitmap", "merge-reports", "annotate", "redact",
+    ], value_name = "N")]
+    dedup_window: Option<u64>,
+
+    /// Stop reading altogether after this many lines, counted across all files
+    #[clap(long, value_name = "N")]
+    head_total: Option<u64>,
+
+    /// Only record which IPv4 addresses were seen, in a bitmap taking at most 512MB no matter how many
+    /// there are, and print them in ascending order. Other addresses are ignored.
+    #[clap(long, conflicts_with_all = &[
+        "format", "fields", "output-format", "threshold", "max-results", "separate", "merge-reports",
+        "count-field", "weight-pattern", "ipv4-as-ipv6", "load-state", "save-state", "baseline", "ioc-output",
+        "max-distinct", "flush-interval",
+    ])]
+    bitmap: bool,
+
+    /// Stop reading as soon as this many distinct IPs have been seen and print the partial report
+    #[clap(long, value_name = "N")]
+    max_unique: Option<usize>,
+
+    /// Abort with an error as soon as more than this many distinct IPs have been seen, to guard
+    /// against running out of memory on high cardinality input
+    #[clap(long, conflicts_with = "max-unique", value_name = "N")]
+    max_distinct: Option<usize>,
+
+    /// Preload the stats saved by a previous run with --save-state, new counts are added on top
+    #[clap(long, value_name = "FILE")]
+    load_state: Option<String>,
+
+    /// Save the stats to this file after processing, to be picked up again with --load-state
+    #[clap(long, value_name = "FILE")]
+    save_state: Option<String>,
+
+    /// Remember how far each file was read in this directory and only process what was appended
+    /// on the next run, starting over if a file was rotated or truncated in between. A last line
+    /// without a line break is left for the next run, and --skip-lines only applies to the start.
+    #[clap(long, requires = "files", value_name = "DIR")]
+    state_dir: Option<String>,
+
+    /// Forget the offsets saved in --state-dir and read all files from the start
+    #[clap(long, requires = "state-dir")]
+    reset_offsets: bool,
+
+    /// Compare against a previous report or state file, showing the previous count and the change,
+    /// sorted by the change. Makes {prev} and {delta} available in the format.
+    #[clap(long, value_name = "FILE")]
+    baseline: Option<String>,
+
+    /// Only show IPs not present in the baseline
+    #[clap(long, requires = "baseline")]
+    only_new: bool,
+
+    /// Only show IPs whose count went up by at least this much compared to the baseline
+    #[clap(long, requires = "baseline", allow_hyphen_values = true, value_name = "N")]
+    min_delta: Option<i64>,
 
-    /// Custom format to use for printing statistics, used once per IP, may contain {host}, {ip} and {cnt}
-    #[clap(long, short)]
+    /// Also write the reported IPs (after --threshold and --max-results) to this file as a bundle of
+    /// STIX 2.1 indicators
+    #[clap(long, conflicts_with = "separate", value_name = "FILE")]
+    ioc_output: Option<String>,
+
+    /// Also write just the reported IPs (after all filtering) to this file, one per line. With `-`
+    /// the list goes to stdout and the report to stderr.
+    #[clap(long, conflicts_with = "separate", value_name = "FILE")]
+    ip_list_output: Option<String>,
+
+    /// Confidence (0-100) to set on the indicators written with --ioc-output
+    #[clap(long, requires = "ioc-output", value_parser = clap::value_parser!(u8).range(0..=100), value_name = "0-100")]
+    ioc_confidence: Option<u8>,
+
+    /// Print a summary about the processed input to stderr
+    #[clap(long)]
+    summary: bool,
+
+    /// Add the sum of the counts printed so far and its share of the total to every row of the built-in
+    /// formats, as {cumulative} and {cumulative_percent}. Combine with --reverse to see how much traffic the
+    /// top IPs make up.
+    #[clap(long)]
+    running_total: bool,
+
+    /// Custom format to use for printing statistics, used once per IP, may contain {host}, {ip}, {cnt}, {percent}, {rank}, {cumulative} and {cumulative_percent} (running totals in printed order, also known as {running_total} and {running_pct}), {est_cnt} (with --sample), {prev}, {delta} (with --baseline), {file} (with --separate), {source} (with --label), {raw} (the first match before normalization), {class} (with --network-class), {country} (with --geoip-db), {city}, {region}, {postal}, {lat}, {lon} (with --geo-city), {abuse_contact} and {netname} (with --whois), {distinct_ports} (with --distinct-ports)
+    #[clap(long, short, env = "IPSTATS_FORMAT")]
     format: Option<String>,
+
+    /// Use one of the built-in format templates instead of --format: `detailed` is
+    /// "{rank}. {cnt} {host} ({ip}) {percent}%", `ips` is just "{ip}" and `abuse` is
+    /// "{cnt} {ip} {country} {netname} {abuse_contact}", implying --whois ({country} only with --geoip-db)
+    #[clap(long, value_enum, conflicts_with_all = &["format", "fields"], value_name = "PRESET")]
+    format_preset: Option<FormatPreset>,
+
+    /// Keep the default format when stdout is not a terminal, instead of switching to `{cnt}\t{ip}`
+    /// without hostname lookups
+    #[clap(long)]
+    no_auto_format: bool,
+
+    /// Comma separated list of variables to print as tab separated columns, instead of a custom format,
+    /// also selects the columns of `--output-format ssv`
+    #[clap(long, use_value_delimiter = true, conflicts_with = "format", value_name = "FIELDS")]
+    fields: Option<Vec<String>>,
+
+    /// How to render the statistics, `dot` produces a graph to be piped into GraphViz, with edges between
+    /// the IPs found on the same line when --key-range counts more than one per line, `jsonl-schema`
+    /// JSON Lines for data lake ingestion, `parquet` a Parquet file (requires the `parquet` feature),
+    /// `markdown` and `latex` a table for reports, `nginx-map` a `geo` block for blocking in nginx,
+    /// `haproxy-acl` ACL lines for HAProxy, `apache-deny` access rules for Apache httpd,
+    /// `hosts` /etc/hosts entries, `syslog-rfc5424` syslog messages for a SIEM
+    #[clap(long, value_enum, default_value_t = OutputFormat::Text)]
+    output_format: OutputFormat,
+
+    /// Character separating the columns of `--output-format ssv`
+    #[clap(long, default_value_t = ' ')]
+    delimiter: char,
+
+    /// Replaces the delimiter wherever it shows up inside a value with `--output-format ssv`
+    #[clap(long, default_value = "_")]
+    escape: String,
+
+    /// Start `--output-format ssv` with a row of column names
+    #[clap(long)]
+    header: bool,
+
+    /// Pretty print the objects of `--output-format jsonl-schema` for reading them, instead of one per
+    /// line for piping. The result is no longer JSON Lines, but still a stream of objects for e.g. jq.
+    #[clap(long)]
+    json_pretty: bool,
+
+    /// Compression to use for `--output-format parquet`
+    #[clap(long, value_enum, default_value_t = Compression::Snappy)]
+    parquet_compression: Compression,
+
+    /// Caption of the table produced by `--output-format latex`, may contain LaTeX markup
+    #[clap(long, value_name = "TEXT")]
+    latex_caption: Option<String>,
+
+    /// Label of the table produced by `--output-format latex`, for referencing it with \ref
+    #[clap(long, value_name = "TEXT")]
+    latex_label: Option<String>,
+
+    /// Variable set by `--output-format nginx-map`
+    #[clap(long, default_value = "blocked", value_name = "NAME")]
+    nginx_map_var: String,
+
+    /// Value the variable of `--output-format nginx-map` gets for the reported IPs, all others get 0
+    #[clap(long, default_value = "1", value_name = "VALUE")]
+    nginx_map_value: String,
+
+    /// TTL to put on the records of `--output-format zone`, otherwise the zone's default applies
+    #[clap(long, value_name = "SECONDS")]
+    zone_ttl: Option<u32>,
+
+    /// With `--output-format nmap`, `haproxy-acl` or `apache-deny`, collapse consecutive IPs into CIDR networks
+    #[clap(long, visible_alias = "cidr")]
+    nmap_cidr: bool,
+
+    /// Name of the ACL produced by `--output-format haproxy-acl`
+    #[clap(long, default_value = "blocked_ips", value_name = "NAME")]
+    haproxy_acl_name: String,
+
+    /// Also print an `http-request <ACTION> if <NAME>` line after the ACL, e.g. `deny`
+    #[clap(long, value_name = "ACTION")]
+    haproxy_action: Option<String>,
+
+    /// Hostname every IP is mapped to with `--output-format hosts`
+    #[clap(long, default_value = "blocked.local", value_name = "DOMAIN")]
+    hosts_domain: String,
+
+    /// Print firewall rules dropping all traffic from the reported IPs instead of a report. Implies
+    /// --numeric and honors --cidr. The rules are applied as is, so review them before running them.
+    #[clap(long, value_enum, conflicts_with_all = &["output-format", "fields", "format-preset", "mac"], value_name = "BACKEND")]
+    emit_rules: Option<Firewall>,
+
+    /// Set the `--emit-rules ipset` lines add IPv4 addresses to, IPv6 addresses go to the same name with a `6` appended
+    #[clap(long, default_value = "blocked_ips", value_name = "NAME")]
+    ipset_name: String,
+
+    /// Apache httpd version to write `--output-format apache-deny` for
+    #[clap(long, value_enum, default_value_t = ApacheVersion::V24)]
+    apache_version: ApacheVersion,
+
+    /// Print counts in a human readable form in text output, either abbreviated (si) or grouped (comma)
+    #[clap(long, value_enum, min_values = 0, require_equals = true, default_missing_value = "si")]
+    human: Option<HumanFormat>,
+
+    /// Send each record of the report to syslog instead of stdout, optionally using the given facility
+    #[clap(long, value_enum, min_values = 0, require_equals = true, default_missing_value = "user")]
+    syslog: Option<Facility>,
+
+    /// Send syslog messages via UDP to this `host[:port]` instead of the local /dev/log socket, or
+    /// instead of stdout with `--output-format syslog-rfc5424`
+    #[clap(long, visible_alias = "syslog-host", value_name = "HOST[:PORT]")]
+    syslog_server: Option<String>,
+
+    /// Severity to use for syslog messages
+    #[clap(long, value_enum, default_value_t = Severity::Info)]
+    syslog_severity: Severity,
+
+    /// Facility to use for `--output-format syslog-rfc5424`, --syslog takes its facility as value
+    #[clap(long, value_enum, default_value_t = Facility::Local0)]
+    syslog_facility: Facility,
+
+    /// MaxMind GeoIP2/GeoLite2 country database, makes {country} available in the format
+    #[clap(long, value_name = "FILE")]
+    geoip_db: Option<String>,
+
+    /// Add city level data from --geoip-city-db, makes {city}, {region}, {postal}, {lat} and {lon}
+    /// available in the format
+    #[clap(long, requires = "geoip-city-db")]
+    geo_city: bool,
+
+    /// MaxMind GeoIP2/GeoLite2 city database used by --geo-city
+    #[clap(long, requires = "geo-city", value_name = "FILE")]
+    geoip_city_db: Option<String>,
+
+    /// Only show IPs located in one of these countries, as comma separated ISO 3166-1 alpha-2 codes
+    #[clap(long, use_value_delimiter = true, requires = "geoip-db", value_name = "CODES")]
+    country_filter: Option<Vec<String>>,
+
+    /// Hide IPs located in one of these countries, as comma separated ISO 3166-1 alpha-2 codes
+    #[clap(long, use_value_delimiter = true, requires = "geoip-db", value_name = "CODES")]
+    country_exclude: Option<Vec<String>>,
+
+    /// Report the autonomous systems instead of the IPs, with the counts of all their IPs summed up,
+    /// e.g. `AS15169 Google LLC`. IPs missing from --asn-db are counted as `unknown`. Implies --numeric.
+    #[clap(long, requires = "asn-db", conflicts_with_all = &[
+        "count-field-values", "mac", "bitmap", "separate", "flush-interval", "always-show", "always-show-file",
+        "baseline", "distinct-ports", "geoip-db", "geo-city", "network-class", "whois", "ioc-output", "ip-list-output",
+        "emit-rules", "annotate", "redact",
+    ])]
+    by_asn: bool,
+
+    /// MaxMind GeoLite2/GeoIP2 ASN database used by --by-asn
+    #[clap(long, requires = "by-asn", value_name = "FILE")]
+    asn_db: Option<String>,
+
+    /// Classify every IP as loopback, private, link-local, multicast, documentation or public,
+    /// makes {class} available in the format
+    #[clap(long)]
+    network_class: bool,
+
+    /// Only show IPs of this class
+    #[clap(long, value_enum, requires = "network-class", value_name = "CLASS")]
+    class_filter: Option<NetworkClass>,
+
+    /// Query whois for every displayed IP, makes {abuse_contact} and {netname} available in the format
+    #[clap(long)]
+    whois: bool,
+}
+
+/// Returns the names of all variables used in a format string, e.g. `host` for `{host:>20}`,
+/// skipping escaped braces like `{{`. Braces are matched the same way strfmt does it.
+fn format_variables(format: &str) -> Result<Vec<&str>> {
+    let mut variables = Vec::new();
+    let mut chars = format.char_indices().peekable();
+    let mut start = None;
+    while let Some((index, c)) = chars.next() {
+        if let Some(begin) = start {
+            match c {
+                '{' => bail!("Nested '{{' in format string {format:?}"),
+                '}' => {
+                    let name: &str = &format[begin..index];
+                    variables.push(name.split(':').next().unwrap_or_default().trim());
+                    start = None;
+                }
+                _ => {}
+            }
+        } else if (c == '{' || c == '}') && chars.peek().map(|(_, next)| *next) == Some(c) {
+            // Escaped literal brace
+            chars.next();
+        } else if c == '{' {
+            start = Some(index + 1);
+        } else if c == '}' {
+            bail!("Unmatched '}}' in format string {format:?}, use '}}}}' for a literal brace")
+        }
+    }
+    if start.is_some() {
+        bail!("Unclosed '{{' in format string {format:?}, use '{{{{' for a literal brace")
+    }
+    Ok(variables)
+}
+
+/// The name a variable is listed under in `FORMAT_VARIABLES`, if it is an alias
+fn canonical_variable(var: &str) -> &str {
+    VARIABLE_ALIASES.iter().find(|(alias, _)| *alias == var).map_or(var, |(_, name)| name)
+}
+
+/// The flag that has to be passed for a variable to be filled in, if any
+fn variable_requirement(var: &str) -> Option<&'static str> {
+    match var {
+        "class" => Some("--network-class"),
+        "country" => Some("--geoip-db"),
+        "city" | "region" | "postal" | "lat" | "lon" => Some("--geoip-city-db"),
+        "abuse_contact" | "netname" => Some("--whois"),
+        "prev" | "delta" => Some("--baseline"),
+        "file" => Some("--separate"),
+        "source" => Some("--label"),
+        "distinct_ports" => Some("--distinct-ports"),
+        _ => None,
+    }
+}
+
+/// The variables that will be filled in for every record, given the other options
+fn available_variables(args: &CountArgs) -> Vec<&'static str> {
+    FORMAT_VARIABLES.iter().copied().filter(|var| match *var {
+        "host" => !args.numeric,
+        "class" => args.network_class,
+        "country" => args.geoip_db.is_some(),
+        "city" | "region" | "postal" | "lat" | "lon" => args.geoip_city_db.is_some(),
+        "abuse_contact" | "netname" => args.whois,
+        "prev" | "delta" => args.baseline.is_some(),
+        "file" => args.separate,
+        "source" => !args.label.is_empty(),
+        "distinct_ports" => args.distinct_ports.is_some(),
+        _ => true,
+    }).collect()
+}
+
+/// Checks every placeholder of a format string against `available`, so a typo fails right away
+/// instead of after all the input has been read
+fn check_format(format: &str, available: &[&str]) -> Result<()> {
+    for var in format_variables(format)? {
+        let var = canonical_variable(var);
+        if available.contains(&var) {
+            continue;
+        }
+        match variable_requirement(var) {
+            Some(flag) if FORMAT_VARIABLES.contains(&var) => {
+                bail!("The format string uses {{{var}}}, which is only available with {flag}")
+            }
+            _ => bail!("Unknown variable {{{var}}} in format string, valid variables are: {}", available.join(", ")),
+        }
+    }
+    Ok(())
+}
+
+/// When and how the output was generated, with the arguments quoted for a shell
+fn generated_by() -> String {
+    let invocation: Vec<_> = std::env::args().map(|arg| {
+        if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || "'\"$`\\*?;&|<>(){}".contains(c)) {
+            format!("'{}'", arg.replace('\'', "'\\''"))
+        } else {
+            arg
+        }
+    }).collect();
+    // A line break in an argument would end the comment
+    format!("Generated by ipstats at {}\n{}", stix::timestamp(SystemTime::now()), invocation.join(" ").replace('\n', " "))
+}
+
+fn uppercase_all(values: Vec<String>) -> Vec<String> {
+    values.iter().map(|value| value.to_uppercase()).collect()
+}
+
+/// Sends the diagnostics to stderr, at the level of -q and -v unless RUST_LOG says otherwise. With
+/// `json` warnings are printed as JSON objects, with the file and line they are about, if any.
+fn init_logging(quiet: u8, verbose: u8, json: bool) {
+    let level = match i16::from(verbose) - i16::from(quiet) {
+        ..=-2 => LevelFilter::Off,
+        -1 => LevelFilter::Error,
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        _ => LevelFilter::Debug,
+    };
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .format(move |buf, record| match record.level() {
+            Level::Warn if json => {
+                let value = |key| record.key_values().get(log::kv::Key::from_str(key));
+                let warning = serde_json::json!({
+                    "kind": "warning",
+                    "message": record.args().to_string(),
+                    "file": value("file").and_then(|file| file.to_borrowed_str().map(String::from)),
+                    "line": value("line").and_then(|line| line.to_u64()),
+                });
+                writeln!(buf, "{warning}")
+            }
+            Level::Warn => writeln!(buf, "Warning: {}", record.args()),
+            _ => writeln!(buf, "{}", record.args()),
+        })
+        .init();
 }
 
 fn main() -> Result<()> {
-    let args = Args::parse();
-    let pattern = Regex::new(
-        &args.pattern.unwrap_or(
-            String::from(r"((::ffff:)(?:[0-9]{1,3}\.){3}[0-9]{1,3})|((([0-9a-f]{1,4}:){7}([0-9a-f]{1,4}|:))|(([0-9a-f]{1,4}:){6}(:[0-9a-f]{1,4}|((25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])(\.(25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])){3})|:))|(([0-9a-f]{1,4}:){5}(((:[0-9a-f]{1,4}){1,2})|:((25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])(\.(25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])){3})|:))|(([0-9a-f]{1,4}:){4}(((:[0-9a-f]{1,4}){1,3})|((:[0-9a-f]{1,4})?:((25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])(\.(25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])){3}))|:))|(([0-9a-f]{1,4}:){3}(((:[0-9a-f]{1,4}){1,4})|((:[0-9a-f]{1,4}){0,2}:((25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])(\.(25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])){3}))|:))|(([0-9a-f]{1,4}:){2}(((:[0-9a-f]{1,4}){1,5})|((:[0-9a-f]{1,4}){0,3}:((25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])(\.(25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])){3}))|:))|(([0-9a-f]{1,4}:){1}(((:[0-9a-f]{1,4}){1,6})|((:[0-9a-f]{1,4}){0,4}:((25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])(\.(25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])){3}))|:))|(:(((:[0-9a-f]{1,4}){1,7})|((:[0-9a-f]{1,4}){0,5}:((25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])(\.(25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])){3}))|:)))(%.+)?"),
-        )
-    ).context("Could not compile regex")?;
-
-    let format = if let Some(format) = args.format {
-        // Since formatting may use {host} with more formatting prarameters, our check should probably be a bit smarter
-        if args.numeric && format.contains("{host}") {
-            bail!("You cannot use {{host}} in the format string and pass --numeric at the same time")
+    let mut args = Args::parse();
+    let json = args.errors == ErrorFormat::Json;
+    init_logging(args.quiet, args.verbose, json);
+    // -qq hides the summary as well
+    if args.quiet >= 2 {
+        args.count.summary = false;
+        if let Some(Command::Count(count)) = &mut args.command {
+            count.summary = false;
+        }
+    }
+    match run(args) {
+        Err(err) if json => {
+            error::print_json(&err.into());
+            std::process::exit(1);
+        }
+        result => result,
+    }
+}
+
+fn run(args: Args) -> Result<()> {
+    match args.command {
+        Some(Command::Completions { shell }) => {
+            clap_complete::generate(shell, &mut Args::command(), "ipstats", &mut io::stdout());
+            Ok(())
+        }
+        Some(Command::Man) => {
+            clap_mangen::Man::new(Args::command()).render(&mut io::stdout()).context("Failed writing man page")
+        }
+        Some(Command::Count(count)) => run_count(*count),
+        Some(Command::Resolve(args)) => run_resolve(args),
+        Some(Command::Filter(args)) => run_filter(args),
+        Some(Command::Diff(args)) => run_diff(args),
+        None => run_count(args.count),
+    }
+}
+
+fn compile_pattern(input: &InputArgs) -> Result<Regex> {
+    Regex::new(input.pattern.as_deref().unwrap_or(DEFAULT_PATTERN)).context("Could not compile regex")
+}
+
+fn run_resolve(mut args: ResolveArgs) -> Result<()> {
+    args.input.expand_dirs(false)?;
+    let opts = args.input.process_options(false)?;
+    let hosts = args.dns.host_cache();
+    let mut seen = HashSet::new();
+    let mut out = io::stdout().lock();
+    for_each_line(&args.input.files, |line| {
+        if let Some(m) = opts.extract(line) {
+            let ip = normalize_key(m, &opts);
+            if !seen.contains(&ip) {
+                writeln!(out, "{ip} {}", hosts.lookup(&ip)?)?;
+                seen.insert(ip);
+            }
+        }
+        Ok(())
+    })?;
+    out.flush().context("Failed flushing output")
+}
+
+fn run_filter(mut args: FilterArgs) -> Result<()> {
+    args.input.expand_dirs(false)?;
+    let opts = args.input.process_options(false)?;
+
+    // stdin can only be read once, so it is buffered when a counting pass comes first
+    let mut buffered = None;
+    let wanted = if let Some(path) = &args.ips_from {
+        Some(load_ip_list(path, &opts)?)
+    } else if args.top.is_some() || args.threshold.is_some() {
+        let mut stats = Stats::default();
+        let mut lines = Vec::new();
+        for_each_line(&args.input.files, |line| {
+            if let Some(m) = opts.extract(line) {
+                *stats.entry(normalize_key(m, &opts)).or_insert(0) += 1;
+            }
+            if args.input.files.is_empty() {
+                lines.push(line.to_string());
+            }
+            Ok(())
+        })?;
+        if args.input.files.is_empty() {
+            buffered = Some(lines);
+        }
+        // Picked just like the report would show them, heavy hitters first
+        let opts = ReportOptions {
+            max_results: args.top.map(|top| top as usize),
+            threshold: args.threshold,
+            reverse: true,
+            ..ReportOptions::new("{ip}")
+        };
+        Some(select(&stats, &opts).into_iter().map(|(ip, _)| ip.clone()).collect())
+    } else {
+        None
+    };
+
+    let mut out = io::stdout().lock();
+    let mut pass = |line: &str| -> Result<(), Error> {
+        let keep = match (opts.extract(line), &wanted) {
+            (Some(m), Some(wanted)) => wanted.contains(&normalize_key(m, &opts)),
+            (m, None) => m.is_some(),
+            (None, _) => false,
+        };
+        if keep {
+            out.write_all(line.as_bytes())?;
+        }
+        Ok(())
+    };
+    match buffered {
+        Some(lines) => lines.iter().try_for_each(|line| pass(line))?,
+        None => for_each_line(&args.input.files, pass)?,
+    }
+    out.flush().context("Failed flushing output")
+}
+
+fn run_diff(args: DiffArgs) -> Result<()> {
+    let old = load_baseline(&args.old)?;
+    let new = load_baseline(&args.new)?;
+
+    // Unchanged IPs are left out, the biggest changes in either direction end up at the bottom
+    let mut changes: Vec<(&String, i64)> = old.keys()
+        .chain(new.keys().filter(|ip| !old.contains_key(*ip)))
+        .map(|ip| (ip, new.get(ip).copied().unwrap_or(0) as i64 - old.get(ip).copied().unwrap_or(0) as i64))
+        .filter(|(_, delta)| *delta != 0)
+        .collect();
+    changes.sort_by_key(|(ip, delta)| (delta.abs(), *ip));
+    if let Some(max) = args.max_results {
+        changes.drain(..changes.len().saturating_sub(max));
+    }
+
+    let mut out = io::stdout().lock();
+    for (ip, delta) in changes {
+        let status = match (old.contains_key(ip), new.contains_key(ip)) {
+            (false, _) => "new",
+            (_, false) => "gone",
+            _ if delta > 0 => "up",
+            _ => "down",
+        };
+        let vars = HashMap::from([
+            ("ip".to_string(), ip.clone()),
+            ("old".to_string(), old.get(ip).copied().unwrap_or(0).to_string()),
+            ("new".to_string(), new.get(ip).copied().unwrap_or(0).to_string()),
+            ("delta".to_string(), format!("{delta:+}")),
+            ("status".to_string(), status.to_string()),
+        ]);
+        writeln!(out, "{}", strfmt::strfmt(&args.format, &vars).context("Error while formatting record")?)?;
+    }
+    out.flush().context("Failed flushing output")
+}
+
+/// Echoes every line with the hostname of its IP inserted right after the IP, instead of counting
+fn annotate(files: &[String], opts: &ProcessOptions, hosts: &HostCache) -> Result<()> {
+    let mut out = io::stdout().lock();
+    for_each_line(files, |line| {
+        let Some(range) = opts.locate(line) else {
+            return Ok(out.write_all(line.as_bytes())?);
+        };
+        let key = normalize_key(&line[range.clone()], opts);
+        // A failed lookup is no reason to stop following a log
+        let host = hosts.lookup(&key).unwrap_or_else(|err| {
+            debug!("{err:#}");
+            key.clone()
+        });
+        write!(out, "{} [host={host}]{}", &line[..range.end], &line[range.end..])?;
+        Ok(())
+    })?;
+    out.flush().context("Failed flushing output")
+}
+
+/// Echoes every line with all IPs replaced, everything else is passed through untouched
+fn redact(files: &[String], opts: &ProcessOptions, redactor: &Redactor) -> Result<()> {
+    // The default pattern misses plain IPv4 and its zone ids would take the rest of the line with them
+    let pattern = if opts.fixed_ips || opts.pattern.as_str() != DEFAULT_PATTERN {
+        opts.pattern.clone()
+    } else {
+        redact::pattern()?
+    };
+    let mut out = io::stdout().lock();
+    for_each_line(files, |line| {
+        if opts.fixed_ips {
+            // Keep the surrounding whitespace, including the line break
+            let range = opts.locate(line).unwrap_or_default();
+            let ip = &line[range.clone()];
+            write!(out, "{}{}{}", &line[..range.start], redactor.redact(ip), &line[range.end..])?;
+        } else {
+            out.write_all(redactor.redact_all(&pattern, line).as_bytes())?;
         }
+        Ok(())
+    })?;
+    out.flush().context("Failed flushing output")
+}
+
+/// Reads the IPs to filter for, one per line or from a previous report
+fn load_ip_list(path: &str, opts: &ProcessOptions) -> Result<HashSet<String>> {
+    let data = std::fs::read_to_string(path).with_context(|| format!("Could not read IP list: {path}"))?;
+    let ips = data.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| parse_report_line(line).map_or(line, |(_, ip)| ip))
+        .map(|ip| normalize_key(ip, opts))
+        .collect();
+    Ok(ips)
+}
+
+fn run_count(mut args: CountArgs) -> Result<()> {
+    // The config file only fills in what was neither passed nor set in the environment
+    let config = if args.no_config { Config::default() } else { Config::load()? };
+    args.input.pattern = args.input.pattern.or(config.pattern);
+    if args.fields.is_none() && args.format_preset.is_none() {
+        args.format = args.format.or(config.format);
+    }
+    args.numeric = args.numeric_arg.or(config.numeric).unwrap_or(false);
+    args.threshold = args.threshold.or(config.threshold);
+    args.max_results = args.max_results.or(config.max_results);
+    args.dns.skip_v4_rdns = args.dns.skip_v4_rdns.or(config.skip_v4_rdns);
+    args.dns.skip_v6_rdns = args.dns.skip_v6_rdns.or(config.skip_v6_rdns);
+    args.dns.dns_rate = args.dns.dns_rate.or(config.dns_rate);
+
+    args.input.expand_dirs(args.pedantic)?;
+
+    // Rules need bare IPs, there is nothing to resolve, and a format from the environment has no say
+    if args.emit_rules.is_some() {
+        args.numeric = true;
+        args.format = None;
+    }
+
+    // Neither MACs nor arbitrary values have hostnames, the format and fields are checked against that below
+    if args.count_field_values {
+        if args.field.is_none() && args.input.pattern.is_none() && !args.input.fixed_ips {
+            bail!("--count-field-values needs --field, --pattern or --fixed-ips to know what to count")
+        }
+        args.numeric = true;
+    }
+    if args.by_asn {
+        args.numeric = true;
+    }
+    if args.mac {
+        args.numeric = true;
+    }
+
+    if let Some(lines) = args.benchmark_patterns {
+        let default = Regex::new(DEFAULT_PATTERN).context("Could not compile regex")?;
+        return benchmark_patterns(&args.input.files, lines as usize, &default, &compile_pattern(&args.input)?);
+    }
+
+    if args.annotate {
+        let opts = args.input.process_options(args.mac)?;
+        return annotate(&args.input.files, &opts, &args.dns.host_cache());
+    }
+
+    if args.redact {
+        let redactor = Redactor::new(args.redact_style.into(), &args.mask, args.hash_key.take())?;
+        return redact(&args.input.files, &args.input.process_options(args.mac)?, &redactor);
+    }
+
+    for field in args.fields.iter_mut().flatten() {
+        *field = canonical_variable(field).to_string();
+    }
+    if let Some(fields) = &args.fields {
+        if let Some(field) = fields.iter().find(|field| !FORMAT_VARIABLES.contains(&field.as_str())) {
+            bail!("Unknown field {field:?}, valid fields are: {}", FORMAT_VARIABLES.join(", "))
+        }
+        if let Some(field) = fields.iter().find(|field| args.numeric && DNS_VARIABLES.contains(&field.as_str())) {
+            bail!("You cannot use the {field} field and pass --numeric at the same time")
+        }
+        let available = available_variables(&args);
+        for field in fields.iter().filter(|field| !available.contains(&field.as_str())) {
+            if let Some(flag) = variable_requirement(field) {
+                bail!("The {field} field is only available with {flag}")
+            }
+        }
+    }
+
+    // Fail before reading any input, instead of when printing the report
+    if args.output_format == OutputFormat::Parquet && !cfg!(feature = "parquet") {
+        bail!("Parquet output is not available, ipstats was built without the `parquet` feature")
+    }
+
+    if args.syslog.is_some() && args.output_format == OutputFormat::SyslogRfc5424 {
+        bail!("--syslog sends plain syslog messages, use --syslog-server to send the RFC 5424 ones instead")
+    }
+    if args.syslog_server.is_some() && args.syslog.is_none() && args.output_format != OutputFormat::SyslogRfc5424 {
+        bail!("--syslog-server only works with --syslog or --output-format syslog-rfc5424")
+    }
+
+    if args.hosts_domain.is_empty() || args.hosts_domain.contains(|c: char| c.is_whitespace() || c == '#') {
+        bail!("--hosts-domain must be a single hostname, got {:?}", args.hosts_domain)
+    }
+
+    // Labels map to files positionally, check that before reading anything
+    if args.label.len() > 1 {
+        if !args.separate {
+            bail!("Multiple --label values only work with --separate, use a single one for the merged report")
+        }
+        if args.label.len() != args.input.files.len() {
+            bail!("Got {} --label values for {} files, pass exactly one per file", args.label.len(), args.input.files.len())
+        }
+    }
+
+    // In a pipeline the lookups only slow things down, and tabs are easier to parse than parentheses
+    let auto_format = !args.no_auto_format
+        && !args.numeric
+        && args.format.is_none()
+        && args.fields.is_none()
+        && args.format_preset.is_none()
+        && args.output_format == OutputFormat::Text
+        && args.syslog.is_none()
+        && !io::stdout().is_terminal();
+    if auto_format {
+        warn!("stdout is not a terminal, printing tab separated IPs without hostnames, pass --no-auto-format to keep the hostnames");
+        args.numeric = true;
+    }
+
+    // Columns for output formats not driven by a format string, mirroring the default format
+    let fields = args.fields.clone().unwrap_or_else(|| {
+        let mut fields = Vec::new();
+        if !args.label.is_empty() {
+            fields.push("source");
+        }
+        if args.separate {
+            fields.push("file");
+        }
+        fields.push("cnt");
+        if args.baseline.is_some() {
+            fields.extend(["prev", "delta"]);
+        }
+        if args.running_total {
+            fields.extend(["cumulative", "cumulative_percent"]);
+        }
+        if !args.numeric {
+            fields.push("host");
+        }
+        fields.push("ip");
+        fields.into_iter().map(String::from).collect()
+    });
+
+    let available = available_variables(&args);
+    // --running-total adds its columns to the built-in formats, custom ones place them where they like
+    let running_total = args.running_total && args.format.is_none() && args.fields.is_none();
+    let format = if let Some(fields) = &args.fields {
+        fields.iter().map(|field| format!("{{{field}}}")).collect::<Vec<_>>().join("\t")
+    } else if let Some(format) = args.format {
+        let variables = format_variables(&format)?;
+        if let Some(var) = variables.iter().find(|var| args.numeric && DNS_VARIABLES.contains(var)) {
+            bail!("You cannot use {{{var}}} in the format string and pass --numeric at the same time")
+        }
+        check_format(&format, &available)?;
         format
+    } else if let Some(preset) = args.format_preset {
+        preset.template(args.numeric, args.geoip_db.is_some())
+    } else if args.baseline.is_some() && auto_format {
+        String::from("{cnt}\t{prev}\t{delta}\t{ip}")
+    } else if auto_format {
+        String::from("{cnt}\t{ip}")
+    } else if args.baseline.is_some() && args.numeric {
+        String::from("{cnt} {prev} {delta} {ip}")
+    } else if args.baseline.is_some() {
+        String::from("{cnt} {prev} {delta} {host} ({ip})")
     } else if args.numeric {
         String::from("{cnt} {ip}")
     } else {
         String::from("{cnt} {host} ({ip})")
     };
+    let format = if !running_total {
+        format
+    } else if auto_format {
+        format!("{format}\t{{cumulative}}\t{{cumulative_percent}}")
+    } else {
+        format!("{format} {{cumulative}} {{cumulative_percent}}%")
+    };
+
+    // Remembering the raw matches costs memory, so only do it when they are actually printed
+    let variables = format_variables(&format)?;
+    let wants_raw = variables.contains(&"raw");
+
+    // Resolving is by far the slowest part, don't do it just to throw the hostnames away.
+    // Everything but text and ssv output, and the STIX export, shows the hostname whenever there is one.
+    let uses_host = match args.output_format {
+        OutputFormat::Text => variables.iter().any(|var| DNS_VARIABLES.contains(var)),
+        OutputFormat::Ssv => fields.iter().any(|field| DNS_VARIABLES.contains(&field.as_str())),
+        _ => true,
+    };
+    if !args.numeric && !uses_host && args.ioc_output.is_none() && args.tiebreak != Tiebreak::Host {
+        info!("Hostnames are not printed, skipping the lookups");
+        args.numeric = true;
+    }
+
+    let mut opts = ReportOptions {
+        max_results: args.max_results,
+        // Filled in below, normalized like the counted IPs
+        always_show: HashSet::new(),
+        reverse: args.reverse,
+        tiebreak: args.tiebreak.into(),
+        numeric: args.numeric,
+        threshold: args.threshold,
+        format,
+        output_format: args.output_format.into(),
+        human: args.human.map(Into::into),
+        geoip: args.geoip_db.as_deref().map(GeoIp::open).transpose()?,
+        geo_city: args.geoip_city_db.as_deref().map(GeoIp::open).transpose()?,
+        country_filter: args.country_filter.map(uppercase_all),
+        country_exclude: args.country_exclude.map(uppercase_all),
+        network_class: args.network_class,
+        class_filter: args.class_filter.map(Into::into),
+        nmap_cidr: args.nmap_cidr,
+        whois: if args.whois || args.format_preset == Some(FormatPreset::Abuse) { Some(Whois::new()?) } else { None },
+        sample_rate: args.sample,
+        baseline: args.baseline.as_deref().map(load_baseline).transpose()?,
+        only_new: args.only_new,
+        min_delta: args.min_delta,
+        running_total: args.running_total,
+        fields,
+        delimiter: args.delimiter,
+        escape: args.escape,
+        header: args.header,
+        json_pretty: args.json_pretty,
+        file: None,
+        source: args.label.first().cloned(),
+        // Filled in after counting, snapshots printed with --flush-interval show the key and 0 for now
+        raw: wants_raw.then(RawMatches::new),
+        ports: args.distinct_ports.is_some().then(PortSets::new),
+        sort_by_ports: args.sort_by_ports,
+        pairs: (args.output_format == OutputFormat::Dot).then(PairCounts::new),
+        zone_ttl: args.zone_ttl,
+        latex_caption: args.latex_caption,
+        latex_label: args.latex_label,
+        nginx_map_var: args.nginx_map_var,
+        nginx_map_value: args.nginx_map_value,
+        haproxy_acl_name: args.haproxy_acl_name,
+        haproxy_action: args.haproxy_action,
+        apache_version: args.apache_version.into(),
+        apache_comment: Some(generated_by()),
+        hosts_domain: args.hosts_domain,
+        emit_rules: args.emit_rules.map(Into::into),
+        ipset_name: args.ipset_name,
+        syslog_facility: args.syslog_facility.into(),
+        syslog_severity: args.syslog_severity.into(),
+        parquet_compression: args.parquet_compression.into(),
+        hosts: args.dns.host_cache(),
+    };
+
+    // The default pattern is huge, so only spell it out with -vv
+    match args.input.pattern.as_deref() {
+        Some(pattern) => info!("Using pattern: {pattern}"),
+        None if log::log_enabled!(Level::Debug) => debug!("Using default pattern: {DEFAULT_PATTERN}"),
+        None => info!("Using default pattern"),
+    }
+    info!("Using format: {:?}", opts.format);
+
+    let rfc5424_server = args.syslog_server.as_deref().filter(|_| args.output_format == OutputFormat::SyslogRfc5424);
+    let mut out: Box<dyn Write> = if let Some(facility) = args.syslog {
+        Box::new(SyslogWriter::new(args.syslog_server.as_deref(), facility.into(), args.syslog_severity.into()))
+    } else if let Some(server) = rfc5424_server {
+        Box::new(SyslogWriter::raw(server))
+    } else if args.ip_list_output.as_deref() == Some("-") {
+        // stdout is taken by the IP list
+        Box::new(io::stderr().lock())
+    } else {
+        Box::new(io::stdout().lock())
+    };
+
+    let process_opts = ProcessOptions {
+        key: args.key_range.map_or(args.input.key, |(start, _)| start),
+        key_end: args.key_range.map(|(_, end)| end),
+        key_fallback: args.key_fallback.into(),
+        pedantic: args.pedantic,
+        // --serve refreshes its metrics on every flush
+        flush_interval: args.flush_interval.or(args.serve.as_ref().map(|_| 1)).map(Duration::from_secs),
+        head: args.head,
+        head_total: args.head_total,
+        sample: args.sample.map(|rate| Sampler::new(rate, args.seed)),
+        verbatim: args.count_field_values,
+        field: args.field.map(|field| field as usize),
+        field_delimiter: args.field_delimiter,
+        prepend_label: args.prepend_label,
+        json_field: args.json_input,
+        xml_path: args.xml_input,
+        max_unique: args.max_unique,
+        max_distinct: args.max_distinct,
+        replacements: args.replace_pattern,
+        first_n_chars: args.first_n_chars.map(|n| n as usize),
+        trim_suffix: args.trim_suffix_regex,
+        count_field: args.count_field.map(|field| field as usize),
+        weight_pattern: args.weight_pattern,
+        weight_key: args.weight_key as usize,
+        port_pattern: args.distinct_ports,
+        skip_lines: args.skip_lines,
+        tail_lines: args.tail_lines,
+        dedup_window: args.dedup_window,
+        max_line_length: args.max_line_length,
+        merge_reports: args.merge_reports,
+        keep_going: args.keep_going,
+        skip_errors: args.skip_errors,
+        bitmap: args.bitmap.then(Bitmap::new),
+        slow_match: args.warn_on_regex_backtracking.then(|| Duration::from_millis(args.backtracking_threshold)),
+        ..args.input.process_options(args.mac)?
+    };
 
-    let mut stats = Stats::new();
+    opts.always_show = args.always_show.iter().map(|ip| normalize_key(ip, &process_opts)).collect();
+    if let Some(path) = &args.always_show_file {
+        opts.always_show.extend(load_ip_list(path, &process_opts)?);
+    }
+
+    // Opened before reading the input, so a wrong path does not cost the whole run
+    let asn_db = args.asn_db.as_deref().map(GeoIp::open).transpose()?;
+
+    let state = match &args.load_state {
+        Some(path) => State::load(path)?,
+        None => State::default(),
+    };
+    let mut stats = state.stats;
+    let mut summary = Summary::default();
+    let mut details = Details { raw: opts.raw.clone(), ports: opts.ports.clone(), pairs: opts.pairs.clone() };
+    let started = Instant::now();
 
-    if args.files.is_empty() {
+    if args.input.files.is_empty() {
+        let metrics = args.serve.as_deref().map(|addr| MetricsServer::start(addr, args.serve_top)).transpose()?;
+        if let Some(metrics) = &metrics {
+            stop_on_interrupt(metrics)?;
+        }
+        let mut flush = |stats: &Stats| -> Result<(), Error> {
+            if let Some(metrics) = &metrics {
+                metrics.update(stats);
+            }
+            if args.flush_interval.is_none() {
+                return Ok(());
+            }
+            print_stats(&mut out, stats, &opts).context("Failed printing stats")?;
+            writeln!(out, "--")?;
+            out.flush().context("Failed flushing output")?;
+            Ok(())
+        };
         process_file(
             &mut io::stdin(),
+            "stdin",
             &mut stats,
-            &pattern,
-            args.key,
-            args.pedantic,
-            args.fixed_ips,
+            &process_opts,
+            &mut summary,
+            Some(&mut details),
+            Some(&mut flush),
         ).context("Failed processing stdin")?;
-
-        print_stats(
-            stats,
-            args.max_results,
-            args.numeric,
-            args.threshold,
-            &format,
-        ).context("Failed printing stats")?;
+        // The input is over, so are the metrics
+        if let Some(metrics) = metrics {
+            metrics.shutdown();
+        }
     } else {
-        for path in args.files {
-            let mut file = File::open(&path).context(format!("Could not open file: {path}"))?;
-            process_file(
-                &mut file,
-                &mut stats,
-                &pattern,
-                args.key,
-                args.pedantic,
-                args.fixed_ips,
-            ).context(format!("Failed processing file: {path}"))?;
-
-        }
-
-        print_stats(
-            stats,
-            args.max_results,
-            args.numeric,
-            args.threshold,
-            &format,
-        ).context("Failed printing stats")?;
+        let mut files = args.input.files;
+        let mut labels = args.label;
+        if args.reverse_input {
+            files.reverse();
+            labels.reverse();
+        }
+        let mut offsets = args.state_dir.as_deref().map(|dir| Offsets::load(dir, args.reset_offsets)).transpose()?;
+        if let Some(dir) = &args.output_dir {
+            std::fs::create_dir_all(dir).with_context(|| format!("Could not create output directory: {dir}"))?;
+        }
+        if args.separate {
+            for (i, path) in files.iter().enumerate() {
+                let mut stats = Stats::default();
+                let mut details = details.clone();
+                process_files(
+                    std::slice::from_ref(path),
+                    &mut stats,
+                    &process_opts,
+                    &mut summary,
+                    offsets.as_mut(),
+                    Some(&mut details),
+                )?;
+                opts.file = Some(path.clone());
+                if labels.len() > 1 {
+                    opts.source = Some(labels[i].clone());
+                }
+                opts.raw = details.raw;
+                opts.ports = details.ports;
+                opts.pairs = details.pairs;
+                if let Some(dir) = &args.output_dir {
+                    let target = report_path(dir, path, labels.get(i).filter(|_| labels.len() > 1).map(String::as_str), &opts);
+                    let file = File::create(&target)
+                        .with_context(|| format!("Could not create report file: {}", target.display()))?;
+                    report(&mut io::BufWriter::new(file), &stats, &opts)
+                        .with_context(|| format!("Could not write report file: {}", target.display()))?;
+                    info!("Wrote the report of {path} to {}", target.display());
+                    continue;
+                }
+                if opts.output_format == ipstats::OutputFormat::Text {
+                    writeln!(out, "== {path} ==")?;
+                } else if opts.output_format == ipstats::OutputFormat::Markdown {
+                    // Tables directly following each other would run into one
+                    writeln!(out, "\n### {path}\n")?;
+                }
+                report(&mut out, &stats, &opts)?;
+            }
+        } else {
+            process_files(&files, &mut stats, &process_opts, &mut summary, offsets.as_mut(), Some(&mut details))?;
+        }
+        if let Some(offsets) = &offsets {
+            offsets.save()?;
+        }
+    }
+
+    // With --separate this includes printing the reports of all but the last file
+    summary.elapsed = started.elapsed();
+
+    if let Some(bitmap) = &process_opts.bitmap {
+        bitmap.each(|ip| writeln!(out, "{ip}"))?;
+        out.flush().context("Failed flushing output")?;
+    } else if !args.separate {
+        // Save before printing, so slow or failing lookups cannot cost us the counts
+        if let Some(path) = &args.save_state {
+            State::save(path, state.lines + summary.lines, &stats)?;
+        }
+        opts.raw = details.raw;
+        opts.ports = details.ports;
+        opts.pairs = details.pairs;
+        let networks = asn_db.as_ref().map(|asn_db| aggregate_by_asn(&stats, asn_db));
+        report(&mut out, networks.as_ref().unwrap_or(&stats), &opts)?;
+        if args.ioc_output.is_some() || args.ip_list_output.is_some() {
+            let selected = select(&stats, &opts);
+            if let Some(path) = &args.ioc_output {
+                write_iocs(path, &selected, &opts, args.ioc_confidence)?;
+            }
+            if let Some(path) = &args.ip_list_output {
+                write_ip_list(path, &selected)?;
+            }
+        }
+    }
+
+    if !opts.numeric {
+        info!("DNS cache: {} hits, {} misses", opts.hosts.hits.get(), opts.hosts.misses.get());
+    }
+
+    if args.summary {
+        eprint!("{summary}");
+    } else if summary.truncated_by == Some(Limit::MaxUnique) {
+        warn!("Input was truncated by --max-unique after {} distinct IPs", process_opts.unique(&stats));
+    }
+
+    // The report is out and every skipped file got a warning, but scripts should notice as well
+    if !summary.incomplete.is_empty() || !summary.skipped.is_empty() {
+        std::process::exit(EXIT_INCOMPLETE);
     }
     Ok(())
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_variables_ignore_the_spec() {
+        assert_eq!(format_variables("{host:<15} {ip:>20} {cnt}").unwrap(), ["host", "ip", "cnt"]);
+    }
+
+    #[test]
+    fn format_variables_skip_escaped_braces() {
+        assert_eq!(format_variables("{{host}} {ip}").unwrap(), ["ip"]);
+        assert_eq!(format_variables("{{{ip}}}").unwrap(), ["ip"]);
+    }
+
+    #[test]
+    fn numeric_only_takes_away_dns_variables() {
+        let mut args = Args::try_parse_from(["ipstats", "--network-class"]).unwrap();
+        // Resolved from -n, the config file and the other options after parsing
+        args.count.numeric = true;
+        let available = available_variables(&args.count);
+        assert!(!available.contains(&"host") && available.contains(&"class"));
+        assert!(check_format("{class} {ip}", &available).is_ok());
+        let err = check_format("{country} {ip}", &available).unwrap_err();
+        assert!(err.to_string().contains("only available with --geoip-db"), "{err}");
+    }
+
+    #[test]
+    fn format_variables_reject_unbalanced_braces() {
+        assert!(format_variables("{ip").is_err());
+        assert!(format_variables("ip}").is_err());
+        assert!(format_variables("{ip{cnt}}").is_err());
+    }
+}
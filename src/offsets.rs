@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{ File, Metadata };
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+use std::path::{ Path, PathBuf };
+
+use anyhow::{ Context, anyhow };
+use serde::{ Deserialize, Serialize };
+
+use crate::Error;
+
+
+/// Bump whenever the layout of `Offsets` changes in an incompatible way
+const OFFSETS_VERSION: u32 = 1;
+const OFFSETS_FILE: &str = "offsets.json";
+
+
+/// Where reading stopped in a file during the last run
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub struct Position {
+    inode: u64,
+    /// Size of the file when it was last read, it only grows until it is rotated or truncated
+    size: u64,
+    offset: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+struct OffsetsFile {
+    version: u32,
+    files: HashMap<String, Position>,
+}
+
+/// Read offsets per input file kept in --state-dir, so every run only processes what
+/// was appended since the previous one, like logtail does
+pub struct Offsets {
+    path: PathBuf,
+    files: HashMap<String, Position>,
+}
+
+impl Offsets {
+    /// Loads the offsets from `dir`, a missing file or `reset` starts from scratch
+    pub fn load(dir: &str, reset: bool) -> Result<Self, Error> {
+        fs::create_dir_all(dir).with_context(|| format!("Could not create state directory: {dir}"))?;
+        let path = Path::new(dir).join(OFFSETS_FILE);
+        if reset || !path.exists() {
+            return Ok(Offsets { path, files: HashMap::new() });
+        }
+
+        let data = fs::read_to_string(&path)
+            .with_context(|| format!("Could not read offsets file: {}", path.display()))?;
+        let offsets: OffsetsFile = serde_json::from_str(&data)
+            .with_context(|| format!("Could not parse offsets file: {}", path.display()))?;
+        if offsets.version != OFFSETS_VERSION {
+            return Err(anyhow!(
+                "Offsets file {} has version {}, but this version of ipstats only supports version {OFFSETS_VERSION}",
+                path.display(),
+                offsets.version,
+            ).into());
+        }
+        Ok(Offsets { path, files: offsets.files })
+    }
+
+    /// Returns where to continue reading `file`, which is 0 if it was never seen, or it was
+    /// rotated (different inode) or truncated (smaller than it was last time) since
+    pub fn resume(&self, name: &str, file: &File) -> Result<u64, Error> {
+        let meta = file.metadata().with_context(|| format!("Could not stat file: {name}"))?;
+        Ok(match self.files.get(&key(name)) {
+            Some(pos) if pos.inode == inode(&meta) && meta.len() >= pos.size => pos.offset,
+            _ => 0,
+        })
+    }
+
+    /// Records that `file` has been read up to `offset`
+    pub fn update(&mut self, name: &str, file: &File, offset: u64) -> Result<(), Error> {
+        let meta = file.metadata().with_context(|| format!("Could not stat file: {name}"))?;
+        self.files.insert(key(name), Position { inode: inode(&meta), size: meta.len(), offset });
+        Ok(())
+    }
+
+    /// Writes the offsets to a temporary file first and renames it afterwards, so an
+    /// interrupted run never leaves half written offsets behind. The temporary file is named
+    /// after the process, so runs sharing a --state-dir do not write into each other's.
+    pub fn save(&self) -> Result<(), Error> {
+        let tmp = self.path.with_file_name(format!("{OFFSETS_FILE}.{}.tmp", std::process::id()));
+        let data = serde_json::to_string(&OffsetsFile { version: OFFSETS_VERSION, files: self.files.clone() })
+            .context("Could not serialize offsets")?;
+        fs::write(&tmp, data).with_context(|| format!("Could not write offsets file: {}", tmp.display()))?;
+        fs::rename(&tmp, &self.path)
+            .with_context(|| format!("Could not move offsets file into place: {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+/// Tells rotated files apart from the one read before under the same name
+#[cfg(unix)]
+fn inode(meta: &Metadata) -> u64 {
+    meta.ino()
+}
+
+/// Without inodes only truncation gives a rotation away
+#[cfg(not(unix))]
+fn inode(_meta: &Metadata) -> u64 {
+    0
+}
+
+/// Files are tracked by their absolute path, so it does not matter where ipstats is run from
+fn key(name: &str) -> String {
+    fs::canonicalize(name).map(|path| path.display().to_string()).unwrap_or_else(|_| name.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// A fresh directory below the system's temporary one, named after the test
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ipstats-offsets-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn append(path: &Path, data: &str) {
+        fs::OpenOptions::new().create(true).append(true).open(path).unwrap().write_all(data.as_bytes()).unwrap();
+    }
+
+    fn resume_after(dir: &Path, log: &Path, offset: u64) -> u64 {
+        let name = log.to_str().unwrap();
+        let mut offsets = Offsets::load(dir.to_str().unwrap(), false).unwrap();
+        offsets.update(name, &File::open(log).unwrap(), offset).unwrap();
+        offsets.save().unwrap();
+        let offsets = Offsets::load(dir.to_str().unwrap(), false).unwrap();
+        offsets.resume(name, &File::open(log).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn resumes_where_it_left_off() {
+        let dir = scratch("resume");
+        let log = dir.join("access.log");
+        append(&log, "one\ntwo\n");
+        assert_eq!(resume_after(&dir, &log, 8), 8);
+        append(&log, "three\n");
+        let offsets = Offsets::load(dir.to_str().unwrap(), false).unwrap();
+        assert_eq!(offsets.resume(log.to_str().unwrap(), &File::open(&log).unwrap()).unwrap(), 8);
+        assert!(!dir.join(format!("{OFFSETS_FILE}.{}.tmp", std::process::id())).exists());
+    }
+
+    #[test]
+    fn starts_over_after_truncation() {
+        let dir = scratch("truncate");
+        let log = dir.join("access.log");
+        append(&log, "one\ntwo\nthree\n");
+        let name = log.to_str().unwrap();
+        let mut offsets = Offsets::load(dir.to_str().unwrap(), false).unwrap();
+        // Only the first line was read, but the file was bigger than that
+        offsets.update(name, &File::open(&log).unwrap(), 4).unwrap();
+        fs::write(&log, "four\nfive\n").unwrap();
+        assert_eq!(offsets.resume(name, &File::open(&log).unwrap()).unwrap(), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn starts_over_after_rotation() {
+        let dir = scratch("rotate");
+        let log = dir.join("access.log");
+        append(&log, "one\n");
+        let name = log.to_str().unwrap();
+        let mut offsets = Offsets::load(dir.to_str().unwrap(), false).unwrap();
+        offsets.update(name, &File::open(&log).unwrap(), 4).unwrap();
+        fs::rename(&log, dir.join("access.log.1")).unwrap();
+        append(&log, "two\nthree\n");
+        assert_eq!(offsets.resume(name, &File::open(&log).unwrap()).unwrap(), 0);
+    }
+
+    #[test]
+    fn reset_forgets_everything() {
+        let dir = scratch("reset");
+        let log = dir.join("access.log");
+        append(&log, "one\n");
+        resume_after(&dir, &log, 4);
+        let offsets = Offsets::load(dir.to_str().unwrap(), true).unwrap();
+        assert_eq!(offsets.resume(log.to_str().unwrap(), &File::open(&log).unwrap()).unwrap(), 0);
+    }
+}
@@ -0,0 +1,132 @@
+use std::borrow::Cow;
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr };
+
+use log::warn;
+use regex::{ Captures, Regex };
+
+use crate::{ DEFAULT_PATTERN, Error };
+
+
+const TOKEN: &str = "[REDACTED]";
+
+/// Plain IPv4 addresses, zero-padded octets included, which the default pattern leaves to --pattern
+const IPV4_PATTERN: &str = r"\b(?:[0-9]{1,3}\.){3}[0-9]{1,3}\b";
+
+/// What --redact replaces without a --pattern: every IPv6 address (mapped ones included) and every
+/// plain IPv4 address. Unlike the default pattern, a zone id only runs as far as its name, e.g.
+/// `%eth0`, instead of to the end of the line.
+pub fn pattern() -> Result<Regex, Error> {
+    let ipv6 = DEFAULT_PATTERN.strip_suffix("(%.+)?").unwrap_or(DEFAULT_PATTERN);
+    Ok(Regex::new(&format!("(?i)(?:{ipv6})(?:%[0-9a-z]+)?|{IPV4_PATTERN}"))?)
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RedactStyle {
+    /// Replace every IP with `[REDACTED]`
+    Token,
+    /// Zero everything but the network prefix given by --mask, e.g. 192.0.2.0 for 192.0.2.55
+    Mask,
+    /// Replace every IP with a pseudonym like `ip-3f2a9c0b61de`, the same for every occurrence
+    Hash,
+}
+
+
+/// Turns IPs into something safe to share, according to the --redact-style
+pub struct Redactor {
+    style: RedactStyle,
+    v4_prefix: u32,
+    v6_prefix: u32,
+    key: String,
+}
+
+impl Redactor {
+    /// `mask` holds the IPv4 and the IPv6 prefix length, in that order
+    pub fn new(style: RedactStyle, mask: &[u32], key: Option<String>) -> Result<Self, Error> {
+        let [v4_prefix, v6_prefix] = mask else {
+            return Err(Error::Other(
+                "--mask takes exactly two prefix lengths, one for IPv4 and one for IPv6, e.g. 24,48".to_string(),
+            ));
+        };
+        if *v4_prefix > 32 || *v6_prefix > 128 {
+            return Err(Error::Other("--mask prefix lengths can be at most 32 for IPv4 and 128 for IPv6".to_string()));
+        }
+        if style == RedactStyle::Hash && key.is_none() {
+            warn!("Without --hash-key the pseudonyms can be reversed by simply hashing all IPv4 addresses");
+        }
+        Ok(Redactor { style, v4_prefix: *v4_prefix, v6_prefix: *v6_prefix, key: key.unwrap_or_default() })
+    }
+
+    /// The replacement for a single match, which may not even be an IP with a custom --pattern
+    pub fn redact(&self, m: &str) -> String {
+        // The zone id is dropped with the address, it only names an interface of the logging host
+        let address = m.split_once('%').map_or(m, |(address, _)| address);
+        let ip = address.parse::<IpAddr>().map(|ip| ip.to_canonical());
+        match (self.style, ip) {
+            (RedactStyle::Mask, Ok(IpAddr::V4(ip))) => {
+                Ipv4Addr::from(u32::from(ip) & u32::MAX.checked_shl(32 - self.v4_prefix).unwrap_or(0)).to_string()
+            }
+            (RedactStyle::Mask, Ok(IpAddr::V6(ip))) => {
+                Ipv6Addr::from(u128::from(ip) & u128::MAX.checked_shl(128 - self.v6_prefix).unwrap_or(0)).to_string()
+            }
+            (RedactStyle::Hash, ip) => {
+                // Hash the canonical form, so ::ffff:192.0.2.1 and 192.0.2.1 get the same pseudonym
+                let input = ip.map_or_else(|_| m.to_string(), |ip| ip.to_string());
+                let mac = hmac_sha256::HMAC::mac(input, &self.key);
+                let hex: String = mac[..6].iter().map(|byte| format!("{byte:02x}")).collect();
+                format!("ip-{hex}")
+            }
+            _ => TOKEN.to_string(),
+        }
+    }
+
+    /// Replaces every match of `pattern` in `line`, leaving everything around them untouched
+    pub fn redact_all<'a>(&self, pattern: &Regex, line: &'a str) -> Cow<'a, str> {
+        pattern.replace_all(line, |caps: &Captures| self.redact(&caps[0]))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redact(style: RedactStyle, line: &str) -> String {
+        let redactor = Redactor::new(style, &[24, 48], Some(String::from("key"))).unwrap();
+        redactor.redact_all(&pattern().unwrap(), line).into_owned()
+    }
+
+    #[test]
+    fn plain_ipv4() {
+        assert_eq!(redact(RedactStyle::Token, "from 192.0.2.55 port 22\n"), "from [REDACTED] port 22\n");
+        assert_eq!(redact(RedactStyle::Mask, "192.0.2.55 and 198.51.100.7"), "192.0.2.0 and 198.51.100.0");
+    }
+
+    #[test]
+    fn ipv6() {
+        assert_eq!(redact(RedactStyle::Token, "[2001:db8::1]:443 closed"), "[[REDACTED]]:443 closed");
+        assert_eq!(redact(RedactStyle::Mask, "client 2001:db8:1:2::3 done"), "client 2001:db8:1:: done");
+    }
+
+    #[test]
+    fn mapped_ipv4() {
+        // The whole mapped address goes, not only the IPv4 part of it
+        assert_eq!(redact(RedactStyle::Token, "peer ::ffff:192.0.2.1 reset"), "peer [REDACTED] reset");
+        assert_eq!(redact(RedactStyle::Mask, "peer ::ffff:192.0.2.1 reset"), "peer 192.0.2.0 reset");
+        assert_eq!(redact(RedactStyle::Hash, "::ffff:192.0.2.1"), redact(RedactStyle::Hash, "192.0.2.1"));
+    }
+
+    #[test]
+    fn zone_id_mid_line() {
+        assert_eq!(
+            redact(RedactStyle::Token, "neighbor fe80::1%eth0 lost, was 192.0.2.1\n"),
+            "neighbor [REDACTED] lost, was [REDACTED]\n",
+        );
+        assert_eq!(redact(RedactStyle::Mask, "via fe80::1:2:3:4%en0 up"), "via fe80:: up");
+    }
+
+    #[test]
+    fn leaves_other_text_alone() {
+        assert_eq!(redact(RedactStyle::Token, "GET /index.html 200 1234\n"), "GET /index.html 200 1234\n");
+    }
+}
@@ -0,0 +1,283 @@
+use std::cell::OnceCell;
+use std::net::IpAddr;
+
+use crate::{ Error, HostCache, Stats, compare_keys, strip_label };
+
+
+/// What `Results::sorted` orders the entries by
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortKey {
+    /// The number of occurrences, ties are ordered by IP
+    Count,
+    /// The IP, numerically, with anything that is not an IP after them
+    Ip,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+/// The address family `Filters::family` keeps
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+
+/// Looks up the hostname of an IP, implemented by `HostCache` and by anything that should stand in
+/// for the real DNS, like a fixed table
+pub trait Resolver {
+    /// The hostname of `ip`, or the IP itself if it has none
+    fn resolve(&self, ip: IpAddr) -> Result<String, Error>;
+}
+
+impl Resolver for HostCache {
+    fn resolve(&self, ip: IpAddr) -> Result<String, Error> {
+        self.lookup(&ip.to_string())
+    }
+}
+
+
+/// Iterating over `Stats` in a defined order, instead of rendering them with `print_stats`
+///
+/// ```
+/// use std::net::IpAddr;
+/// use ipstats::{ Error, Order, Resolver, Results, SortKey, Stats };
+///
+/// struct Fake;
+///
+/// impl Resolver for Fake {
+///     fn resolve(&self, ip: IpAddr) -> Result<String, Error> {
+///         Ok(format!("host-{ip}.example"))
+///     }
+/// }
+///
+/// let stats: Stats = [("192.0.2.1".to_string(), 2), ("198.51.100.7".to_string(), 5)].into_iter().collect();
+/// let top: Vec<_> = stats.sorted(SortKey::Count, Order::Desc).resolve_with(&Fake).take(1).collect();
+/// assert_eq!((top[0].ip, top[0].count), ("198.51.100.7", 5));
+/// assert_eq!(top[0].host()?, "host-198.51.100.7.example");
+/// # Ok::<(), ipstats::Error>(())
+/// ```
+pub trait Results {
+    fn sorted(&self, key: SortKey, order: Order) -> Entries<'_>;
+}
+
+impl Results for Stats {
+    fn sorted(&self, key: SortKey, order: Order) -> Entries<'_> {
+        let mut entries: Vec<_> = self.iter().collect();
+        entries.sort_by(|a, b| {
+            let primary = match key {
+                SortKey::Count => a.1.cmp(b.1),
+                SortKey::Ip => compare_keys(a.0, b.0),
+            };
+            // Only the sort key is reversed, ties on the count stay in ascending order of the IP
+            let primary = if order == Order::Desc { primary.reverse() } else { primary };
+            primary.then_with(|| compare_keys(a.0, b.0))
+        });
+        Entries { inner: entries.into_iter(), resolver: None }
+    }
+}
+
+
+/// The entries of `Results::sorted`, filtering and limiting is up to the usual iterator adapters
+pub struct Entries<'a> {
+    inner: std::vec::IntoIter<(&'a String, &'a u32)>,
+    resolver: Option<&'a dyn Resolver>,
+}
+
+impl<'a> Entries<'a> {
+    /// Makes `Entry::host` look up the hostnames with `resolver`, otherwise it is always the IP
+    pub fn resolve_with(mut self, resolver: &'a dyn Resolver) -> Self {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    fn entry(&self, (ip, count): (&'a String, &'a u32)) -> Entry<'a> {
+        Entry { ip, count: *count, resolver: self.resolver, host: OnceCell::new() }
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Entry<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.inner.next()?;
+        Some(self.entry(next))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl DoubleEndedIterator for Entries<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let next = self.inner.next_back()?;
+        Some(self.entry(next))
+    }
+}
+
+impl ExactSizeIterator for Entries<'_> {}
+
+/// Entries in the order they were already put in, e.g. by `select`
+impl<'a> FromIterator<(&'a String, &'a u32)> for Entries<'a> {
+    fn from_iter<I: IntoIterator<Item = (&'a String, &'a u32)>>(entries: I) -> Self {
+        let entries: Vec<_> = entries.into_iter().collect();
+        Entries { inner: entries.into_iter(), resolver: None }
+    }
+}
+
+
+/// Filters for any iterator over entries, so they can be chained with each other and the usual adapters
+///
+/// ```
+/// use ipstats::{ Family, Filters, Order, Results, SortKey, Stats };
+///
+/// let stats: Stats = [("192.0.2.1", 2), ("192.0.2.200", 5), ("2001:db8::1", 9), ("198.51.100.7", 1)]
+///     .into_iter()
+///     .map(|(ip, count)| (ip.to_string(), count))
+///     .collect();
+/// let entries = stats.sorted(SortKey::Count, Order::Desc).family(Family::V4).above(1).within("192.0.2.0".parse()?, 24);
+/// assert_eq!(entries.map(|entry| entry.ip).collect::<Vec<_>>(), ["192.0.2.200", "192.0.2.1"]);
+/// # Ok::<(), std::net::AddrParseError>(())
+/// ```
+pub trait Filters<'a>: Iterator<Item = Entry<'a>> + Sized {
+    /// Keeps the entries counted more often than `threshold`, like --threshold
+    fn above(self, threshold: u32) -> impl Iterator<Item = Entry<'a>> {
+        self.filter(move |entry| entry.count > threshold)
+    }
+
+    /// Keeps the IPs in the network `network/prefix_len`, IPv4-mapped addresses count as IPv4.
+    /// Keys that are no IP are dropped.
+    fn within(self, network: IpAddr, prefix_len: u8) -> impl Iterator<Item = Entry<'a>> {
+        self.filter(move |entry| entry.addr().is_some_and(|ip| in_network(ip, network, prefix_len)))
+    }
+
+    /// Keeps the IPs of one family, IPv4-mapped addresses count as IPv4. Keys that are no IP are dropped.
+    fn family(self, family: Family) -> impl Iterator<Item = Entry<'a>> {
+        self.filter(move |entry| match entry.addr().map(|ip| ip.to_canonical()) {
+            Some(IpAddr::V4(_)) => family == Family::V4,
+            Some(IpAddr::V6(_)) => family == Family::V6,
+            None => false,
+        })
+    }
+}
+
+impl<'a, I: Iterator<Item = Entry<'a>>> Filters<'a> for I {}
+
+fn in_network(ip: IpAddr, network: IpAddr, prefix_len: u8) -> bool {
+    let prefix = |bits: u32| u32::from(prefix_len).min(bits);
+    match (ip.to_canonical(), network.to_canonical()) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix(32)).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix(128)).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+
+/// A single counted IP, the hostname is only looked up when asked for
+pub struct Entry<'a> {
+    pub ip: &'a str,
+    pub count: u32,
+    resolver: Option<&'a dyn Resolver>,
+    host: OnceCell<String>,
+}
+
+impl Entry<'_> {
+    /// The address, without the label of --prepend-label, if the key is an IP at all
+    pub fn addr(&self) -> Option<IpAddr> {
+        strip_label(self.ip).parse().ok()
+    }
+
+    /// The hostname, looked up on the first call only. Without a resolver, and for keys that are
+    /// not an IP, it is the key itself.
+    pub fn host(&self) -> Result<&str, Error> {
+        if let Some(host) = self.host.get() {
+            return Ok(host);
+        }
+        let host = match (self.resolver, self.addr()) {
+            (Some(resolver), Some(ip)) => resolver.resolve(ip)?,
+            _ => self.ip.to_string(),
+        };
+        Ok(self.host.get_or_init(|| host))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats() -> Stats {
+        [("192.0.2.1", 2), ("192.0.2.200", 5), ("::ffff:198.51.100.7", 3), ("2001:db8::1", 9), ("not an ip", 4)]
+            .into_iter()
+            .map(|(ip, count)| (ip.to_string(), count))
+            .collect()
+    }
+
+    fn ips<'a>(entries: impl Iterator<Item = Entry<'a>>) -> Vec<&'a str> {
+        entries.map(|entry| entry.ip).collect()
+    }
+
+    #[test]
+    fn sorted_breaks_ties_by_ip() {
+        let stats: Stats = [("192.0.2.2".to_string(), 1), ("192.0.2.10".to_string(), 1)].into_iter().collect();
+        assert_eq!(ips(stats.sorted(SortKey::Count, Order::Desc)), ["192.0.2.2", "192.0.2.10"]);
+    }
+
+    #[test]
+    fn above_drops_the_threshold_itself() {
+        let stats = stats();
+        assert_eq!(ips(stats.sorted(SortKey::Count, Order::Asc).above(3)), ["not an ip", "192.0.2.200", "2001:db8::1"]);
+    }
+
+    #[test]
+    fn within_matches_the_prefix() {
+        let stats = stats();
+        let within = |network: &str, prefix_len| {
+            ips(stats.sorted(SortKey::Ip, Order::Asc).within(network.parse().unwrap(), prefix_len))
+        };
+        assert_eq!(within("192.0.2.128", 25), ["192.0.2.200"]);
+        assert_eq!(within("192.0.2.0", 24), ["192.0.2.1", "192.0.2.200"]);
+        assert_eq!(within("198.51.100.0", 24), ["::ffff:198.51.100.7"]);
+        assert_eq!(within("2001:db8::", 32), ["2001:db8::1"]);
+        assert_eq!(within("0.0.0.0", 0).len(), 3);
+    }
+
+    #[test]
+    fn family_counts_mapped_addresses_as_ipv4() {
+        let stats = stats();
+        assert_eq!(ips(stats.sorted(SortKey::Count, Order::Desc).family(Family::V6)), ["2001:db8::1"]);
+        assert_eq!(ips(stats.sorted(SortKey::Count, Order::Desc).family(Family::V4).take(2)), ["192.0.2.200", "::ffff:198.51.100.7"]);
+    }
+
+    #[test]
+    fn hosts_are_resolved_once() {
+        struct Counting(std::cell::Cell<u32>);
+
+        impl Resolver for Counting {
+            fn resolve(&self, ip: IpAddr) -> Result<String, Error> {
+                self.0.set(self.0.get() + 1);
+                Ok(format!("host-{ip}"))
+            }
+        }
+
+        let stats = stats();
+        let resolver = Counting(std::cell::Cell::new(0));
+        let entries: Vec<_> = stats.sorted(SortKey::Count, Order::Desc).resolve_with(&resolver).collect();
+        assert_eq!(resolver.0.get(), 0);
+        assert_eq!(entries[0].host().unwrap(), "host-2001:db8::1");
+        assert_eq!(entries[0].host().unwrap(), "host-2001:db8::1");
+        assert_eq!(entries[2].host().unwrap(), "not an ip");
+        assert_eq!(resolver.0.get(), 1);
+    }
+}
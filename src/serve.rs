@@ -0,0 +1,172 @@
+use std::fmt::Write;
+
+use crate::{ Order, Results, SortKey, Stats };
+
+
+/// Renders the `top` IPs with the highest counts in the Prometheus text exposition format. The rest
+/// is left out, so a busy log cannot make the number of series explode.
+pub fn exposition(stats: &Stats, top: usize) -> String {
+    let mut text = String::new();
+    text.push_str("# HELP ipstats_hits_total Occurrences of the IP in the input so far\n");
+    text.push_str("# TYPE ipstats_hits_total counter\n");
+    for entry in stats.sorted(SortKey::Count, Order::Desc).take(top) {
+        // Label values need backslashes, quotes and line breaks escaped, --count-field-values may count anything
+        let ip = entry.ip.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+        let _ = writeln!(text, "ipstats_hits_total{{ip=\"{ip}\"}} {}", entry.count);
+    }
+    text.push_str("# HELP ipstats_distinct_ips Distinct IPs in the input so far, including those not exposed\n");
+    text.push_str("# TYPE ipstats_distinct_ips gauge\n");
+    let _ = writeln!(text, "ipstats_distinct_ips {}", stats.len());
+    text
+}
+
+
+#[cfg(feature = "serve")]
+mod server {
+    use std::net::SocketAddr;
+    use std::sync::{ Arc, Mutex };
+    use std::thread::{ self, JoinHandle };
+
+    use log::debug;
+    use tiny_http::{ Header, Method, Response, Server };
+
+    use super::exposition;
+    use crate::{ Error, Stats };
+
+
+    /// Answers `GET /metrics` with the stats last handed to `update`, from a thread of its own.
+    ///
+    /// The server only ever sees a rendered snapshot, so the counting never waits for a scrape.
+    pub struct MetricsServer {
+        server: Arc<Server>,
+        snapshot: Arc<Mutex<String>>,
+        top: usize,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl MetricsServer {
+        /// Listens on `addr`, e.g. `127.0.0.1:9100`, exposing the `top` IPs with the highest counts
+        pub fn start(addr: &str, top: usize) -> Result<Self, Error> {
+            let server = Server::http(addr).map_err(|err| Error::Other(format!("Could not listen on {addr}: {err}")))?;
+            let server = Arc::new(server);
+            let snapshot = Arc::new(Mutex::new(exposition(&Stats::default(), top)));
+            let thread = {
+                let server = server.clone();
+                let snapshot = snapshot.clone();
+                thread::spawn(move || serve(&server, &snapshot))
+            };
+            Ok(MetricsServer { server, snapshot, top, thread: Some(thread) })
+        }
+
+        /// Where the server listens, which tells the port picked for port 0
+        pub fn addr(&self) -> Option<SocketAddr> {
+            self.server.server_addr().to_ip()
+        }
+
+        /// Replaces what is served with `stats`
+        pub fn update(&self, stats: &Stats) {
+            let text = exposition(stats, self.top);
+            *self.snapshot.lock().unwrap_or_else(|err| err.into_inner()) = text;
+        }
+
+        /// Stops the server from anywhere, e.g. a signal handler, once the request at hand is answered
+        pub fn stopper(&self) -> impl Fn() + Send + 'static {
+            let server = self.server.clone();
+            move || server.unblock()
+        }
+
+        /// Stops the server and waits for the request at hand to be answered
+        pub fn shutdown(mut self) {
+            self.server.unblock();
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    fn serve(server: &Server, snapshot: &Mutex<String>) {
+        let content_type = Header::from_bytes(&b"Content-Type"[..], &b"text/plain; version=0.0.4"[..])
+            .expect("a valid header");
+        for request in server.incoming_requests() {
+            let response = match (request.method(), request.url()) {
+                (Method::Get, "/metrics") => {
+                    let text = snapshot.lock().unwrap_or_else(|err| err.into_inner()).clone();
+                    Response::from_string(text).with_header(content_type.clone())
+                }
+                _ => Response::from_string("Not found, try /metrics\n").with_status_code(404),
+            };
+            if let Err(err) = request.respond(response) {
+                debug!("Could not answer a metrics request: {err}");
+            }
+        }
+    }
+}
+
+#[cfg(feature = "serve")]
+pub use server::MetricsServer;
+
+
+/// Stand-in used when built without the `serve` feature, which refuses to start
+#[cfg(not(feature = "serve"))]
+pub struct MetricsServer;
+
+#[cfg(not(feature = "serve"))]
+impl MetricsServer {
+    pub fn start(_addr: &str, _top: usize) -> Result<Self, crate::Error> {
+        Err(crate::Error::Other("Serving metrics is not available, ipstats was built without the `serve` feature".to_string()))
+    }
+
+    pub fn update(&self, _stats: &Stats) {}
+
+    pub fn shutdown(self) {}
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exposition_keeps_the_top_ips() {
+        let stats: Stats = [("192.0.2.1", 2), ("192.0.2.2", 5), ("192.0.2.3", 2)]
+            .into_iter()
+            .map(|(ip, count)| (ip.to_string(), count))
+            .collect();
+        let text = exposition(&stats, 2);
+        let series: Vec<_> = text.lines().filter(|line| !line.starts_with('#')).collect();
+        assert_eq!(series, [
+            "ipstats_hits_total{ip=\"192.0.2.2\"} 5",
+            "ipstats_hits_total{ip=\"192.0.2.1\"} 2",
+            "ipstats_distinct_ips 3",
+        ]);
+    }
+
+    #[test]
+    fn exposition_escapes_label_values() {
+        let stats: Stats = [("say \"hi\"\\".to_string(), 1)].into_iter().collect();
+        assert!(exposition(&stats, 10).contains("ipstats_hits_total{ip=\"say \\\"hi\\\"\\\\\"} 1\n"));
+    }
+
+    #[cfg(feature = "serve")]
+    #[test]
+    fn serves_the_last_update() {
+        use std::io::{ Read, Write };
+        use std::net::TcpStream;
+
+        let metrics = MetricsServer::start("127.0.0.1:0", 10).unwrap();
+        let addr = metrics.addr().unwrap();
+        metrics.update(&[("192.0.2.1".to_string(), 3)].into_iter().collect());
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {path} HTTP/1.0\r\n\r\n").unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.0 200"), "{response}");
+        assert!(response.contains("\r\n\r\n# HELP") && response.contains("ipstats_hits_total{ip=\"192.0.2.1\"} 3\n"));
+        assert!(get("/").starts_with("HTTP/1.0 404"));
+        metrics.shutdown();
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::VecDeque;
+use std::io;
+use std::io::{ BufRead, Read };
+
+use crate::{ Error, PENDING_WAIT };
+
+
+/// What `LineSource::next_line` came up with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Next {
+    /// A line was appended to the buffer, `bytes` is how much input it took including the line
+    /// break. Lines the source skipped for being too long are not appended, but reported here.
+    Line { bytes: usize, too_long: bool },
+    /// Nothing new yet, but there may be more later, e.g. a followed log or a consumer waiting for
+    /// messages. Asked again after a short pause.
+    Pending,
+    /// There will not be any more lines
+    Eof,
+}
+
+/// Where lines come from for `process_source`, for feeding ipstats from anything that is not a
+/// file or stdin
+pub trait LineSource {
+    /// Appends the next line to `line`, which is always empty when this is called
+    fn next_line(&mut self, line: &mut String) -> io::Result<Next>;
+
+    /// Names the input in messages
+    fn name(&self) -> &str;
+
+    /// The size of the whole input in bytes, if known in advance
+    fn len_hint(&self) -> Option<u64> {
+        None
+    }
+
+    /// Whether the input is known to be over, without taking another line from it. Tells a limit
+    /// that cut the input short from one that was reached right at its end. Sources that cannot
+    /// tell without reading on say no.
+    fn is_exhausted(&mut self) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+
+/// Lines of a reader, like a file, stdin or a decompressed gzip stream
+pub struct ReaderSource<R> {
+    reader: R,
+    name: String,
+    max_line_length: Option<usize>,
+    whole_lines: bool,
+    consumed: u64,
+}
+
+impl<R: BufRead> ReaderSource<R> {
+    /// Lines over `max_line_length` bytes are skipped without ever being held in memory
+    pub fn new(reader: R, name: &str, max_line_length: Option<usize>) -> Self {
+        ReaderSource { reader, name: name.to_string(), max_line_length, whole_lines: false, consumed: 0 }
+    }
+
+    /// Ends the input before a last line without a line break, which is left out of `consumed` as
+    /// well, so a file that is still being written can be picked up there on the next run
+    pub fn whole_lines(self) -> Self {
+        ReaderSource { whole_lines: true, ..self }
+    }
+
+    /// Number of bytes read so far
+    pub fn consumed(&self) -> u64 {
+        self.consumed
+    }
+}
+
+impl<R: BufRead> LineSource for ReaderSource<R> {
+    fn next_line(&mut self, line: &mut String) -> io::Result<Next> {
+        let (bytes, too_long, terminated) = match self.max_line_length {
+            Some(max) => read_line_bounded(&mut self.reader, line, max)?,
+            None => {
+                let bytes = self.reader.read_line(line)?;
+                (bytes, false, line.ends_with('\n'))
+            }
+        };
+        if self.whole_lines && bytes > 0 && !terminated {
+            line.clear();
+            return Ok(Next::Eof);
+        }
+        self.consumed += bytes as u64;
+        Ok(if bytes == 0 { Next::Eof } else { Next::Line { bytes, too_long } })
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn is_exhausted(&mut self) -> io::Result<bool> {
+        Ok(self.reader.fill_buf()?.is_empty())
+    }
+}
+
+
+/// Only the last `lines` lines of another source, which is read to its end before the first line
+/// is handed out, keeping no more than those lines in memory
+pub struct TailSource<S> {
+    inner: S,
+    lines: usize,
+    buffer: Option<VecDeque<(String, Next)>>,
+}
+
+impl<S: LineSource> TailSource<S> {
+    pub fn new(inner: S, lines: usize) -> Self {
+        TailSource { inner, lines, buffer: None }
+    }
+
+    fn fill(&mut self) -> io::Result<VecDeque<(String, Next)>> {
+        let mut buffer = VecDeque::with_capacity(self.lines.min(4096));
+        loop {
+            let mut line = String::new();
+            match self.inner.next_line(&mut line)? {
+                Next::Eof => return Ok(buffer),
+                Next::Pending => std::thread::sleep(PENDING_WAIT),
+                next => {
+                    if buffer.len() == self.lines {
+                        buffer.pop_front();
+                    }
+                    buffer.push_back((line, next));
+                }
+            }
+        }
+    }
+}
+
+impl<S: LineSource> LineSource for TailSource<S> {
+    fn next_line(&mut self, line: &mut String) -> io::Result<Next> {
+        if self.buffer.is_none() {
+            self.buffer = Some(self.fill()?);
+        }
+        Ok(match self.buffer.as_mut().and_then(VecDeque::pop_front) {
+            Some((text, next)) => {
+                line.push_str(&text);
+                next
+            }
+            None => Next::Eof,
+        })
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn is_exhausted(&mut self) -> io::Result<bool> {
+        if self.buffer.is_none() {
+            self.buffer = Some(self.fill()?);
+        }
+        Ok(self.buffer.as_ref().is_some_and(VecDeque::is_empty))
+    }
+}
+
+
+/// Whether an input names a URL to fetch instead of a file
+pub fn is_url(input: &str) -> bool {
+    input.starts_with("http://") || input.starts_with("https://")
+}
+
+/// The body of `url`, fetched with a GET request, to be read like a file, e.g. by `ReaderSource`
+#[cfg(feature = "url")]
+pub fn open_url(url: &str) -> Result<Box<dyn Read + Send + Sync>, Error> {
+    match ureq::get(url).call() {
+        Ok(response) => Ok(response.into_reader()),
+        Err(err) => Err(Error::Io { context: format!("Could not fetch {url}"), source: io::Error::other(err) }),
+    }
+}
+
+/// Stand-in used when built without the `url` feature
+#[cfg(not(feature = "url"))]
+pub fn open_url(_url: &str) -> Result<Box<dyn Read + Send + Sync>, Error> {
+    Err(Error::Other("Reading URLs is not available, ipstats was built without the `url` feature".to_string()))
+}
+
+
+/// Like `BufRead::read_line`, but only keeps the line if it is at most `max` bytes long without the
+/// line break, otherwise the rest of it is skipped without ever being held in memory. Returns the
+/// number of bytes consumed, whether the line was too long and whether it ended with a line break.
+fn read_line_bounded(reader: &mut impl BufRead, line: &mut String, max: usize) -> io::Result<(usize, bool, bool)> {
+    // Room for a \r\n, so a line of exactly `max` bytes can still be told apart from a longer one
+    let limit = max.saturating_add(2);
+    let mut bytes = Vec::new();
+    let mut consumed = 0;
+    let mut terminated = false;
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        let (chunk, done) = match buf.iter().position(|b| *b == b'\n') {
+            Some(pos) => (&buf[..=pos], true),
+            None => (buf, false),
+        };
+        let keep = limit.saturating_sub(bytes.len()).min(chunk.len());
+        bytes.extend_from_slice(&chunk[..keep]);
+        let length = chunk.len();
+        reader.consume(length);
+        consumed += length;
+        if done {
+            terminated = true;
+            break;
+        }
+    }
+    let content = bytes.strip_suffix(b"\n").unwrap_or(&bytes);
+    let content = content.strip_suffix(b"\r").unwrap_or(content);
+    if consumed > limit || content.len() > max {
+        return Ok((consumed, true, terminated));
+    }
+    let text = std::str::from_utf8(&bytes)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8"))?;
+    line.push_str(text);
+    Ok((consumed, false, terminated))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounded_lines_skip_what_is_too_long() {
+        // A tiny buffer makes the long line span many reads
+        let mut reader = io::BufReader::with_capacity(4, "abc\r\n0123456789abcdef\nxyz".as_bytes());
+        let mut line = String::new();
+        assert_eq!(read_line_bounded(&mut reader, &mut line, 3).unwrap(), (5, false, true));
+        assert_eq!(line, "abc\r\n");
+        line.clear();
+        assert_eq!(read_line_bounded(&mut reader, &mut line, 3).unwrap(), (17, true, true));
+        assert_eq!(line, "");
+        assert_eq!(read_line_bounded(&mut reader, &mut line, 3).unwrap(), (3, false, false));
+        assert_eq!(line, "xyz");
+    }
+
+    #[test]
+    fn tail_source_hands_out_the_last_lines() {
+        let inner = ReaderSource::new("a\nb\nc".as_bytes(), "input", None);
+        let mut source = TailSource::new(inner, 2);
+        let mut line = String::new();
+        assert!(!source.is_exhausted().unwrap());
+        assert_eq!(source.next_line(&mut line).unwrap(), Next::Line { bytes: 2, too_long: false });
+        assert_eq!(source.next_line(&mut line).unwrap(), Next::Line { bytes: 1, too_long: false });
+        assert_eq!(line, "b\nc");
+        assert!(source.is_exhausted().unwrap());
+        assert_eq!(source.next_line(&mut line).unwrap(), Next::Eof);
+    }
+
+    #[test]
+    fn reader_source_reports_skipped_lines() {
+        let mut source = ReaderSource::new("192.0.2.1\n192.0.2.100 padding\n192.0.2.2\n".as_bytes(), "input", Some(12));
+        let mut line = String::new();
+        assert_eq!(source.next_line(&mut line).unwrap(), Next::Line { bytes: 10, too_long: false });
+        line.clear();
+        assert_eq!(source.next_line(&mut line).unwrap(), Next::Line { bytes: 20, too_long: true });
+        assert_eq!(line, "");
+        assert_eq!(source.next_line(&mut line).unwrap(), Next::Line { bytes: 10, too_long: false });
+        assert_eq!(source.consumed(), 40);
+        line.clear();
+        assert_eq!(source.next_line(&mut line).unwrap(), Next::Eof);
+    }
+
+    #[cfg(feature = "url")]
+    #[test]
+    fn urls_are_read_like_files() {
+        use std::io::Write;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.read(&mut [0; 4096]).unwrap();
+            let body = "192.0.2.1\n192.0.2.2\n";
+            write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len()).unwrap();
+        });
+        let url = format!("http://{addr}/access.log");
+        assert!(is_url(&url));
+        let mut source = ReaderSource::new(io::BufReader::new(open_url(&url).unwrap()), &url, None);
+        let mut line = String::new();
+        while source.next_line(&mut line).unwrap() != Next::Eof {}
+        assert_eq!(line, "192.0.2.1\n192.0.2.2\n");
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,75 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{ Context, anyhow };
+use serde::Deserialize;
+
+use crate::{ Error, Stats };
+
+
+/// Bump whenever the layout of `State` changes in an incompatible way
+const STATE_VERSION: u32 = 1;
+
+
+/// Counting state carried over between runs via --save-state/--load-state,
+/// the version is checked separately via `Header`
+#[derive(Default, Deserialize)]
+pub struct State {
+    /// Total number of lines read to arrive at these stats, across all runs
+    pub lines: u64,
+    pub stats: Stats,
+}
+
+/// Only the version, so it can be checked before trying to make sense of the rest
+#[derive(Deserialize)]
+struct Header {
+    version: u32,
+}
+
+impl State {
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let data = fs::read_to_string(path).with_context(|| format!("Could not read state file: {path}"))?;
+        let header: Header = serde_json::from_str(&data)
+            .with_context(|| format!("Could not parse state file: {path}"))?;
+        if header.version != STATE_VERSION {
+            return Err(anyhow!(
+                "State file {path} has version {}, but this version of ipstats only supports version {STATE_VERSION}",
+                header.version,
+            ).into());
+        }
+        Ok(serde_json::from_str(&data).with_context(|| format!("Could not parse state file: {path}"))?)
+    }
+
+    /// Writes the state to a temporary file next to `path` first and renames it
+    /// afterwards, so an interrupted run never leaves a half written state behind. The
+    /// temporary file is named after the process, so concurrent runs do not share it.
+    pub fn save(path: &str, lines: u64, stats: &Stats) -> Result<(), Error> {
+        let mut name = Path::new(path).file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".{}.tmp", std::process::id()));
+        let tmp = Path::new(path).with_file_name(name);
+        let state = serde_json::json!({ "version": STATE_VERSION, "lines": lines, "stats": stats });
+        let data = serde_json::to_string(&state).context("Could not serialize state")?;
+        fs::write(&tmp, data).with_context(|| format!("Could not write state file: {}", tmp.display()))?;
+        fs::rename(&tmp, path).with_context(|| format!("Could not move state file into place: {path}"))?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saves_next_to_a_path_ending_in_tmp() {
+        let dir = std::env::temp_dir().join(format!("ipstats-state-tmp-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("counts.tmp");
+        let stats: Stats = [(String::from("192.0.2.1"), 3)].into_iter().collect();
+        State::save(path.to_str().unwrap(), 5, &stats).unwrap();
+        let state = State::load(path.to_str().unwrap()).unwrap();
+        assert_eq!((state.lines, state.stats), (5, stats));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,74 @@
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+use anyhow::Context;
+use serde_json::{ Value, json };
+use uuid::Uuid;
+
+use crate::Error;
+
+
+/// Writes the IPs as STIX 2.1 indicators in a bundle, each entry being the IP and the
+/// name to give the indicator, e.g. its hostname
+pub fn write_bundle(path: &str, entries: &[(IpAddr, String)], confidence: Option<u8>) -> Result<(), Error> {
+    let now = timestamp(SystemTime::now());
+    let objects: Vec<Value> = entries.iter().map(|(ip, name)| {
+        let pattern = match ip {
+            IpAddr::V4(ip) => format!("[ipv4-addr:value = '{ip}']"),
+            IpAddr::V6(ip) => format!("[ipv6-addr:value = '{ip}']"),
+        };
+        let mut indicator = json!({
+            "type": "indicator",
+            "spec_version": "2.1",
+            "id": format!("indicator--{}", Uuid::new_v4()),
+            "created": now,
+            "modified": now,
+            "name": name,
+            "indicator_types": ["anomalous-activity"],
+            "pattern": pattern,
+            "pattern_type": "stix",
+            "valid_from": now,
+        });
+        if let Some(confidence) = confidence {
+            indicator["confidence"] = json!(confidence);
+        }
+        indicator
+    }).collect();
+
+    let bundle = json!({
+        "type": "bundle",
+        "id": format!("bundle--{}", Uuid::new_v4()),
+        "objects": objects,
+    });
+    let data = serde_json::to_string_pretty(&bundle).context("Could not serialize STIX bundle")?;
+    fs::write(Path::new(path), data).with_context(|| format!("Could not write IOC file: {path}"))?;
+    Ok(())
+}
+
+/// Formats the time as a UTC RFC 3339 timestamp with millisecond precision, as STIX wants it
+pub fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rest) = ((secs / 86400) as i64, secs % 86400);
+
+    // Civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        rest / 3600,
+        rest % 3600 / 60,
+        rest % 60,
+        since_epoch.subsec_millis(),
+    )
+}
@@ -0,0 +1,306 @@
+use std::io;
+use std::io::prelude::*;
+use std::net::{ ToSocketAddrs, UdpSocket };
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::thread::sleep;
+use std::time::{ Duration, SystemTime };
+
+#[cfg(not(unix))]
+use anyhow::bail;
+use anyhow::{ Context, Result };
+use log::warn;
+
+use crate::stix::timestamp;
+
+
+const TAG: &str = "ipstats";
+const LOCAL_SOCKET: &str = "/dev/log";
+const DEFAULT_PORT: u16 = 514;
+
+// The private enterprise number reserved for documentation (RFC 5612), ipstats has none of its own
+const SD_ID: &str = "ipstats@32473";
+
+// Classic BSD syslog receivers truncate anything longer than this, header included
+const MAX_MESSAGE_BYTES: usize = 1024;
+
+// Pause for a bit after every burst of messages, so long reports do not flood
+// the receiver and get dropped on the floor
+const BURST_SIZE: usize = 100;
+const BURST_PAUSE: Duration = Duration::from_millis(100);
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Facility {
+    Kern = 0,
+    User = 1,
+    Mail = 2,
+    Daemon = 3,
+    Auth = 4,
+    Syslog = 5,
+    Lpr = 6,
+    News = 7,
+    Uucp = 8,
+    Cron = 9,
+    Authpriv = 10,
+    Ftp = 11,
+    Local0 = 16,
+    Local1 = 17,
+    Local2 = 18,
+    Local3 = 19,
+    Local4 = 20,
+    Local5 = 21,
+    Local6 = 22,
+    Local7 = 23,
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Emerg = 0,
+    Alert = 1,
+    Crit = 2,
+    Err = 3,
+    Warning = 4,
+    Notice = 5,
+    Info = 6,
+    Debug = 7,
+}
+
+
+enum Socket {
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+    Udp(UdpSocket),
+}
+
+impl Socket {
+    fn connect(server: Option<&str>) -> Result<Self> {
+        let Some(server) = server else {
+            return Socket::local();
+        };
+
+        // Accept both `host:port` and a bare `host`, which uses the default syslog port
+        let addr = server
+            .to_socket_addrs()
+            .or_else(|_| (server, DEFAULT_PORT).to_socket_addrs())
+            .with_context(|| format!("Could not resolve syslog server: {server}"))?
+            .next()
+            .with_context(|| format!("No address found for syslog server: {server}"))?;
+        let bind = if addr.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" };
+        let socket = UdpSocket::bind(bind).context("Could not create UDP socket")?;
+        socket.connect(addr).with_context(|| format!("Could not connect to syslog server: {addr}"))?;
+        Ok(Socket::Udp(socket))
+    }
+
+    #[cfg(unix)]
+    fn local() -> Result<Self> {
+        let socket = UnixDatagram::unbound().context("Could not create unix socket")?;
+        socket.connect(LOCAL_SOCKET).with_context(|| format!("Could not connect to {LOCAL_SOCKET}"))?;
+        Ok(Socket::Unix(socket))
+    }
+
+    #[cfg(not(unix))]
+    fn local() -> Result<Self> {
+        bail!("There is no local {LOCAL_SOCKET} socket on this platform, pass --syslog-server")
+    }
+
+    fn send(&self, message: &[u8]) -> io::Result<usize> {
+        match self {
+            #[cfg(unix)]
+            Socket::Unix(socket) => socket.send(message),
+            Socket::Udp(socket) => socket.send(message),
+        }
+    }
+}
+
+
+/// Sends every line written to it as a separate syslog message.
+///
+/// If the syslog socket cannot be reached, a warning is printed and all
+/// messages are written to stderr instead, so the report is not lost.
+pub struct SyslogWriter {
+    socket: Option<Socket>,
+    /// None if the lines are complete messages already, see `SyslogWriter::raw`
+    priority: Option<u8>,
+    pending: Vec<u8>,
+    sent: usize,
+}
+
+impl SyslogWriter {
+    pub fn new(server: Option<&str>, facility: Facility, severity: Severity) -> Self {
+        let socket = match Socket::connect(server) {
+            Ok(socket) => Some(socket),
+            Err(err) => {
+                warn!("{err:#}, writing report to stderr instead");
+                None
+            }
+        };
+        SyslogWriter {
+            socket,
+            priority: Some(priority(facility, severity)),
+            pending: Vec::new(),
+            sent: 0,
+        }
+    }
+
+    /// Sends every line as is, for messages formatted by `Rfc5424`
+    pub fn raw(server: &str) -> Self {
+        SyslogWriter { priority: None, ..SyslogWriter::new(Some(server), Facility::User, Severity::Info) }
+    }
+
+    /// The messages a line is sent as, split so that none is longer than MAX_MESSAGE_BYTES
+    /// including its header
+    fn messages(&self, line: &str) -> Vec<String> {
+        // Splitting would break the structured data apart
+        let Some(priority) = self.priority else {
+            return vec![line.to_string()];
+        };
+        let header = format!("<{priority}>{TAG}[{}]: ", std::process::id());
+        let limit = MAX_MESSAGE_BYTES - header.len();
+        let mut messages = Vec::new();
+        let mut rest = line;
+        while !rest.is_empty() {
+            let mut end = rest.len().min(limit);
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            let (chunk, tail) = rest.split_at(end);
+            messages.push(format!("{header}{chunk}"));
+            rest = tail;
+        }
+        messages
+    }
+
+    fn send_line(&mut self, line: &str) {
+        for message in self.messages(line) {
+            self.send_message(&message);
+        }
+    }
+
+    fn send_message(&mut self, message: &str) {
+        if let Some(socket) = &self.socket {
+            if self.sent > 0 && self.sent.is_multiple_of(BURST_SIZE) {
+                sleep(BURST_PAUSE);
+            }
+            match socket.send(message.as_bytes()) {
+                Ok(_) => {
+                    self.sent += 1;
+                    return;
+                }
+                Err(err) => {
+                    warn!("Could not send to syslog: {err}, writing report to stderr instead");
+                    self.socket = None;
+                }
+            }
+        }
+        eprintln!("{message}");
+    }
+}
+
+impl Write for SyslogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        while let Some(pos) = self.pending.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=pos).collect();
+            self.send_line(String::from_utf8_lossy(&line[..pos]).as_ref());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            let line: Vec<u8> = self.pending.drain(..).collect();
+            self.send_line(String::from_utf8_lossy(&line).as_ref());
+        }
+        Ok(())
+    }
+}
+
+
+/// Formats RFC 5424 messages, with the details of a record as structured data
+pub struct Rfc5424 {
+    priority: u8,
+    hostname: String,
+}
+
+impl Rfc5424 {
+    pub fn new(facility: Facility, severity: Severity) -> Self {
+        // The hostname field only allows printable ASCII without spaces
+        let hostname = dns_lookup::get_hostname()
+            .ok()
+            .filter(|name| !name.is_empty() && name.bytes().all(|b| b.is_ascii_graphic()))
+            .unwrap_or_else(|| String::from("-"));
+        Rfc5424 { priority: priority(facility, severity), hostname }
+    }
+
+    pub fn message(&self, params: &[(&str, &str)], message: &str) -> String {
+        let params: String = params.iter()
+            .map(|(name, value)| format!(" {name}=\"{}\"", escape_param(value)))
+            .collect();
+        format!(
+            "<{}>1 {} {} {TAG} {} - [{SD_ID}{params}] {message}",
+            self.priority,
+            timestamp(SystemTime::now()),
+            self.hostname,
+            std::process::id(),
+        )
+    }
+}
+
+fn priority(facility: Facility, severity: Severity) -> u8 {
+    (facility as u8) * 8 + severity as u8
+}
+
+/// Backslash escapes the characters that would end a structured data value
+fn escape_param(value: &str) -> String {
+    value.chars().fold(String::with_capacity(value.len()), |mut escaped, c| {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+        escaped
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn writer(priority: Option<u8>) -> SyslogWriter {
+        SyslogWriter { socket: None, priority, pending: Vec::new(), sent: 0 }
+    }
+
+    #[test]
+    fn messages_fit_with_their_header() {
+        let line = "x".repeat(3000);
+        let messages = writer(Some(14)).messages(&line);
+        assert_eq!(messages.len(), 3);
+        assert!(messages.iter().all(|message| message.len() <= MAX_MESSAGE_BYTES));
+        assert_eq!(messages[0].len(), MAX_MESSAGE_BYTES);
+        let header = format!("<14>{TAG}[{}]: ", std::process::id());
+        let body: String = messages.iter().map(|message| message.strip_prefix(&header).unwrap()).collect();
+        assert_eq!(body, line);
+    }
+
+    #[test]
+    fn messages_split_at_char_boundaries() {
+        let line = "\u{e9}".repeat(1000);
+        let messages = writer(Some(14)).messages(&line);
+        assert!(messages.iter().all(|message| message.len() <= MAX_MESSAGE_BYTES));
+        assert_eq!(messages.iter().map(|message| message.matches('\u{e9}').count()).sum::<usize>(), 1000);
+    }
+
+    #[test]
+    fn raw_messages_are_not_split() {
+        let line = "y".repeat(3000);
+        assert_eq!(writer(None).messages(&line), vec![line]);
+    }
+
+    #[test]
+    fn priority_combines_facility_and_severity() {
+        assert_eq!(priority(Facility::Local0, Severity::Info), 134);
+        assert_eq!(priority(Facility::Kern, Severity::Emerg), 0);
+    }
+}
@@ -0,0 +1,151 @@
+/// Network name and abuse contact of the network an IP belongs to
+#[derive(Clone, Default)]
+pub struct WhoisInfo {
+    pub abuse_contact: String,
+    pub netname: String,
+}
+
+
+#[cfg(feature = "whois")]
+mod client {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::io::prelude::*;
+    use std::net::{ IpAddr, TcpStream, ToSocketAddrs };
+    use std::thread::sleep;
+    use std::time::{ Duration, Instant };
+
+    use anyhow::{ Context, Result };
+
+    use super::WhoisInfo;
+    use crate::Error;
+
+
+    const IANA_SERVER: &str = "whois.iana.org";
+    const PORT: u16 = 43;
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    // Whois servers are quick to block clients, so never query the same server
+    // more often than this
+    const MIN_QUERY_INTERVAL: Duration = Duration::from_secs(1);
+
+
+    /// Queries whois servers, following the referral from IANA to the responsible registry.
+    ///
+    /// Referrals are cached per /8 (IPv4) or /32 (IPv6) and results per IP, so every
+    /// server is asked as little as possible.
+    #[derive(Default)]
+    pub struct Whois {
+        referrals: RefCell<HashMap<IpAddr, String>>,
+        cache: RefCell<HashMap<IpAddr, WhoisInfo>>,
+        last_query: RefCell<HashMap<String, Instant>>,
+    }
+
+    impl Whois {
+        pub fn new() -> std::result::Result<Self, Error> {
+            Ok(Whois::default())
+        }
+
+        /// Looks up the IP, any failure results in empty fields
+        pub fn lookup(&self, ip: IpAddr) -> WhoisInfo {
+            if let Some(info) = self.cache.borrow().get(&ip) {
+                return info.clone();
+            }
+            let info = self.query_registry(ip).unwrap_or_default();
+            self.cache.borrow_mut().insert(ip, info.clone());
+            info
+        }
+
+        fn query_registry(&self, ip: IpAddr) -> Result<WhoisInfo> {
+            let server = self.referral(ip)?;
+            // ARIN only returns the network details when explicitly asked for them
+            let query = if server == "whois.arin.net" { format!("n + {ip}") } else { ip.to_string() };
+            let response = self.query(&server, &query)?;
+            Ok(parse_response(&response))
+        }
+
+        fn referral(&self, ip: IpAddr) -> Result<String> {
+            let prefix = match ip {
+                IpAddr::V4(ip) => IpAddr::V4((u32::from(ip) & 0xff00_0000).into()),
+                IpAddr::V6(ip) => IpAddr::V6((u128::from(ip) & (0xffff_ffff << 96)).into()),
+            };
+            if let Some(server) = self.referrals.borrow().get(&prefix) {
+                return Ok(server.clone());
+            }
+            let response = self.query(IANA_SERVER, &ip.to_string())?;
+            let server = response
+                .lines()
+                .find_map(|line| line.strip_prefix("refer:"))
+                .map(|server| server.trim().to_string())
+                .with_context(|| format!("IANA did not refer to a whois server for {ip}"))?;
+            self.referrals.borrow_mut().insert(prefix, server.clone());
+            Ok(server)
+        }
+
+        fn query(&self, server: &str, query: &str) -> Result<String> {
+            if let Some(last) = self.last_query.borrow().get(server) {
+                if let Some(wait) = MIN_QUERY_INTERVAL.checked_sub(last.elapsed()) {
+                    sleep(wait);
+                }
+            }
+            self.last_query.borrow_mut().insert(server.to_string(), Instant::now());
+
+            let addr = (server, PORT)
+                .to_socket_addrs()
+                .with_context(|| format!("Could not resolve whois server: {server}"))?
+                .next()
+                .with_context(|| format!("No address found for whois server: {server}"))?;
+            let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)
+                .with_context(|| format!("Could not connect to whois server: {server}"))?;
+            stream.set_read_timeout(Some(TIMEOUT))?;
+            stream.set_write_timeout(Some(TIMEOUT))?;
+            stream.write_all(format!("{query}\r\n").as_bytes())?;
+
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).with_context(|| format!("Could not read from whois server: {server}"))?;
+            Ok(String::from_utf8_lossy(&response).into_owned())
+        }
+    }
+
+    /// Picks the network name and abuse contact out of the different registries' output formats
+    fn parse_response(response: &str) -> WhoisInfo {
+        let mut info = WhoisInfo::default();
+        for line in response.lines() {
+            let Some((field, value)) = line.split_once(':') else { continue };
+            let value = value.trim();
+            match field.trim().to_lowercase().as_str() {
+                "netname" if info.netname.is_empty() => info.netname = value.to_string(),
+                "orgabuseemail" | "abuse-mailbox" if info.abuse_contact.is_empty() => {
+                    info.abuse_contact = value.to_string()
+                }
+                // RIPE only mentions the abuse contact in a comment: % Abuse contact for '...' is 'abuse@...'
+                _ if info.abuse_contact.is_empty() && line.starts_with("% Abuse contact for") => {
+                    if let Some(contact) = line.rsplit('\'').nth(1) {
+                        info.abuse_contact = contact.to_string();
+                    }
+                }
+                _ => {}
+            }
+        }
+        info
+    }
+}
+
+#[cfg(feature = "whois")]
+pub use client::Whois;
+
+
+/// Stand-in used when built without the `whois` feature, which refuses to be created
+#[cfg(not(feature = "whois"))]
+pub struct Whois;
+
+#[cfg(not(feature = "whois"))]
+impl Whois {
+    pub fn new() -> Result<Self, crate::Error> {
+        Err(crate::Error::Other("Whois support is not available, ipstats was built without the `whois` feature".to_string()))
+    }
+
+    pub fn lookup(&self, _ip: std::net::IpAddr) -> WhoisInfo {
+        WhoisInfo::default()
+    }
+}
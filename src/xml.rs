@@ -0,0 +1,210 @@
+use std::io::BufRead;
+
+use anyhow::{ Context, bail };
+use log::info;
+use quick_xml::Reader;
+use quick_xml::events::Event;
+
+use crate::{ DedupWindow, Error, Limit, ProcessOptions, RawMatches, Stats, Summary, normalize_key };
+
+
+/// Element path for --xml-input like `log/entry/clientIP`, matching every element whose
+/// innermost enclosing elements are named like that, without namespace prefixes
+#[derive(Clone, Debug)]
+pub struct XmlPath {
+    names: Vec<String>,
+}
+
+impl XmlPath {
+    pub fn parse(path: &str) -> Result<Self, Error> {
+        let names: Vec<String> = path.trim_matches('/').split('/').map(String::from).collect();
+        if names.iter().any(String::is_empty) {
+            return Err(Error::Other(format!(
+                "Invalid XML element path {path:?}, expected element names separated by `/`, e.g. log/entry/clientIP"
+            )));
+        }
+        Ok(XmlPath { names })
+    }
+
+    fn matches(&self, stack: &[String]) -> bool {
+        stack.ends_with(&self.names)
+    }
+
+    /// Counts the text of every matching element of the XML documents in `reader`. Every document
+    /// counts as one line, which must have a match with `opts.pedantic`, and the options working on
+    /// lines like `head`, `sample` or `dedup_window` work on documents. The first `skip_documents`
+    /// documents are not counted.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn count(
+        &self,
+        reader: impl BufRead,
+        name: &str,
+        stats: &mut Stats,
+        opts: &ProcessOptions,
+        summary: &mut Summary,
+        mut raw: Option<&mut RawMatches>,
+        skip_documents: Option<u64>,
+    ) -> anyhow::Result<()> {
+        let mut reader = Reader::from_reader(reader);
+        let mut buf = Vec::new();
+        let mut stack: Vec<String> = Vec::new();
+        // The text of the matching element we are in, if any
+        let mut text: Option<String> = None;
+        let mut documents: u64 = 0;
+        let mut matched: u64 = 0;
+        let mut window = opts.dedup_window.map(DedupWindow::new);
+        // Whether the document we are in is counted, decided as it starts
+        let mut counted = true;
+        // A limit reached after the last document, which only truncates the input if another one follows
+        let mut limit = opts.global_limit(stats, summary);
+
+        loop {
+            let event = match reader.read_event_into(&mut buf) {
+                Ok(event) => event,
+                Err(err) => {
+                    let message = format!("Invalid XML in {name} at byte {}: {err}", reader.error_position());
+                    bail!(Error::input(name, documents + 1, message))
+                }
+            };
+            match event {
+                Event::Start(element) => {
+                    if stack.is_empty() {
+                        if let Some(limit) = limit {
+                            summary.truncated_by = Some(limit);
+                            break
+                        }
+                        counted = skip_documents.is_none_or(|skip| documents >= skip)
+                            && opts.sample.as_ref().is_none_or(|sample| sample.keep());
+                        if counted && opts.sample.is_some() {
+                            *summary.sampled.get_or_insert(0) += 1;
+                        }
+                    }
+                    stack.push(String::from_utf8_lossy(element.local_name().as_ref()).into_owned());
+                    if self.matches(&stack) {
+                        text = Some(String::new());
+                    }
+                }
+                Event::Text(content) => {
+                    if let Some(text) = text.as_mut() {
+                        text.push_str(&content.unescape().with_context(|| format!("Invalid text in {name}"))?);
+                    }
+                }
+                Event::CData(content) => {
+                    if let Some(text) = text.as_mut() {
+                        text.push_str(&content.decode().with_context(|| format!("Invalid CDATA in {name}"))?);
+                    }
+                }
+                Event::End(_) => {
+                    let m = if self.matches(&stack) { text.take().filter(|_| counted) } else { None };
+                    if let Some(m) = m.as_deref().map(str::trim).filter(|m| !m.is_empty()) {
+                        matched += 1;
+                        let ip = normalize_key(m, opts);
+                        if window.as_mut().is_some_and(|window| window.seen(&ip, documents + 1)) {
+                            *summary.deduplicated.get_or_insert(0) += 1;
+                        } else {
+                            if let Some(raw) = raw.as_mut() {
+                                raw.entry(ip.clone()).or_insert_with(|| m.to_string());
+                            }
+                            stats.entry(ip)
+                                .and_modify(|counter: &mut u32| *counter = counter.saturating_add(1))
+                                .or_insert(1);
+                            if let Some(max) = opts.max_distinct.filter(|max| stats.len() > *max) {
+                                bail!("Found more than {max} distinct IPs, giving up (use --max-unique to print a partial report instead)");
+                            }
+                        }
+                    }
+                    stack.pop();
+                    // The root element is closed, that was one document
+                    if stack.is_empty() {
+                        documents += 1;
+                        summary.lines += 1;
+                        if opts.pedantic && counted && matched == 0 {
+                            let message = format!("Document {documents} of {name} has no {} element", self.names.join("/"));
+                            bail!(Error::input(name, documents, message));
+                        }
+                        matched = 0;
+                        limit = if opts.head.is_some_and(|head| documents >= head) {
+                            Some(Limit::Head)
+                        } else {
+                            opts.global_limit(stats, summary)
+                        };
+                    }
+                }
+                Event::Eof => {
+                    if let Some(element) = stack.last() {
+                        let message = format!("{name} ends inside the <{element}> element of document {}", documents + 1);
+                        bail!(Error::input(name, documents + 1, message))
+                    }
+                    break
+                }
+                _ => {}
+            }
+            buf.clear();
+        }
+        info!("Finished {name}: {documents} XML documents");
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+
+    use super::*;
+
+    const DOCUMENTS: &str = "<log><ip>192.0.2.1</ip></log>\n<log><ip>192.0.2.2</ip></log>\n<log><ip>192.0.2.1</ip></log>\n";
+
+    fn options() -> ProcessOptions {
+        ProcessOptions::new(Regex::new(crate::DEFAULT_PATTERN).unwrap())
+    }
+
+    fn count(opts: &ProcessOptions, skip_documents: Option<u64>) -> (Vec<(String, u32)>, Summary) {
+        let (mut stats, mut summary) = (Stats::default(), Summary::default());
+        let path = XmlPath::parse("log/ip").unwrap();
+        path.count(DOCUMENTS.as_bytes(), "input", &mut stats, opts, &mut summary, None, skip_documents).unwrap();
+        let mut counts: Vec<(String, u32)> = stats.into_iter().collect();
+        counts.sort();
+        (counts, summary)
+    }
+
+    #[test]
+    fn head_counts_documents() {
+        let (counts, summary) = count(&ProcessOptions { head: Some(2), ..options() }, None);
+        assert_eq!(counts, [("192.0.2.1".into(), 1), ("192.0.2.2".into(), 1)]);
+        assert_eq!(summary.truncated_by, Some(Limit::Head));
+        let (_, summary) = count(&ProcessOptions { head: Some(3), ..options() }, None);
+        assert_eq!(summary.truncated_by, None);
+    }
+
+    #[test]
+    fn max_unique_stops_after_the_document() {
+        let (counts, summary) = count(&ProcessOptions { max_unique: Some(1), ..options() }, None);
+        assert_eq!(counts, [("192.0.2.1".into(), 1)]);
+        assert_eq!(summary.truncated_by, Some(Limit::MaxUnique));
+    }
+
+    #[test]
+    fn skipped_documents_are_not_counted() {
+        let (counts, summary) = count(&options(), Some(2));
+        assert_eq!(counts, [("192.0.2.1".into(), 1)]);
+        assert_eq!(summary.lines, 3);
+    }
+
+    #[test]
+    fn dedup_window_spans_documents() {
+        let (counts, summary) = count(&ProcessOptions { dedup_window: Some(3), ..options() }, None);
+        assert_eq!(counts, [("192.0.2.1".into(), 1), ("192.0.2.2".into(), 1)]);
+        assert_eq!(summary.deduplicated, Some(1));
+        let (counts, _) = count(&ProcessOptions { dedup_window: Some(2), ..options() }, None);
+        assert_eq!(counts, [("192.0.2.1".into(), 2), ("192.0.2.2".into(), 1)]);
+    }
+
+    #[test]
+    fn max_distinct_gives_up() {
+        let opts = ProcessOptions { max_distinct: Some(1), ..options() };
+        let path = XmlPath::parse("log/ip").unwrap();
+        let mut stats = Stats::default();
+        assert!(path.count(DOCUMENTS.as_bytes(), "input", &mut stats, &opts, &mut Summary::default(), None, None).is_err());
+    }
+}
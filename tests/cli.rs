@@ -0,0 +1,432 @@
+//! Runs the ipstats binary on small inputs and checks what it prints
+
+use std::fs;
+use std::io::Write;
+use std::path::{ Path, PathBuf };
+use std::process::{ Command, Output, Stdio };
+
+
+/// Runs ipstats with `args` and `input` on stdin, away from any config file
+fn ipstats(args: &[&str], input: &str) -> Output {
+    ipstats_in(Path::new(env!("CARGO_TARGET_TMPDIR")), args, input)
+}
+
+/// Runs ipstats in `dir`, picking up the `.ipstats.toml` there if any
+fn ipstats_in(dir: &Path, args: &[&str], input: &str) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_ipstats"));
+    for (name, _) in std::env::vars().filter(|(name, _)| name.starts_with("IPSTATS_")) {
+        command.env_remove(name);
+    }
+    let mut child = command
+        .args(args)
+        .current_dir(dir)
+        .env("HOME", env!("CARGO_TARGET_TMPDIR"))
+        .env_remove("XDG_CONFIG_HOME")
+        .env_remove("RUST_LOG")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("ipstats to start");
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+/// A fresh directory for the files of one test
+fn scratch(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(output.status.success(), "ipstats failed: {}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+
+#[test]
+fn redact_replaces_ipv4_and_ipv6() {
+    let input = "a 192.0.2.1 b\nc ::ffff:198.51.100.2 d\ne fe80::1%eth0 f 2001:db8::2\n";
+    let output = ipstats(&["--redact"], input);
+    assert_eq!(stdout(&output), "a [REDACTED] b\nc [REDACTED] d\ne [REDACTED] f [REDACTED]\n");
+}
+
+#[test]
+fn max_unique_at_the_end_is_no_truncation() {
+    let input: String = (1..=10).map(|i| format!("192.0.2.{i}\n")).collect();
+    let output = ipstats(&["-n", "-p", r"([0-9]+\.){3}[0-9]+", "--max-unique", "10"], &input);
+    assert_eq!(stdout(&output).lines().count(), 10);
+    assert!(output.stderr.is_empty(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let output = ipstats(&["-n", "-p", r"([0-9]+\.){3}[0-9]+", "--max-unique", "9"], &input);
+    assert_eq!(stdout(&output).lines().count(), 9);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("truncated by --max-unique after 9 distinct IPs"));
+}
+
+#[test]
+fn head_at_the_end_is_no_truncation() {
+    let input: String = (1..=10).map(|i| format!("192.0.2.{i}\n")).collect();
+    let output = ipstats(&["-n", "-p", r"([0-9]+\.){3}[0-9]+", "--head", "10", "--summary"], &input);
+    assert_eq!(stdout(&output).lines().count(), 10);
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("truncated"));
+
+    let output = ipstats(&["-n", "-p", r"([0-9]+\.){3}[0-9]+", "--head", "5", "--summary"], &input);
+    assert_eq!(stdout(&output).lines().count(), 5);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Input was truncated by --head"));
+}
+
+#[test]
+fn filter_top_breaks_ties_by_ip() {
+    let input = "192.0.2.3 a\n192.0.2.1 b\n192.0.2.2 c\n192.0.2.1 d\n192.0.2.3 e\n192.0.2.2 f\n192.0.2.4 g\n";
+    let pattern = r"([0-9]+\.){3}[0-9]+";
+    let output = ipstats(&["filter", "-p", pattern, "--top", "2"], input);
+    assert_eq!(stdout(&output), "192.0.2.1 b\n192.0.2.2 c\n192.0.2.1 d\n192.0.2.2 f\n");
+    let output = ipstats(&["filter", "-p", pattern, "--threshold", "1"], input);
+    assert_eq!(stdout(&output), "192.0.2.3 a\n192.0.2.1 b\n192.0.2.2 c\n192.0.2.1 d\n192.0.2.3 e\n192.0.2.2 f\n");
+}
+
+#[test]
+fn fields_need_their_options() {
+    let output = ipstats(&["-n", "--fields", "cnt,country"], "::ffff:192.0.2.1\n");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("The country field is only available with --geoip-db"));
+    let output = ipstats(&["-n", "--fields", "cnt,ip"], "::ffff:192.0.2.1\n");
+    assert_eq!(stdout(&output), "1\t192.0.2.1\n");
+}
+
+#[test]
+fn command_line_overrides_config() {
+    let dir = scratch("config");
+    fs::write(dir.join(".ipstats.toml"), "numeric = true\nmax_results = 1\nthreshold = 5\n").unwrap();
+    let input = "::ffff:192.0.2.1\n::ffff:192.0.2.2\n::ffff:192.0.2.2\n";
+    assert_eq!(stdout(&ipstats_in(&dir, &["--no-auto-format"], input)), "");
+    let output = ipstats_in(&dir, &["--no-auto-format", "-t", "0", "-m", "2"], input);
+    assert_eq!(stdout(&output), "1 192.0.2.1\n2 192.0.2.2\n");
+    // Skipping the lookups keeps the hostname column, but does not depend on the resolver
+    let output = ipstats_in(&dir, &["--no-auto-format", "-t", "0", "--numeric=false", "--skip-v4-rdns"], input);
+    assert_eq!(stdout(&output), "2 192.0.2.2 (192.0.2.2)\n");
+    let output = ipstats_in(&dir, &["--no-auto-format", "-t", "0", "--no-config"], input);
+    assert_eq!(stdout(&output).lines().count(), 2);
+}
+
+#[test]
+fn config_sets_dns_options() {
+    let dir = scratch("config-dns");
+    fs::write(dir.join(".ipstats.toml"), "skip_v4_rdns = true\n").unwrap();
+    let output = ipstats_in(&dir, &["-f", "{host}"], "::ffff:127.0.0.1\n");
+    assert_eq!(stdout(&output), "127.0.0.1\n");
+}
+
+#[test]
+fn ioc_and_ip_list_outputs_match_the_report() {
+    let dir = scratch("ioc");
+    let input = "::ffff:192.0.2.1\n::ffff:192.0.2.2\n::ffff:192.0.2.2\n::ffff:192.0.2.3\n";
+    let (iocs, list) = (dir.join("iocs.json"), dir.join("ips.txt"));
+    let output = ipstats(&[
+        "-n", "-m", "2", "--ioc-output", iocs.to_str().unwrap(), "--ip-list-output", list.to_str().unwrap(),
+    ], input);
+    assert_eq!(stdout(&output), "1 192.0.2.3\n2 192.0.2.2\n");
+    assert_eq!(fs::read_to_string(&list).unwrap(), "192.0.2.3\n192.0.2.2\n");
+    let bundle = fs::read_to_string(&iocs).unwrap();
+    assert!(bundle.contains("[ipv4-addr:value = '192.0.2.2']") && bundle.contains("[ipv4-addr:value = '192.0.2.3']"));
+    assert!(!bundle.contains("192.0.2.1"));
+}
+
+#[test]
+fn distinct_ports_are_counted_per_file_with_separate() {
+    let dir = scratch("distinct-ports");
+    fs::write(dir.join("a.log"), "::ffff:192.0.2.1 port 80\n").unwrap();
+    fs::write(dir.join("b.log"), "::ffff:192.0.2.1 port 80\n::ffff:192.0.2.1 port 81\n").unwrap();
+    let args = ["-n", "--distinct-ports", r"port (\d+)", "-f", "{distinct_ports} {ip}", "--separate", "a.log", "b.log"];
+    let output = ipstats_in(&dir, &args, "");
+    assert_eq!(stdout(&output), "== a.log ==\n1 192.0.2.1\n== b.log ==\n2 192.0.2.1\n");
+}
+
+#[test]
+fn xml_input_takes_line_options_per_document() {
+    let input = "<e><ip>192.0.2.1</ip></e>\n<e><ip>192.0.2.2</ip></e>\n<e><ip>192.0.2.3</ip></e>\n";
+    let output = ipstats(&["-n", "--xml-input", "e/ip", "--skip-lines", "1", "--head", "2"], input);
+    assert_eq!(stdout(&output), "1 192.0.2.2\n");
+}
+
+#[test]
+fn jsonl_schema_is_compact_or_pretty() {
+    let input = "::ffff:192.0.2.1\n::ffff:192.0.2.2\n::ffff:192.0.2.2\n";
+    let output = ipstats(&["-n", "--output-format", "jsonl-schema"], input);
+    assert_eq!(stdout(&output), concat!(
+        "{\"schema\":{\"ip\":\"string\",\"count\":\"integer\"}}\n",
+        "{\"ip\":\"192.0.2.1\",\"count\":1}\n",
+        "{\"ip\":\"192.0.2.2\",\"count\":2}\n",
+    ));
+    let output = ipstats(&["-n", "--output-format", "jsonl-schema", "--json-pretty"], input);
+    assert_eq!(stdout(&output), concat!(
+        "{\n  \"schema\": {\n    \"ip\": \"string\",\n    \"count\": \"integer\"\n  }\n}\n",
+        "{\n  \"ip\": \"192.0.2.1\",\n  \"count\": 1\n}\n",
+        "{\n  \"ip\": \"192.0.2.2\",\n  \"count\": 2\n}\n",
+    ));
+}
+
+#[test]
+fn json_warnings_point_at_the_line() {
+    let input = "{\"ip\": \"192.0.2.1\"}\nnot json\n";
+    let output = ipstats(&["-n", "--errors", "json", "--json-input", "ip"], input);
+    assert_eq!(stdout(&output), "1 192.0.2.1\n");
+    let warning: serde_json::Value = serde_json::from_slice(&output.stderr).unwrap();
+    assert_eq!((&warning["kind"], &warning["file"], &warning["line"]), (&"warning".into(), &"stdin".into(), &2.into()));
+}
+
+#[test]
+fn quiet_hides_warnings_and_then_the_summary() {
+    let input = "1 ::ffff:192.0.2.1\nnot a report line\n";
+    let args = ["-n", "--merge-reports", "--keep-going", "--summary"];
+    let stderr = |extra: &[&str]| {
+        let output = ipstats(&[&args[..], extra].concat(), input);
+        assert_eq!(stdout(&output), "1 192.0.2.1\n");
+        String::from_utf8(output.stderr).unwrap()
+    };
+    let all = stderr(&[]);
+    assert!(all.starts_with("Warning: Skipping malformed report line 2") && all.contains("Lines read: 2"));
+    let quiet = stderr(&["-q"]);
+    assert!(!quiet.contains("Warning") && quiet.contains("Lines read: 2"));
+    assert_eq!(stderr(&["-qq"]), "");
+}
+
+#[test]
+fn running_total_adds_up_the_printed_rows() {
+    let input = "::ffff:192.0.2.1\n::ffff:192.0.2.2\n::ffff:192.0.2.1\n::ffff:192.0.2.3\n";
+    let output = ipstats(&["-n", "--reverse", "--running-total"], input);
+    assert_eq!(stdout(&output), "2 192.0.2.1 2 50.0%\n1 192.0.2.2 3 75.0%\n1 192.0.2.3 4 100.0%\n");
+    let output = ipstats(&["-n", "--reverse", "--running-total", "-m", "1", "-f", "{running_pct}% {ip}"], input);
+    assert_eq!(stdout(&output), "50.0% 192.0.2.1\n");
+    // The names asked for by --running-total are only aliases of the cumulative variables
+    let output = ipstats(&["-n", "--reverse", "-f", "{running_total} {cumulative} {ip}"], input);
+    assert_eq!(stdout(&output).lines().next(), Some("2 2 192.0.2.1"));
+    let output = ipstats(&["-n", "--reverse", "--fields", "running_pct,ip"], input);
+    assert_eq!(stdout(&output).lines().next(), Some("50.0\t192.0.2.1"));
+}
+
+#[test]
+fn numeric_only_rejects_real_host_variables() {
+    let output = ipstats(&["-n", "-f", "{host:<15} {ip}"], "::ffff:192.0.2.1\n");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("You cannot use {host}"));
+    let output = ipstats(&["-n", "-f", "{{host}} {ip}"], "::ffff:192.0.2.1\n");
+    assert_eq!(stdout(&output), "{host} 192.0.2.1\n");
+}
+
+#[test]
+fn output_dir_gets_one_report_per_file() {
+    let dir = scratch("output-dir");
+    fs::create_dir(dir.join("logs")).unwrap();
+    fs::write(dir.join("logs/a.log"), "::ffff:192.0.2.1\n").unwrap();
+    fs::write(dir.join("b.log"), "::ffff:192.0.2.2\n::ffff:192.0.2.2\n").unwrap();
+    let output = ipstats_in(&dir, &["-n", "--separate", "--output-dir", "reports", "logs/a.log", "b.log"], "");
+    assert_eq!(stdout(&output), "");
+    assert_eq!(fs::read_to_string(dir.join("reports/logs_a.log.txt")).unwrap(), "1 192.0.2.1\n");
+    assert_eq!(fs::read_to_string(dir.join("reports/b.log.txt")).unwrap(), "2 192.0.2.2\n");
+
+    let args = ["-n", "--separate", "--output-dir", "reports", "--label", "web", "--label", "db", "--output-format", "markdown"];
+    let output = ipstats_in(&dir, &[&args[..], &["logs/a.log", "b.log"]].concat(), "");
+    assert_eq!(stdout(&output), "");
+    assert!(fs::read_to_string(dir.join("reports/db.md")).unwrap().contains("192.0.2.2"));
+}
+
+#[test]
+fn files_named_like_subcommands_follow_a_double_dash() {
+    let dir = scratch("subcommand-files");
+    fs::write(dir.join("completions"), "::ffff:192.0.2.1\n").unwrap();
+    let output = ipstats_in(&dir, &["completions", "bash"], "");
+    assert!(stdout(&output).contains("complete"));
+    let output = ipstats_in(&dir, &["-n", "--", "completions"], "");
+    assert_eq!(stdout(&output), "1 192.0.2.1\n");
+    let output = ipstats_in(&dir, &["count", "-n", "completions"], "");
+    assert_eq!(stdout(&output), "1 192.0.2.1\n");
+}
+
+#[test]
+fn man_page_explains_files_named_man() {
+    let dir = scratch("man-file");
+    fs::write(dir.join("man"), "::ffff:192.0.2.1\n::ffff:192.0.2.1\n").unwrap();
+    let page = stdout(&ipstats_in(&dir, &["man"], ""));
+    // clap_mangen may write the dashes as roff escapes
+    let text = page.replace("\\-", "-");
+    assert!(page.starts_with(".TH ipstats 1") && text.contains("ipstats -- man"), "{page}");
+    let output = ipstats_in(&dir, &["-n", "--", "man"], "");
+    assert_eq!(stdout(&output), "2 192.0.2.1\n");
+}
+
+#[test]
+fn skip_lines_skips_the_header_of_every_file() {
+    let dir = scratch("skip-lines");
+    fs::write(dir.join("a.csv"), "client\n::ffff:10.0.0.1 header\n::ffff:192.0.2.1\n").unwrap();
+    fs::write(dir.join("b.csv"), "client\n::ffff:10.0.0.1 header\n::ffff:192.0.2.1\n").unwrap();
+    let output = ipstats_in(&dir, &["-n", "--skip-lines", "2", "--summary", "a.csv", "b.csv"], "");
+    assert_eq!(stdout(&output), "2 192.0.2.1\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Lines read: 6"));
+}
+
+#[test]
+fn percent_is_the_share_of_all_counted_ips() {
+    let input = "::ffff:192.0.2.1\n::ffff:192.0.2.2\n::ffff:192.0.2.2\n";
+    let output = ipstats(&["-n", "-f", "{percent}% {ip}"], input);
+    assert_eq!(stdout(&output), "33.3% 192.0.2.1\n66.7% 192.0.2.2\n");
+    let output = ipstats(&["-n", "-m", "1", "-f", "{percent}% {ip}"], input);
+    assert_eq!(stdout(&output), "66.7% 192.0.2.2\n");
+}
+
+#[test]
+fn raw_is_the_first_match_as_written() {
+    let input = "::ffff:010.000.002.001\n::ffff:10.0.2.1\n";
+    let output = ipstats(&["-n", "-f", "{cnt} {ip} {raw}"], input);
+    assert_eq!(stdout(&output), "2 10.0.2.1 ::ffff:010.000.002.001\n");
+}
+
+#[test]
+fn max_distinct_fails_instead_of_printing_a_report() {
+    let input: String = (1..=3).map(|i| format!("::ffff:192.0.2.{i}\n")).collect();
+    let output = ipstats(&["-n", "--max-distinct", "2"], &input);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Found more than 2 distinct IPs"));
+    let output = ipstats(&["-n", "--max-distinct", "2", "--errors", "json"], &input);
+    let error: serde_json::Value = serde_json::from_slice(&output.stderr).unwrap();
+    assert_eq!(error["kind"], "limit");
+    assert_eq!(stdout(&ipstats(&["-n", "--max-distinct", "3"], &input)).lines().count(), 3);
+}
+
+#[test]
+fn numeric_keeps_the_variables_without_lookups() {
+    let output = ipstats(&["-n", "--network-class", "-f", "{cnt} {ip} {class}"], "::ffff:192.0.2.1\n");
+    assert_eq!(stdout(&output), "1 192.0.2.1 documentation\n");
+    let output = ipstats(&["-n", "--fields", "cnt,host"], "::ffff:192.0.2.1\n");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("You cannot use the host field"));
+}
+
+#[test]
+fn skipped_lookups_print_the_ip_as_host() {
+    let input = "::ffff:127.0.0.1\n::1\n";
+    let output = ipstats(&["--no-auto-format", "--skip-v4-rdns", "--skip-v6-rdns", "-f", "{host}"], input);
+    assert_eq!(stdout(&output), "127.0.0.1\n::1\n");
+}
+
+#[test]
+fn mac_counts_every_spelling_of_an_address() {
+    let input = "DHCPACK 00:1A:2B:3C:4D:5E\nDHCPACK 00-1a-2b-3c-4d-5e\nDHCPACK 001a.2b3c.4d5f\n";
+    let output = ipstats(&["--mac"], input);
+    assert_eq!(stdout(&output), "1 00:1a:2b:3c:4d:5f\n2 00:1a:2b:3c:4d:5e\n");
+}
+
+#[test]
+fn max_line_length_skips_long_lines_and_keeps_going() {
+    let input = format!("::ffff:192.0.2.1\n::ffff:192.0.2.2 {}\n::ffff:192.0.2.3\n", "x".repeat(100_000));
+    let output = ipstats(&["-n", "--max-line-length", "100", "--summary"], &input);
+    assert_eq!(stdout(&output), "1 192.0.2.1\n1 192.0.2.3\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Lines skipped for exceeding --max-line-length: 1"));
+    let output = ipstats(&["-n", "--max-line-length", "100", "--pedantic"], &input);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn contradicting_input_options_are_rejected() {
+    for (args, message) in [
+        (&["-k", "0"][..], "counts from 1"),
+        (&["--fixed-ips", "-k", "2"][..], "no match number 2"),
+        (&["--fixed-ips", "-p", "[0-9.]+"][..], "a pattern would never be used"),
+    ] {
+        let output = ipstats(&[&["-n"], args].concat(), "192.0.2.1\n");
+        assert_eq!(output.status.code(), Some(1), "{args:?}");
+        assert!(String::from_utf8_lossy(&output.stderr).contains(message), "{args:?}");
+    }
+    assert_eq!(stdout(&ipstats(&["-n", "--fixed-ips"], "192.0.2.1\n")), "1 192.0.2.1\n");
+}
+
+#[test]
+fn count_field_values_counts_any_field() {
+    let input = "192.0.2.1 GET /index.html\n192.0.2.2 GET /login\n192.0.2.1 POST /login\n";
+    let output = ipstats(&["--count-field-values", "--field", "3"], input);
+    assert_eq!(stdout(&output), "1 /index.html\n2 /login\n");
+    let output = ipstats(&["--count-field-values"], input);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("needs --field, --pattern or --fixed-ips"));
+}
+
+#[test]
+fn sort_by_ports_ranks_by_distinct_ports() {
+    let input = "::ffff:192.0.2.1 port 80\n::ffff:192.0.2.1 port 80\n::ffff:192.0.2.1 port 80\n::ffff:192.0.2.2 port 22\n::ffff:192.0.2.2 port 23\n";
+    let args = ["-n", "--distinct-ports", r"port (\d+)", "-f", "{cnt} {distinct_ports} {ip}"];
+    assert_eq!(stdout(&ipstats(&args, input)), "2 2 192.0.2.2\n3 1 192.0.2.1\n");
+    let output = ipstats(&[&args[..], &["--sort-by-ports"]].concat(), input);
+    assert_eq!(stdout(&output), "3 1 192.0.2.1\n2 2 192.0.2.2\n");
+}
+
+#[test]
+fn tail_lines_counts_the_end_of_every_input() {
+    let dir = scratch("tail-lines");
+    fs::write(dir.join("a.log"), "::ffff:192.0.2.1\n::ffff:192.0.2.2\n::ffff:192.0.2.3\n").unwrap();
+    fs::write(dir.join("b.log"), "::ffff:192.0.2.3\n").unwrap();
+    let output = ipstats_in(&dir, &["-n", "--tail-lines", "2", "a.log", "b.log"], "");
+    assert_eq!(stdout(&output), "1 192.0.2.2\n2 192.0.2.3\n");
+    let output = ipstats(&["-n", "--tail-lines", "1"], "::ffff:192.0.2.1\n::ffff:192.0.2.2\n");
+    assert_eq!(stdout(&output), "1 192.0.2.2\n");
+}
+
+#[test]
+fn zero_padded_octets_are_counted_with_the_plain_form() {
+    let input = "192.0.2.1\n192.000.002.001\n";
+    let pattern = r"([0-9]+\.){3}[0-9]+";
+    assert_eq!(stdout(&ipstats(&["-n", "-p", pattern], input)), "2 192.0.2.1\n");
+    let output = ipstats(&["-n", "-p", pattern, "--no-normalize-octets"], input);
+    assert_eq!(stdout(&output), "1 192.0.2.1\n1 192.000.002.001\n");
+}
+
+#[test]
+fn nmap_cidr_collapses_neighbouring_ips() {
+    let input: String = (0..=4).map(|i| format!("::ffff:192.0.2.{i}\n")).collect();
+    let output = ipstats(&["-n", "--output-format", "nmap", "--cidr"], &input);
+    assert_eq!(stdout(&output), "192.0.2.0/30\n192.0.2.4/32\n");
+    let output = ipstats(&["-n", "--output-format", "nmap"], &input);
+    assert_eq!(stdout(&output).lines().count(), 5);
+}
+
+#[test]
+fn dedup_window_counts_bursts_once() {
+    let input = "::ffff:192.0.2.1\n::ffff:192.0.2.1\n::ffff:192.0.2.2\n::ffff:192.0.2.1\n";
+    let output = ipstats(&["-n", "--dedup-window", "2", "--summary"], input);
+    assert_eq!(stdout(&output), "1 192.0.2.2\n2 192.0.2.1\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Matches skipped by --dedup-window: 1"));
+}
+
+#[test]
+fn key_fallback_counts_lines_with_too_few_matches() {
+    let input = "::ffff:192.0.2.9 via ::ffff:192.0.2.1\n::ffff:192.0.2.2\n";
+    assert_eq!(stdout(&ipstats(&["-n", "-k", "2"], input)), "1 192.0.2.1\n");
+    let output = ipstats(&["-n", "-k", "2", "--key-fallback", "last", "--summary"], input);
+    assert_eq!(stdout(&output), "1 192.0.2.1\n1 192.0.2.2\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Lines counted with the --key-fallback match: 1"));
+}
+
+#[test]
+fn state_dir_only_counts_what_was_appended() {
+    let dir = scratch("state-dir");
+    let args = ["-n", "--state-dir", "state", "access.log"];
+    fs::write(dir.join("access.log"), "::ffff:192.0.2.1\n").unwrap();
+    assert_eq!(stdout(&ipstats_in(&dir, &args, "")), "1 192.0.2.1\n");
+    fs::OpenOptions::new().append(true).open(dir.join("access.log")).unwrap().write_all(b"::ffff:192.0.2.2\n").unwrap();
+    assert_eq!(stdout(&ipstats_in(&dir, &args, "")), "1 192.0.2.2\n");
+    assert_eq!(stdout(&ipstats_in(&dir, &args, "")), "");
+    let output = ipstats_in(&dir, &[&args[..], &["--reset-offsets"]].concat(), "");
+    assert_eq!(stdout(&output), "1 192.0.2.1\n1 192.0.2.2\n");
+}
+
+#[test]
+fn dot_connects_ips_of_the_same_line() {
+    let input = "::ffff:192.0.2.1 > ::ffff:192.0.2.2\n::ffff:192.0.2.3\n";
+    let output = ipstats(&["-n", "--key-range", "1:2", "--output-format", "dot"], input);
+    let graph = stdout(&output);
+    assert!(graph.starts_with("digraph ipstats {\n") && graph.ends_with("}\n"), "{graph}");
+    assert!(graph.contains("    \"192.0.2.1\" -> \"192.0.2.2\" [label=\"1\""), "{graph}");
+    assert_eq!(graph.matches(" -> ").count(), 1);
+}